@@ -1,5 +1,8 @@
 //! Audio logic
 
+use crate::cpu::model::Model;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
 const APU_BASE: usize = 0xFF10;
 
 #[derive(Clone)]
@@ -47,7 +50,7 @@ impl Apu {
         }
     }
 
-    pub fn reset(&mut self, sgb_mode: bool) {
+    pub fn reset(&mut self, model: Model) {
         self.apu_mem[0xFF10 - APU_BASE] = 0x80;
         self.apu_mem[0xFF11 - APU_BASE] = 0xBF;
         self.apu_mem[0xFF12 - APU_BASE] = 0xF3;
@@ -67,7 +70,7 @@ impl Apu {
         self.apu_mem[0xFF23 - APU_BASE] = 0xBF;
         self.apu_mem[0xFF24 - APU_BASE] = 0x77;
         self.apu_mem[0xFF25 - APU_BASE] = 0xF3;
-        self.apu_mem[0xFF26 - APU_BASE] = if sgb_mode { 0xF0 } else { 0xF1 };
+        self.apu_mem[0xFF26 - APU_BASE] = if model.is_sgb() { 0xF0 } else { 0xF1 };
 
         self.channel1_envelope_increasing = self.channel1_envelope_increasing();
         self.channel1_envelope_volume = self.channel1_envelope_volume();
@@ -788,3 +791,42 @@ impl Apu {
         }
     }
 }
+
+impl SaveState for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.channel1_sweep_counter);
+        writer.write_bool(self.channel1_sweep_enabled);
+        writer.write_u16(self.channel1_frequency);
+        writer.write_bool(self.channel1_negate_executed);
+        writer.write_u8(self.channel1_envelope_counter);
+        writer.write_bool(self.channel1_envelope_increasing);
+        writer.write_u8(self.channel1_envelope_volume);
+        writer.write_u8(self.channel2_envelope_counter);
+        writer.write_bool(self.channel2_envelope_increasing);
+        writer.write_u8(self.channel2_envelope_volume);
+        writer.write_u8(self.channel4_envelope_counter);
+        writer.write_bool(self.channel4_envelope_increasing);
+        writer.write_u8(self.channel4_envelope_volume);
+        writer.write_u8(self.div_apu);
+        writer.write_bytes(&self.apu_mem);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.channel1_sweep_counter = reader.read_u8()?;
+        self.channel1_sweep_enabled = reader.read_bool()?;
+        self.channel1_frequency = reader.read_u16()?;
+        self.channel1_negate_executed = reader.read_bool()?;
+        self.channel1_envelope_counter = reader.read_u8()?;
+        self.channel1_envelope_increasing = reader.read_bool()?;
+        self.channel1_envelope_volume = reader.read_u8()?;
+        self.channel2_envelope_counter = reader.read_u8()?;
+        self.channel2_envelope_increasing = reader.read_bool()?;
+        self.channel2_envelope_volume = reader.read_u8()?;
+        self.channel4_envelope_counter = reader.read_u8()?;
+        self.channel4_envelope_increasing = reader.read_bool()?;
+        self.channel4_envelope_volume = reader.read_u8()?;
+        self.div_apu = reader.read_u8()?;
+        reader.read_bytes(&mut self.apu_mem)?;
+        Ok(())
+    }
+}
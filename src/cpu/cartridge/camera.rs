@@ -0,0 +1,514 @@
+//! Pocket Camera: the M64282FP image sensor and its registers
+//!
+//! Setting bit 4 of the RAM bank register (0x4000-0x5FFF) maps the camera
+//! registers over 0xA000-0xBFFF in place of RAM.  Writing 1 to bit 0 of
+//! 0xA000 takes a photo; the bit reads back as 1 until the exposure has
+//! finished, at which point the sensor image has been run through the edge
+//! filter and the dithering matrix and stored as 2bpp tiles at
+//! 0xA100-0xAEFF of RAM bank 0.
+//!
+//! The scene in front of the sensor comes from a `CameraSource`.
+
+use std::path::Path;
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// Width of the image the sensor delivers, in pixels
+pub const CAMERA_WIDTH: usize = 128;
+/// Height of the image the sensor delivers, in pixels
+pub const CAMERA_HEIGHT: usize = 112;
+
+/// The registers that can be written: control, 5 sensor settings and the
+/// 4x4x3 dithering matrix
+const CAMERA_REGISTER_COUNT: usize = 0x36;
+/// First register of the dithering matrix
+const MATRIX_START: usize = 0x06;
+/// Where the photo goes in RAM bank 0
+const PHOTO_RAM_START: usize = 0x0100;
+
+/// CPU cycles a capture takes, on top of the exposure time
+const CAPTURE_BASE_CYCLES: u32 = 129_784;
+/// Extra cycles when the N bit (0xA001 bit 7) is clear
+const CAPTURE_NO_N_CYCLES: u32 = 2_048;
+/// CPU cycles per unit of exposure time (0xA002-0xA003)
+const CAPTURE_CYCLES_PER_EXPOSURE: u32 = 64;
+
+/// Exposure time at which the sensor output matches the source image
+const EXPOSURE_UNITY: f32 = 0x0800 as f32;
+/// Edge enhancement ratio selected by bits 4-6 of 0xA004
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+static CAMERA_WRITE_ONLY: u8 = 0x00;
+
+/// What the sensor of a Pocket Camera sees
+pub trait CameraSource {
+    /// Fills `image` with `CAMERA_WIDTH` x `CAMERA_HEIGHT` grayscale pixels,
+    /// row by row, where 0 is black and 255 is white
+    fn capture(&mut self, image: &mut [u8]);
+}
+
+/// Nothing in front of the lens: an even mid gray
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BlankCamera;
+
+impl CameraSource for BlankCamera {
+    fn capture(&mut self, image: &mut [u8]) {
+        for pixel in image.iter_mut() {
+            *pixel = 0x80;
+        }
+    }
+}
+
+/// Shows the sensor the same still image every time
+#[derive(Debug, Clone)]
+pub struct StillImageCamera {
+    image: Vec<u8>,
+}
+
+impl StillImageCamera {
+    /// Scales a grayscale image of any size to the sensor's resolution
+    pub fn from_grayscale(width: usize, height: usize, pixels: &[u8]) -> Result<Self, String> {
+        if width == 0 || height == 0 || pixels.len() < width * height {
+            return Err(format!(
+                "{} bytes is not a {}x{} grayscale image",
+                pixels.len(),
+                width,
+                height
+            ));
+        }
+        let mut image = Vec::with_capacity(CAMERA_WIDTH * CAMERA_HEIGHT);
+        for y in 0..CAMERA_HEIGHT {
+            let src_y = y * height / CAMERA_HEIGHT;
+            for x in 0..CAMERA_WIDTH {
+                let src_x = x * width / CAMERA_WIDTH;
+                image.push(pixels[src_y * width + src_x]);
+            }
+        }
+        Ok(StillImageCamera { image })
+    }
+
+    /// Loads a PNG or a binary/ASCII PGM (P5/P2) file
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("Could not read image {:?}: {}", path, e))?;
+        let (width, height, pixels) = if data.starts_with(b"\x89PNG") {
+            decode_png(&data)
+        } else if data.starts_with(b"P5") || data.starts_with(b"P2") {
+            decode_pgm(&data)
+        } else {
+            Err("not a PNG or PGM file".to_string())
+        }
+        .map_err(|e| format!("Could not load image {:?}: {}", path, e))?;
+        Self::from_grayscale(width, height, &pixels)
+    }
+}
+
+impl CameraSource for StillImageCamera {
+    fn capture(&mut self, image: &mut [u8]) {
+        image.copy_from_slice(&self.image);
+    }
+}
+
+/// Fills in a photo, see `CameraSource::capture`
+type CameraCallback = Box<dyn FnMut(&mut [u8])>;
+
+/// Asks the host for every photo, e.g. to read a webcam
+pub struct CallbackCamera {
+    callback: CameraCallback,
+}
+
+impl CallbackCamera {
+    /// `callback` is given the image to fill, as described in
+    /// `CameraSource::capture`
+    pub fn new(callback: impl FnMut(&mut [u8]) + 'static) -> CallbackCamera {
+        CallbackCamera {
+            callback: Box::new(callback),
+        }
+    }
+}
+
+impl CameraSource for CallbackCamera {
+    fn capture(&mut self, image: &mut [u8]) {
+        (self.callback)(image)
+    }
+}
+
+/// Width, height and 8-bit grayscale pixels
+type DecodedImage = (usize, usize, Vec<u8>);
+
+/// Decodes a PNG to 8-bit grayscale
+fn decode_png(data: &[u8]) -> Result<DecodedImage, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    let channels = info.color_type.samples();
+    let gray = buffer[..info.buffer_size()]
+        .chunks(channels)
+        .map(|pixel| match channels {
+            1 | 2 => pixel[0],
+            _ => {
+                ((pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000)
+                    as u8
+            }
+        })
+        .collect();
+    Ok((info.width as usize, info.height as usize, gray))
+}
+
+/// Decodes a binary (P5) or ASCII (P2) PGM to 8-bit grayscale
+fn decode_pgm(data: &[u8]) -> Result<DecodedImage, String> {
+    let binary = data.starts_with(b"P5");
+    let mut pos = 2;
+    let next_number = |pos: &mut usize| -> Result<usize, String> {
+        // skip whitespace and comments
+        loop {
+            match data.get(*pos) {
+                Some(b'#') => {
+                    while data.get(*pos).is_some_and(|&c| c != b'\n') {
+                        *pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => *pos += 1,
+                _ => break,
+            }
+        }
+        let start = *pos;
+        while data.get(*pos).is_some_and(|c| c.is_ascii_digit()) {
+            *pos += 1;
+        }
+        std::str::from_utf8(&data[start..*pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "malformed PGM header".to_string())
+    };
+    let width = next_number(&mut pos)?;
+    let height = next_number(&mut pos)?;
+    let max_value = next_number(&mut pos)?;
+    if max_value == 0 || max_value > 0xFFFF {
+        return Err(format!("unsupported PGM maximum value {}", max_value));
+    }
+    let scale = |v: usize| (v.min(max_value) * 255 / max_value) as u8;
+
+    let count = width * height;
+    let pixels = if binary {
+        // a single whitespace character separates the header from the data
+        pos += 1;
+        let bytes_per_sample = if max_value > 0xFF { 2 } else { 1 };
+        let raster = data
+            .get(pos..pos + count * bytes_per_sample)
+            .ok_or_else(|| "PGM data is truncated".to_string())?;
+        raster
+            .chunks(bytes_per_sample)
+            .map(|s| scale(s.iter().fold(0, |acc, &b| (acc << 8) | b as usize)))
+            .collect()
+    } else {
+        (0..count)
+            .map(|_| next_number(&mut pos).map(scale))
+            .collect::<Result<Vec<u8>, String>>()?
+    };
+    Ok((width, height, pixels))
+}
+
+/// The camera registers at 0xA000-0xA035
+///
+/// 0xA000 bit 0 starts a capture, 0xA001 holds the N/VH edge bits and the
+/// gain, 0xA002-0xA003 the exposure time, 0xA004 the edge ratio and the
+/// invert bit, 0xA005 the output bias and 0xA006-0xA035 the thresholds of
+/// the dithering matrix.  The sensor's output voltage calibration (0xA004
+/// bits 0-2 and 0xA005) is not modelled.
+#[derive(Debug, Clone)]
+pub struct CameraRegisters {
+    registers: [u8; CAMERA_REGISTER_COUNT],
+    /// Cycles until the capture in progress finishes
+    capture_cycles: u32,
+    /// A capture has finished and the photo has yet to be developed
+    photo_pending: bool,
+}
+
+impl CameraRegisters {
+    pub fn new() -> CameraRegisters {
+        CameraRegisters {
+            registers: [0; CAMERA_REGISTER_COUNT],
+            capture_cycles: 0,
+            photo_pending: false,
+        }
+    }
+
+    /// Only 0xA000 can be read back; the rest read as 0
+    pub fn read(&self, index: u16) -> &u8 {
+        if index & 0x7F == 0 {
+            &self.registers[0]
+        } else {
+            &CAMERA_WRITE_ONLY
+        }
+    }
+
+    pub fn write(&mut self, index: u16, value: u8) {
+        let register = (index & 0x7F) as usize;
+        if register >= CAMERA_REGISTER_COUNT {
+            return;
+        }
+        if register != 0 {
+            self.registers[register] = value;
+            return;
+        }
+
+        let busy = self.capture_cycles > 0;
+        if value & 1 == 1 && !busy {
+            let exposure = u16::from_be_bytes([self.registers[2], self.registers[3]]) as u32;
+            let n_cycles = if self.registers[1] & 0x80 == 0 {
+                CAPTURE_NO_N_CYCLES
+            } else {
+                0
+            };
+            self.capture_cycles =
+                CAPTURE_BASE_CYCLES + n_cycles + exposure * CAPTURE_CYCLES_PER_EXPOSURE;
+            debug!("Pocket Camera: capture started");
+        } else if value & 1 == 0 && busy {
+            self.capture_cycles = 0;
+            debug!("Pocket Camera: capture cancelled");
+        }
+        self.registers[0] = value & 0x07;
+    }
+
+    /// Runs the capture in progress for `cycles` CPU cycles
+    pub fn step(&mut self, cycles: u32) {
+        if self.capture_cycles == 0 {
+            return;
+        }
+        self.capture_cycles = self.capture_cycles.saturating_sub(cycles);
+        if self.capture_cycles == 0 {
+            self.registers[0] &= !1;
+            self.photo_pending = true;
+        }
+    }
+
+    /// Whether a finished capture is waiting for `develop`
+    pub fn photo_pending(&self) -> bool {
+        self.photo_pending
+    }
+
+    /// Processes the sensor image and writes the photo into `ram` (RAM
+    /// bank 0) as 16x14 tiles
+    pub fn develop(&mut self, sensor: &[u8], ram: &mut [u8; 0x2000]) {
+        self.photo_pending = false;
+
+        let exposure = u16::from_be_bytes([self.registers[2], self.registers[3]]) as f32;
+        let gain = 10f32.powf((self.registers[1] & 0x1F) as f32 * 1.5 / 20.0);
+        let scale = exposure / EXPOSURE_UNITY * gain;
+        let exposed = sensor
+            .iter()
+            .map(|&p| p as f32 * scale)
+            .collect::<Vec<f32>>();
+
+        let edge_mode = (self.registers[1] >> 5) & 0x3;
+        let edge_ratio = EDGE_RATIOS[((self.registers[4] >> 4) & 0x7) as usize];
+        let invert = self.registers[4] & 0x08 == 0x08;
+        let at = |x: isize, y: isize| {
+            let x = x.clamp(0, CAMERA_WIDTH as isize - 1) as usize;
+            let y = y.clamp(0, CAMERA_HEIGHT as isize - 1) as usize;
+            exposed[y * CAMERA_WIDTH + x]
+        };
+
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let (xi, yi) = (x as isize, y as isize);
+                let centre = at(xi, yi);
+                let vertical = 2.0 * centre - at(xi, yi - 1) - at(xi, yi + 1);
+                let horizontal = 2.0 * centre - at(xi - 1, yi) - at(xi + 1, yi);
+                let enhanced = match edge_mode {
+                    1 => centre + edge_ratio * vertical,
+                    2 => centre + edge_ratio * horizontal,
+                    3 => centre + edge_ratio * (vertical + horizontal),
+                    _ => centre,
+                };
+                let mut value = enhanced.clamp(0.0, 255.0) as u8;
+                if invert {
+                    value = 255 - value;
+                }
+
+                let matrix = MATRIX_START + ((y & 3) * 4 + (x & 3)) * 3;
+                let thresholds = &self.registers[matrix..matrix + 3];
+                let colour = if value < thresholds[0] {
+                    3
+                } else if value < thresholds[1] {
+                    2
+                } else if value < thresholds[2] {
+                    1
+                } else {
+                    0
+                };
+
+                let tile = (y / 8) * (CAMERA_WIDTH / 8) + x / 8;
+                let row = PHOTO_RAM_START + tile * 16 + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+                ram[row] = (ram[row] & !(1 << bit)) | ((colour & 1) << bit);
+                ram[row + 1] = (ram[row + 1] & !(1 << bit)) | ((colour >> 1) << bit);
+            }
+        }
+        debug!("Pocket Camera: photo developed");
+    }
+}
+
+impl Default for CameraRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for CameraRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.registers);
+        writer.write_u32(self.capture_cycles);
+        writer.write_bool(self.photo_pending);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_bytes(&mut self.registers)?;
+        self.capture_cycles = reader.read_u32()?;
+        self.photo_pending = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The Pocket Camera mapper: 128KB of RAM for the photos and the image
+/// sensor's registers
+#[derive(Clone)]
+pub struct PocketCamera {
+    ram_banks: RamBanks,
+    /// only gates writes, RAM can always be read
+    ram_active: bool,
+    mem_bank_selector: u32,
+    ram_bank_selector: u32,
+    /// 0xA000-0xBFFF accesses the camera registers instead of RAM
+    camera_mapped: bool,
+    camera: CameraRegisters,
+}
+
+impl PocketCamera {
+    pub fn new(context: &MapperContext) -> PocketCamera {
+        PocketCamera {
+            ram_banks: RamBanks::new(context.ram_banks),
+            ram_active: false,
+            mem_bank_selector: 1,
+            ram_bank_selector: 0,
+            camera_mapped: false,
+            camera: CameraRegisters::new(),
+        }
+    }
+}
+
+impl Mapper for PocketCamera {
+    fn name(&self) -> &'static str {
+        "POCKET CAMERA"
+    }
+
+    fn state_tag(&self) -> u8 {
+        10
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => banked_rom_offset(self.mem_bank_selector, address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if self.camera_mapped {
+            self.camera.read(address)
+        } else {
+            self.ram_banks.read(self.ram_bank_selector, address)
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if self.camera_mapped {
+            self.camera.write(address, value);
+            false
+        } else {
+            self.ram_active && self.ram_banks.write(self.ram_bank_selector, address, value)
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_active = value & 0xF == 0xA,
+            0x2000..=0x3FFF => {
+                self.mem_bank_selector = value as u32 & 0x3F;
+                debug!(
+                    "Pocket Camera: Switching to ROM bank {}",
+                    self.mem_bank_selector
+                );
+            }
+            0x4000..=0x5FFF => {
+                self.camera_mapped = value & 0x10 == 0x10;
+                self.ram_bank_selector = value as u32 & 0xF;
+                debug!(
+                    "Pocket Camera: Switching to RAM bank {}",
+                    self.ram_bank_selector
+                );
+            }
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_active = false;
+        self.mem_bank_selector = 1;
+        self.ram_bank_selector = 0;
+        self.camera_mapped = false;
+        self.camera = CameraRegisters::new();
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+
+    fn step(&mut self, cycles: u32, _cycles_per_second: u64) {
+        self.camera.step(cycles);
+    }
+
+    fn develop_photo(&mut self, source: &mut dyn CameraSource) {
+        if !self.camera.photo_pending() {
+            return;
+        }
+        let mut sensor = vec![0; CAMERA_WIDTH * CAMERA_HEIGHT];
+        source.capture(&mut sensor);
+        match self.ram_banks.bank_mut(0) {
+            Some(bank) => self.camera.develop(&sensor, bank),
+            None => error!("Pocket Camera has no RAM to store the photo in"),
+        }
+    }
+}
+
+impl SaveState for PocketCamera {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram_banks.save_state(writer);
+        writer.write_bool(self.ram_active);
+        writer.write_u32(self.mem_bank_selector);
+        writer.write_u32(self.ram_bank_selector);
+        writer.write_bool(self.camera_mapped);
+        self.camera.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_banks.load_state(reader)?;
+        self.ram_active = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        self.camera_mapped = reader.read_bool()?;
+        self.camera.load_state(reader)
+    }
+}
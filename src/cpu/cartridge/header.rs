@@ -0,0 +1,523 @@
+//! Checking cartridge headers and reporting what's in them
+//!
+//! The header lives at 0x0100-0x014F of every ROM:
+//!
+//! | address       | contents                                       |
+//! |---------------|------------------------------------------------|
+//! | 0x0104-0x0133 | Nintendo logo, checked by the boot ROM         |
+//! | 0x0134-0x0143 | title; 0x0143 is the CGB flag on newer games   |
+//! | 0x0144-0x0145 | new licensee code, when 0x014B is 0x33         |
+//! | 0x0146        | SGB flag, 0x03 for SGB support                 |
+//! | 0x0147        | cartridge type (mapper and extra hardware)     |
+//! | 0x0148        | ROM size, 32KB << n                            |
+//! | 0x0149        | RAM size                                       |
+//! | 0x014B        | old licensee code                              |
+//! | 0x014C        | mask ROM version                               |
+//! | 0x014D        | header checksum of 0x0134-0x014C               |
+//! | 0x014E-0x014F | global checksum of the ROM, big-endian         |
+
+/// The logo every licensed game has to carry for the boot ROM to start it
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// How much of a ROM has to be there for it to have a header
+pub const HEADER_END: usize = 0x150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    /// A DMG game
+    None,
+    /// Runs on both DMG and CGB
+    Compatible,
+    /// Only runs on CGB
+    Only,
+}
+
+impl CgbSupport {
+    fn name(self) -> &'static str {
+        match self {
+            CgbSupport::None => "none",
+            CgbSupport::Compatible => "compatible",
+            CgbSupport::Only => "only",
+        }
+    }
+}
+
+/// Everything the header says about a ROM, and whether it adds up
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: u8,
+    /// e.g. "MBC3", or "unknown"
+    pub mapper: &'static str,
+    pub battery: bool,
+    pub rtc: bool,
+    pub rumble: bool,
+    /// ROM size according to the header, if the size code is known
+    pub rom_size: Option<usize>,
+    pub file_size: usize,
+    /// RAM size according to the header, if the size code is known
+    pub ram_size: Option<usize>,
+    /// The new two character licensee code, or the old one in hex
+    pub licensee_code: String,
+    /// Who the licensee code belongs to, if it is a known one
+    pub licensee: Option<&'static str>,
+    pub licensee_valid: bool,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub header_checksum_valid: bool,
+    pub global_checksum: u16,
+    pub global_checksum_valid: bool,
+    pub logo_valid: bool,
+}
+
+/// The name of the mapper for a cartridge type byte
+pub fn mapper_name(cartridge_type: u8) -> &'static str {
+    match cartridge_type {
+        0x00 => "ROM ONLY",
+        0x01..=0x03 => "MBC1",
+        0x05 | 0x06 => "MBC2",
+        0x08 | 0x09 => "ROM+RAM",
+        0x0B..=0x0D => "MMM01",
+        0x0F..=0x13 => "MBC3",
+        0x19..=0x1E => "MBC5",
+        0x20 => "MBC6",
+        0x22 => "MBC7",
+        0xFC => "POCKET CAMERA",
+        0xFD => "TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1",
+        _ => "unknown",
+    }
+}
+
+/// Whether cartridges of this type keep their RAM with a battery
+pub fn has_battery(cartridge_type: u8) -> bool {
+    matches!(
+        cartridge_type,
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC | 0xFE | 0xFF
+    )
+}
+
+fn has_rtc(cartridge_type: u8) -> bool {
+    matches!(cartridge_type, 0x0F | 0x10 | 0xFE)
+}
+
+fn has_rumble(cartridge_type: u8) -> bool {
+    matches!(cartridge_type, 0x1C..=0x1E)
+}
+
+/// The publisher for an old licensee code at 0x014B; 0x33 means the new
+/// code is used instead
+fn old_licensee_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        0x00 => "None",
+        0x01 | 0x31 => "Nintendo",
+        0x08 | 0x38 => "Capcom",
+        0x09 => "Hot-B",
+        0x0A | 0xE0 => "Jaleco",
+        0x0B => "Coconuts Japan",
+        0x0C | 0x6E => "Elite Systems",
+        0x13 | 0x69 => "Electronic Arts",
+        0x18 => "Hudson Soft",
+        0x19 => "ITC Entertainment",
+        0x1A => "Yanoman",
+        0x1D => "Japan Clary",
+        0x1F | 0x4A | 0x61 => "Virgin Interactive",
+        0x24 => "PCM Complete",
+        0x25 => "San-X",
+        0x28 => "Kotobuki Systems",
+        0x29 => "Seta",
+        0x30 | 0x70 => "Infogrames",
+        0x32 | 0xA2 | 0xB2 => "Bandai",
+        0x34 | 0xA4 => "Konami",
+        0x35 => "HectorSoft",
+        0x39 | 0x9D | 0xD9 => "Banpresto",
+        0x3C => "Entertainment International",
+        0x3E => "Gremlin",
+        0x41 => "Ubi Soft",
+        0x42 | 0xEB => "Atlus",
+        0x44 | 0x4D => "Malibu",
+        0x46 | 0xCF => "Angel",
+        0x47 => "Spectrum HoloByte",
+        0x49 => "Irem",
+        0x4F => "U.S. Gold",
+        0x50 => "Absolute",
+        0x51 | 0xB0 => "Acclaim",
+        0x52 => "Activision",
+        0x53 => "American Sammy",
+        0x54 => "GameTek",
+        0x55 => "Park Place",
+        0x56 | 0xDB | 0xFF => "LJN",
+        0x57 => "Matchbox",
+        0x59 => "Milton Bradley",
+        0x5A => "Mindscape",
+        0x5B => "Romstar",
+        0x5C | 0xD6 => "Naxat Soft",
+        0x5D => "Tradewest",
+        0x60 => "Titus",
+        0x67 => "Ocean",
+        0x6F => "Electro Brain",
+        0x71 => "Interplay",
+        0x72 | 0xAA => "Broderbund",
+        0x73 => "Sculptured Software",
+        0x75 => "The Sales Curve",
+        0x78 => "THQ",
+        0x79 => "Accolade",
+        0x7A => "Triffix Entertainment",
+        0x7C => "MicroProse",
+        0x7F | 0xC2 => "Kemco",
+        0x80 => "Misawa Entertainment",
+        0x83 => "LOZC",
+        0x86 | 0xC4 => "Tokuma Shoten",
+        0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai",
+        0x8E => "Ape",
+        0x8F => "I'Max",
+        0x91 => "Chunsoft",
+        0x92 => "Video System",
+        0x93 => "Tsuburaya Productions",
+        0x95 | 0xE3 => "Varie",
+        0x96 => "Yonezawa/S'Pal",
+        0x97 => "Kaneko",
+        0x99 => "Arc",
+        0x9A => "Nihon Bussan",
+        0x9B => "Tecmo",
+        0x9C => "Imagineer",
+        0x9F => "Nova",
+        0xA1 => "Hori Electric",
+        0xA6 => "Kawada",
+        0xA7 => "Takara",
+        0xA9 => "Technos Japan",
+        0xAC => "Toei Animation",
+        0xAD => "Toho",
+        0xAF => "Namco",
+        0xB1 => "ASCII or Nexsoft",
+        0xB4 => "Square Enix",
+        0xB6 => "HAL Laboratory",
+        0xB7 => "SNK",
+        0xB9 | 0xCE => "Pony Canyon",
+        0xBA => "Culture Brain",
+        0xBB => "Sunsoft",
+        0xBD => "Sony Imagesoft",
+        0xBF => "Sammy",
+        0xC0 | 0xD0 => "Taito",
+        0xC3 => "Squaresoft",
+        0xC5 => "Data East",
+        0xC6 => "Tonkin House",
+        0xC8 => "Koei",
+        0xC9 => "UFL",
+        0xCA => "Ultra",
+        0xCB => "Vap",
+        0xCC => "Use Corporation",
+        0xCD => "Meldac",
+        0xD1 => "Sofel",
+        0xD2 => "Quest",
+        0xD3 => "Sigma Enterprises",
+        0xD4 => "ASK Kodansha",
+        0xD7 => "Copya System",
+        0xDA => "Tomy",
+        0xDD => "NCS",
+        0xDE => "Human",
+        0xDF => "Altron",
+        0xE1 => "Towa Chiki",
+        0xE2 => "Yutaka",
+        0xE5 => "Epoch",
+        0xE7 => "Athena",
+        0xE8 => "Asmik Ace Entertainment",
+        0xE9 => "Natsume",
+        0xEA => "King Records",
+        0xEC => "Epic/Sony Records",
+        0xEE => "IGS",
+        0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment",
+        _ => return None,
+    })
+}
+
+/// The publisher for a new licensee code at 0x0144-0x0145
+fn new_licensee_name(code: &[u8]) -> Option<&'static str> {
+    Some(match code {
+        b"00" => "None",
+        b"01" => "Nintendo R&D1",
+        b"08" => "Capcom",
+        b"13" | b"69" => "Electronic Arts",
+        b"18" | b"38" => "Hudson Soft",
+        b"19" => "B-AI",
+        b"20" => "KSS",
+        b"22" => "POW",
+        b"24" => "PCM Complete",
+        b"25" => "San-X",
+        b"28" => "Kemco Japan",
+        b"29" => "Seta",
+        b"30" => "Viacom",
+        b"31" => "Nintendo",
+        b"32" => "Bandai",
+        b"33" | b"93" => "Ocean/Acclaim",
+        b"34" | b"54" | b"A4" => "Konami",
+        b"35" => "HectorSoft",
+        b"37" => "Taito",
+        b"39" => "Banpresto",
+        b"41" => "Ubi Soft",
+        b"42" => "Atlus",
+        b"44" => "Malibu",
+        b"46" => "Angel",
+        b"47" => "Bullet-Proof Software",
+        b"49" => "Irem",
+        b"50" => "Absolute",
+        b"51" => "Acclaim",
+        b"52" => "Activision",
+        b"53" => "American Sammy",
+        b"55" => "Hi Tech Entertainment",
+        b"56" => "LJN",
+        b"57" => "Matchbox",
+        b"58" => "Mattel",
+        b"59" => "Milton Bradley",
+        b"60" => "Titus",
+        b"61" => "Virgin Interactive",
+        b"64" => "LucasArts",
+        b"67" => "Ocean",
+        b"70" => "Infogrames",
+        b"71" => "Interplay",
+        b"72" => "Broderbund",
+        b"73" => "Sculptured Software",
+        b"75" => "The Sales Curve",
+        b"78" => "THQ",
+        b"79" => "Accolade",
+        b"80" => "Misawa Entertainment",
+        b"83" => "LOZC",
+        b"86" => "Tokuma Shoten",
+        b"87" => "Tsukuda Original",
+        b"91" => "Chunsoft",
+        b"92" => "Video System",
+        b"95" => "Varie",
+        b"96" => "Yonezawa/S'Pal",
+        b"97" => "Kaneko",
+        b"99" => "Pack-In-Video",
+        _ => return None,
+    })
+}
+
+fn ram_size(code: u8) -> Option<usize> {
+    match code {
+        0 => Some(0),
+        1 => Some(0x800),
+        2 => Some(0x2000),
+        3 => Some(0x8000),
+        4 => Some(0x20000),
+        5 => Some(0x10000),
+        _ => None,
+    }
+}
+
+/// The header checksum as the boot ROM computes it
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..=0x14C]
+        .iter()
+        .fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1))
+}
+
+/// The sum of every byte of the ROM except the global checksum itself
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |acc, (_, &b)| acc.wrapping_add(b as u16))
+}
+
+/// Reads and checks the header of `rom`
+pub fn rom_info(rom: &[u8]) -> Result<RomInfo, String> {
+    if rom.len() < HEADER_END {
+        return Err(format!(
+            "ROM is {} bytes, too small to have a header",
+            rom.len()
+        ));
+    }
+
+    let cgb = match rom[0x143] {
+        0xC0 => CgbSupport::Only,
+        0x80 => CgbSupport::Compatible,
+        _ => CgbSupport::None,
+    };
+    // on CGB games the last byte of the title is the CGB flag
+    let title_end = if cgb == CgbSupport::None {
+        0x144
+    } else {
+        0x143
+    };
+    let title = rom[0x134..title_end]
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>();
+
+    let old_licensee = rom[0x14B];
+    let (licensee_code, licensee) = if old_licensee == 0x33 {
+        let code = &rom[0x144..0x146];
+        (
+            String::from_utf8_lossy(code).into_owned(),
+            new_licensee_name(code),
+        )
+    } else {
+        (
+            format!("{:02X}", old_licensee),
+            old_licensee_name(old_licensee),
+        )
+    };
+
+    let cartridge_type = rom[0x147];
+    let rom_size = match rom[0x148] {
+        code @ 0..=8 => Some(0x8000 << code),
+        _ => None,
+    };
+    let global_checksum_stored = u16::from_be_bytes([rom[0x14E], rom[0x14F]]);
+
+    Ok(RomInfo {
+        title,
+        cgb,
+        // the SGB functions are only enabled with the new licensee code
+        sgb: rom[0x146] == 0x03 && old_licensee == 0x33,
+        cartridge_type,
+        mapper: mapper_name(cartridge_type),
+        battery: has_battery(cartridge_type),
+        rtc: has_rtc(cartridge_type),
+        rumble: has_rumble(cartridge_type),
+        rom_size,
+        file_size: rom.len(),
+        ram_size: ram_size(rom[0x149]),
+        licensee_code,
+        licensee,
+        licensee_valid: licensee.is_some(),
+        version: rom[0x14C],
+        header_checksum: rom[0x14D],
+        header_checksum_valid: header_checksum(rom) == rom[0x14D],
+        global_checksum: global_checksum_stored,
+        global_checksum_valid: global_checksum(rom) == global_checksum_stored,
+        logo_valid: rom[0x104..0x134] == NINTENDO_LOGO,
+    })
+}
+
+impl RomInfo {
+    /// What's wrong with the header; the global checksum isn't checked by
+    /// the hardware, so it is only worth a mention here
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.logo_valid {
+            problems.push("Nintendo logo does not match".to_string());
+        }
+        if !self.header_checksum_valid {
+            problems.push(format!(
+                "header checksum 0x{:02X} is wrong",
+                self.header_checksum
+            ));
+        }
+        if !self.global_checksum_valid {
+            problems.push(format!(
+                "global checksum 0x{:04X} is wrong",
+                self.global_checksum
+            ));
+        }
+        match self.rom_size {
+            Some(size) if size != self.file_size => problems.push(format!(
+                "header says the ROM is {} bytes but the file is {} bytes",
+                size, self.file_size
+            )),
+            None => problems.push("unknown ROM size code".to_string()),
+            _ => (),
+        }
+        if self.ram_size.is_none() {
+            problems.push("unknown RAM size code".to_string());
+        }
+        if self.mapper == "unknown" {
+            problems.push(format!(
+                "unknown cartridge type 0x{:02X}",
+                self.cartridge_type
+            ));
+        }
+        if !self.licensee_valid {
+            problems.push(format!("unknown licensee code {:?}", self.licensee_code));
+        }
+        problems
+    }
+
+    /// Whether the ROM would boot and its size adds up
+    pub fn is_valid(&self) -> bool {
+        self.logo_valid && self.header_checksum_valid && self.rom_size == Some(self.file_size)
+    }
+
+    /// The report as a JSON object
+    pub fn to_json(&self) -> String {
+        let optional = |size: Option<usize>| size.map_or("null".to_string(), |s| s.to_string());
+        let problems = self
+            .problems()
+            .iter()
+            .map(|p| json_string(p))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let fields = [
+            ("title", json_string(&self.title)),
+            ("mapper", json_string(self.mapper)),
+            ("cartridge_type", self.cartridge_type.to_string()),
+            ("battery", self.battery.to_string()),
+            ("rtc", self.rtc.to_string()),
+            ("rumble", self.rumble.to_string()),
+            ("cgb", json_string(self.cgb.name())),
+            ("sgb", self.sgb.to_string()),
+            ("rom_size", optional(self.rom_size)),
+            ("file_size", self.file_size.to_string()),
+            ("ram_size", optional(self.ram_size)),
+            ("licensee_code", json_string(&self.licensee_code)),
+            (
+                "licensee",
+                self.licensee.map_or("null".to_string(), json_string),
+            ),
+            ("licensee_valid", self.licensee_valid.to_string()),
+            ("version", self.version.to_string()),
+            ("logo_valid", self.logo_valid.to_string()),
+            ("header_checksum", self.header_checksum.to_string()),
+            (
+                "header_checksum_valid",
+                self.header_checksum_valid.to_string(),
+            ),
+            ("global_checksum", self.global_checksum.to_string()),
+            (
+                "global_checksum_valid",
+                self.global_checksum_valid.to_string(),
+            ),
+            ("valid", self.is_valid().to_string()),
+            ("problems", format!("[{}]", problems)),
+        ];
+        let body = fields
+            .iter()
+            .map(|(key, value)| format!("  \"{}\": {}", key, value))
+            .collect::<Vec<String>>()
+            .join(",\n");
+        format!("{{\n{}\n}}", body)
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,108 @@
+//! HuC1: Hudson's MBC with an infrared port
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// What the infrared port reads when no light is seen
+pub static NO_IR_LIGHT: u8 = 0xC0;
+
+#[derive(Clone)]
+pub struct Huc1 {
+    ram_banks: RamBanks,
+    /// 0xA000-0xBFFF accesses the infrared port instead of RAM
+    ir_mode: bool,
+    mem_bank_selector: u32,
+    ram_bank_selector: u32,
+}
+
+impl Huc1 {
+    pub fn new(context: &MapperContext) -> Huc1 {
+        Huc1 {
+            ram_banks: RamBanks::new(context.ram_banks),
+            ir_mode: false,
+            mem_bank_selector: 1,
+            ram_bank_selector: 0,
+        }
+    }
+}
+
+impl Mapper for Huc1 {
+    fn name(&self) -> &'static str {
+        "HuC1"
+    }
+
+    fn state_tag(&self) -> u8 {
+        7
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => banked_rom_offset(self.mem_bank_selector, address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if self.ir_mode {
+            &NO_IR_LIGHT
+        } else {
+            self.ram_banks.read(self.ram_bank_selector, address)
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        // writes in IR mode switch the cartridge's LED, which isn't shown
+        !self.ir_mode && self.ram_banks.write(self.ram_bank_selector, address, value)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ir_mode = (value & 0xF) == 0xE,
+            0x2000..=0x3FFF => {
+                self.mem_bank_selector = if value & 0x3F == 0 {
+                    1
+                } else {
+                    (value & 0x3F) as u32
+                };
+                debug!("HuC1: Switching to ROM bank {}", self.mem_bank_selector);
+            }
+            0x4000..=0x5FFF => self.ram_bank_selector = (value & 0x3) as u32,
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ir_mode = false;
+        self.mem_bank_selector = 1;
+        self.ram_bank_selector = 0;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+}
+
+impl SaveState for Huc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram_banks.save_state(writer);
+        writer.write_bool(self.ir_mode);
+        writer.write_u32(self.mem_bank_selector);
+        writer.write_u32(self.ram_bank_selector);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_banks.load_state(reader)?;
+        self.ir_mode = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! HuC3: Hudson's MBC with a real-time clock and an infrared port
+
+use super::huc1::NO_IR_LIGHT;
+use super::mapper::*;
+use super::rtc::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+#[derive(Clone)]
+pub struct Huc3 {
+    ram_banks: RamBanks,
+    /// what 0xA000-0xBFFF accesses: 0x0/0xA RAM, 0xB RTC commands,
+    /// 0xC RTC results, 0xD RTC status, 0xE infrared
+    mode: u8,
+    mem_bank_selector: u32,
+    ram_bank_selector: u32,
+    rtc: Huc3Rtc,
+}
+
+impl Huc3 {
+    pub fn new(context: &MapperContext) -> Huc3 {
+        Huc3 {
+            ram_banks: RamBanks::new(context.ram_banks),
+            mode: 0,
+            mem_bank_selector: 1,
+            ram_bank_selector: 0,
+            rtc: Huc3Rtc::new(),
+        }
+    }
+}
+
+impl Mapper for Huc3 {
+    fn name(&self) -> &'static str {
+        "HuC3"
+    }
+
+    fn state_tag(&self) -> u8 {
+        8
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => banked_rom_offset(self.mem_bank_selector, address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        match self.mode {
+            0x0 | 0xA => self.ram_banks.read(self.ram_bank_selector, address),
+            0xC => self.rtc.read(),
+            0xD => &HUC3_RTC_READY,
+            0xE => &NO_IR_LIGHT,
+            _ => &OPEN_BUS,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        // RAM is only writable in mode 0xA
+        match self.mode {
+            0xA => self.ram_banks.write(self.ram_bank_selector, address, value),
+            // the clock is saved with RAM
+            0xB => self.rtc.command(value),
+            _ => false,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.mode = value & 0xF,
+            0x2000..=0x3FFF => {
+                self.mem_bank_selector = if value & 0x7F == 0 {
+                    1
+                } else {
+                    (value & 0x7F) as u32
+                };
+                debug!("HuC3: Switching to ROM bank {}", self.mem_bank_selector);
+            }
+            0x4000..=0x5FFF => self.ram_bank_selector = (value & 0xF) as u32,
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.mode = 0;
+        self.mem_bank_selector = 1;
+        self.ram_bank_selector = 0;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+
+    fn clock_footer_sizes(&self) -> &'static [usize] {
+        &[HUC3_RTC_FOOTER_SIZE]
+    }
+
+    fn clock_footer(&self, unix_time: u64) -> Vec<u8> {
+        self.rtc.to_footer(unix_time).to_vec()
+    }
+
+    fn load_clock_footer(&mut self, footer: &[u8], unix_time: u64) -> Result<(), String> {
+        self.rtc.load_footer(footer, unix_time)
+    }
+
+    fn step(&mut self, cycles: u32, cycles_per_second: u64) {
+        self.rtc.step(cycles, cycles_per_second);
+    }
+}
+
+impl SaveState for Huc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram_banks.save_state(writer);
+        writer.write_u8(self.mode);
+        writer.write_u32(self.mem_bank_selector);
+        writer.write_u32(self.ram_bank_selector);
+        self.rtc.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_banks.load_state(reader)?;
+        self.mode = reader.read_u8()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        self.rtc.load_state(reader)
+    }
+}
@@ -0,0 +1,305 @@
+//! The memory bank controllers that sit between the Game Boy and the ROM
+//! and RAM on a cartridge
+//!
+//! Every MBC implements `Mapper`.  Which one a ROM gets is decided by the
+//! cartridge type byte at 0x0147 of its header.  Mappers registered with
+//! `register_mapper` are tried before the built-in ones, so flash carts and
+//! homebrew boards can be supported from outside this crate, and built-in
+//! mappers can be replaced.
+//!
+//! Types nothing knows about get `OpenBus`: the first 32KB of the ROM is
+//! mapped as it is, writes to 0x0000-0x7FFF are ignored, and there is no
+//! RAM, so 0xA000-0xBFFF reads 0xFF and writes there are lost.
+
+use std::sync::Mutex;
+
+use super::camera::CameraSource;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// What reads of an address nothing drives return
+pub static OPEN_BUS: u8 = 0xFF;
+
+/// Size of a switchable RAM bank at 0xA000-0xBFFF
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// A memory bank controller
+///
+/// Addresses passed in are the ones the CPU used: 0x0000-0x7FFF for ROM
+/// and registers, 0xA000-0xBFFF for RAM.
+pub trait Mapper: SaveState + MapperClone {
+    /// Shown in logs, e.g. "MBC3"
+    fn name(&self) -> &'static str;
+
+    /// Identifies the mapper in save-states, so a state is never loaded
+    /// into a different one.  0 is `OpenBus` and 1-10 are the built-in
+    /// mappers; registered mappers should use 0x80 and up.
+    fn state_tag(&self) -> u8;
+
+    /// Where in the ROM a read of 0x0000-0x7FFF goes.  The cartridge
+    /// wraps the offset to the size of the ROM.
+    fn rom_offset(&self, address: u16) -> usize;
+
+    /// Reads 0xA000-0xBFFF: RAM, or whatever is mapped over it
+    fn read_ram(&self, address: u16) -> &u8;
+
+    /// Writes 0xA000-0xBFFF; returns whether anything kept by the battery
+    /// changed, so that only then the save file is written again
+    fn write_ram(&mut self, address: u16, value: u8) -> bool;
+
+    /// Writes to the control registers at 0x0000-0x7FFF
+    fn write_register(&mut self, address: u16, value: u8);
+
+    /// Puts the registers back to how they are at power on.  RAM and
+    /// anything else a battery keeps are left alone.
+    fn reset(&mut self);
+
+    /// The battery backed RAM (or EEPROM) as it is stored in a save file
+    fn battery_ram(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Restores battery backed RAM from a save file.  `data` may be
+    /// shorter than the RAM, in which case the rest is left as it is.
+    #[allow(unused_variables)]
+    fn load_battery_ram(&mut self, data: &[u8]) {}
+
+    /// Size of what `battery_ram` returns
+    fn battery_ram_size(&self) -> usize {
+        0
+    }
+
+    /// Sizes of the clock footer the cartridge's saves may end with, if it
+    /// has a clock
+    fn clock_footer_sizes(&self) -> &'static [usize] {
+        &[]
+    }
+
+    /// The clock footer to append to a save file, empty without a clock
+    #[allow(unused_variables)]
+    fn clock_footer(&self, unix_time: u64) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Restores the clock from a save file's footer
+    #[allow(unused_variables)]
+    fn load_clock_footer(&mut self, footer: &[u8], unix_time: u64) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Advances anything on the cartridge that runs on its own
+    #[allow(unused_variables)]
+    fn step(&mut self, cycles: u32, cycles_per_second: u64) {}
+
+    /// Sets the tilt an accelerometer reads, in g from -1 to 1
+    #[allow(unused_variables)]
+    fn set_tilt(&mut self, x: f32, y: f32) {}
+
+    /// Whether a rumble motor is running
+    fn rumble_active(&self) -> bool {
+        false
+    }
+
+    /// Takes a picture from `source` if the cartridge has a camera that
+    /// is waiting for one
+    #[allow(unused_variables)]
+    fn develop_photo(&mut self, source: &mut dyn CameraSource) {}
+}
+
+/// Lets `Box<dyn Mapper>` be cloned.  Implemented for every mapper that
+/// is `Clone`.
+pub trait MapperClone {
+    fn clone_box(&self) -> Box<dyn Mapper>;
+}
+
+impl<T: Mapper + Clone + 'static> MapperClone for T {
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Mapper> {
+    fn clone(&self) -> Box<dyn Mapper> {
+        self.clone_box()
+    }
+}
+
+/// What a mapper is built from
+pub struct MapperContext<'a> {
+    pub rom: &'a [u8],
+    /// The cartridge type byte from the header
+    pub cartridge_type: u8,
+    /// Number of 8KB RAM banks the header asks for
+    pub ram_banks: usize,
+}
+
+impl MapperContext<'_> {
+    /// Number of 16KB banks in the ROM
+    pub fn rom_banks(&self) -> u32 {
+        (self.rom.len() / 0x4000) as u32
+    }
+}
+
+pub type MapperConstructor = fn(&MapperContext) -> Box<dyn Mapper>;
+
+static REGISTERED_MAPPERS: Mutex<Vec<(u8, MapperConstructor)>> = Mutex::new(Vec::new());
+
+/// Makes ROMs with the cartridge type `cartridge_type` use the mapper
+/// `constructor` builds, in place of any earlier registration or built-in
+/// mapper for that type.  Only affects ROMs loaded afterwards.
+pub fn register_mapper(cartridge_type: u8, constructor: MapperConstructor) {
+    let mut mappers = REGISTERED_MAPPERS.lock().unwrap_or_else(|e| e.into_inner());
+    mappers.retain(|&(t, _)| t != cartridge_type);
+    mappers.push((cartridge_type, constructor));
+}
+
+/// Removes a mapper added with `register_mapper`
+pub fn unregister_mapper(cartridge_type: u8) {
+    let mut mappers = REGISTERED_MAPPERS.lock().unwrap_or_else(|e| e.into_inner());
+    mappers.retain(|&(t, _)| t != cartridge_type);
+}
+
+/// The registered mapper for `cartridge_type`, if there is one
+pub fn registered_mapper(cartridge_type: u8) -> Option<MapperConstructor> {
+    let mappers = REGISTERED_MAPPERS.lock().unwrap_or_else(|e| e.into_inner());
+    mappers
+        .iter()
+        .find(|&&(t, _)| t == cartridge_type)
+        .map(|&(_, constructor)| constructor)
+}
+
+/// The fallback for cartridge types without a mapper: ROM banks 0 and 1
+/// and nothing else
+#[derive(Debug, Clone, Default)]
+pub struct OpenBus;
+
+impl Mapper for OpenBus {
+    fn name(&self) -> &'static str {
+        "open bus"
+    }
+
+    fn state_tag(&self) -> u8 {
+        0
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn read_ram(&self, _address: u16) -> &u8 {
+        &OPEN_BUS
+    }
+
+    fn write_ram(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn write_register(&mut self, _address: u16, _value: u8) {}
+
+    fn reset(&mut self) {}
+}
+
+impl SaveState for OpenBus {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Switchable 8KB RAM banks, as most mappers have
+#[derive(Debug, Clone, Default)]
+pub struct RamBanks {
+    banks: Vec<[u8; RAM_BANK_SIZE]>,
+}
+
+impl RamBanks {
+    pub fn new(count: usize) -> RamBanks {
+        RamBanks {
+            banks: vec![[0; RAM_BANK_SIZE]; count],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.banks.is_empty()
+    }
+
+    /// Size of all the banks together
+    pub fn size(&self) -> usize {
+        self.banks.len() * RAM_BANK_SIZE
+    }
+
+    /// Reads `address` in 0xA000-0xBFFF from `bank`; banks that don't exist
+    /// read as open bus
+    pub fn read(&self, bank: u32, address: u16) -> &u8 {
+        match self.banks.get(bank as usize) {
+            Some(bank) => &bank[(address as usize - 0xA000) % RAM_BANK_SIZE],
+            None => &OPEN_BUS,
+        }
+    }
+
+    /// Writes `address` in 0xA000-0xBFFF of `bank`, if the bank exists
+    /// Returns whether the bank exists and the byte changed
+    pub fn write(&mut self, bank: u32, address: u16, value: u8) -> bool {
+        match self.banks.get_mut(bank as usize) {
+            Some(bank) => {
+                let byte = &mut bank[(address as usize - 0xA000) % RAM_BANK_SIZE];
+                let changed = *byte != value;
+                *byte = value;
+                changed
+            }
+            None => false,
+        }
+    }
+
+    pub fn bank_mut(&mut self, bank: usize) -> Option<&mut [u8; RAM_BANK_SIZE]> {
+        self.banks.get_mut(bank)
+    }
+
+    /// Every bank, one after the other
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.banks
+            .iter()
+            .flat_map(|bank| bank.iter().copied())
+            .collect()
+    }
+
+    /// Fills the banks from `data`, as far as it goes
+    pub fn load_bytes(&mut self, data: &[u8]) {
+        for (bank, chunk) in self.banks.iter_mut().zip(data.chunks(RAM_BANK_SIZE)) {
+            bank[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+}
+
+impl SaveState for RamBanks {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.banks.len() as u32);
+        for bank in self.banks.iter() {
+            writer.write_bytes(&bank[..]);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let num_banks = reader.read_u32()? as usize;
+        if num_banks != self.banks.len() {
+            return Err(format!(
+                "Save-state has {} RAM banks but the cartridge has {}",
+                num_banks,
+                self.banks.len()
+            ));
+        }
+        for bank in self.banks.iter_mut() {
+            reader.read_bytes(&mut bank[..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Offset into the ROM of `address` in 0x4000-0x7FFF with `bank` mapped
+pub fn banked_rom_offset(bank: u32, address: u16) -> usize {
+    bank as usize * 0x4000 + (address as usize & 0x3FFF)
+}
@@ -0,0 +1,186 @@
+//! MBC1: up to 2MB of ROM and 32KB of RAM
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+#[derive(Clone, Copy)]
+pub enum Mbc1Type {
+    SixteenEight,
+    FourThirtytwo,
+}
+
+#[derive(Clone)]
+pub struct Mbc1 {
+    memory_model: Mbc1Type,
+    ram_banks: RamBanks,
+    ram_active: bool,
+    // calculated from the rom size, just a cached division
+    num_rom_banks: u32,
+    //top two bits (21 & 22?) used for selecting RAM in 4_32 mode
+    mem_bank_selector: u32,
+    ram_bank_selector: u32,
+    /// MBC1M: the upper bank bits select one of four 16-bank games
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(context: &MapperContext) -> Mbc1 {
+        let num_rom_banks = context.rom_banks();
+        let rom = context.rom;
+        // 1M collections wire only 4 bits of the bank register, so
+        // every game's bank 0 (and its logo) starts on a multiple
+        // of 0x10 banks
+        let multicart = num_rom_banks == 64 && rom[0x104..0x134] == rom[0x40104..0x40134];
+        if multicart {
+            info!("MBC1 multicart detected");
+        }
+        Mbc1 {
+            memory_model: Mbc1Type::SixteenEight,
+            ram_banks: RamBanks::new(context.ram_banks),
+            ram_active: false,
+            num_rom_banks,
+            mem_bank_selector: 1,
+            ram_bank_selector: 0,
+            multicart,
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn name(&self) -> &'static str {
+        "MBC1"
+    }
+
+    fn state_tag(&self) -> u8 {
+        2
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => match self.memory_model {
+                Mbc1Type::FourThirtytwo if self.multicart => {
+                    let bank = (self.ram_bank_selector << 4) % self.num_rom_banks;
+                    banked_rom_offset(bank, address)
+                }
+                _ => address as usize,
+            },
+            _ if self.multicart => {
+                let bank = ((self.ram_bank_selector << 4) | (self.mem_bank_selector & 0xF))
+                    % self.num_rom_banks;
+                banked_rom_offset(bank, address)
+            }
+            _ => {
+                let adjusted_bank_selector = if self.mem_bank_selector == 0 {
+                    1
+                } else {
+                    self.mem_bank_selector as usize
+                } - 1;
+                let m = (0x4000 * self.num_rom_banks as usize) - 1;
+                ((adjusted_bank_selector * 0x4000) + address as usize) & m
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if self.ram_active {
+            self.ram_banks.read(self.ram_bank_selector, address)
+        } else {
+            &OPEN_BUS
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        self.ram_active && self.ram_banks.write(self.ram_bank_selector, address, value)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            //RAM activation
+            0x0000..=0x1FFF => {
+                let ram_active = (value & 0xF) == 0b1010;
+                if ram_active {
+                    debug!("MBC1: set RAM to active");
+                } else {
+                    debug!("MBC1: set RAM to inactive");
+                }
+
+                self.ram_active = ram_active;
+            }
+            // bank select
+            0x2000..=0x3FFF => {
+                let rom_bank = if (value & 0x1F) == 0 {
+                    1
+                } else {
+                    (value & 0x1F) as u32
+                };
+                debug!("MBC1: Switching to ROM bank {}", rom_bank);
+                self.mem_bank_selector = rom_bank
+            }
+            // TODO: selecting MSBs of ROM bank in 16/8 mode
+            0x4000..=0x5FFF => match self.memory_model {
+                Mbc1Type::FourThirtytwo => {
+                    debug!("MBC1 4-32: selecting ROM bank {}", value & 0x3);
+                    self.ram_bank_selector = (value & 0x3) as u32;
+                }
+                Mbc1Type::SixteenEight => {
+                    self.ram_bank_selector = (value & 0x3) as u32;
+                    // TODO: review all this MBS stuff
+                }
+            },
+            // cartridge memory model select
+            _ => {
+                self.memory_model = if (value & 1) == 1 {
+                    debug!("MBC1: Switching to 4-32 mode");
+                    // swap bits of mbs and rbs here
+                    Mbc1Type::FourThirtytwo
+                } else {
+                    debug!("MBC1: Switching to 16-8 mode");
+                    // swap bits here
+                    Mbc1Type::SixteenEight
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.memory_model = Mbc1Type::SixteenEight;
+        self.ram_active = false;
+        self.mem_bank_selector = 1;
+        self.ram_bank_selector = 0;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(matches!(self.memory_model, Mbc1Type::FourThirtytwo));
+        self.ram_banks.save_state(writer);
+        writer.write_bool(self.ram_active);
+        writer.write_u32(self.mem_bank_selector);
+        writer.write_u32(self.ram_bank_selector);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory_model = if reader.read_bool()? {
+            Mbc1Type::FourThirtytwo
+        } else {
+            Mbc1Type::SixteenEight
+        };
+        self.ram_banks.load_state(reader)?;
+        self.ram_active = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        Ok(())
+    }
+}
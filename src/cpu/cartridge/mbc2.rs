@@ -0,0 +1,120 @@
+//! MBC2: up to 256KB of ROM and 512 half-bytes of RAM built in
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// MBC2 has 512x4 bits of RAM built in, echoed across 0xA000-0xBFFF
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Clone)]
+pub struct Mbc2 {
+    /// 512 half-bytes, stored with the upper nibble set
+    ram: [u8; MBC2_RAM_SIZE],
+    ram_active: bool,
+    mem_bank_selector: u32,
+}
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram: [0xF0; MBC2_RAM_SIZE],
+            ram_active: false,
+            mem_bank_selector: 1,
+        }
+    }
+}
+
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn name(&self) -> &'static str {
+        "MBC2"
+    }
+
+    fn state_tag(&self) -> u8 {
+        5
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => banked_rom_offset(self.mem_bank_selector, address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if self.ram_active {
+            &self.ram[(address as usize) & (MBC2_RAM_SIZE - 1)]
+        } else {
+            &OPEN_BUS
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        // only the low nibble exists, the rest reads back as open bus
+        if !self.ram_active {
+            return false;
+        }
+        let byte = &mut self.ram[(address as usize) & (MBC2_RAM_SIZE - 1)];
+        let changed = *byte != value | 0xF0;
+        *byte = value | 0xF0;
+        changed
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            // bit 8 of the address picks the register
+            0x0000..=0x3FFF if address & 0x100 == 0 => {
+                self.ram_active = (value & 0xF) == 0xA;
+                debug!("MBC2: set RAM active: {}", self.ram_active);
+            }
+            0x0000..=0x3FFF => {
+                self.mem_bank_selector = if value & 0xF == 0 {
+                    1
+                } else {
+                    (value & 0xF) as u32
+                };
+                debug!("MBC2: Switching to ROM bank {}", self.mem_bank_selector);
+            }
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_active = false;
+        self.mem_bank_selector = 1;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram.iter().map(|cell| cell & 0x0F).collect()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        for (cell, saved) in self.ram.iter_mut().zip(data.iter()) {
+            *cell = saved | 0xF0;
+        }
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        MBC2_RAM_SIZE
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram[..]);
+        writer.write_bool(self.ram_active);
+        writer.write_u32(self.mem_bank_selector);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_bytes(&mut self.ram[..])?;
+        self.ram_active = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        Ok(())
+    }
+}
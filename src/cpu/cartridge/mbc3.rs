@@ -0,0 +1,199 @@
+//! MBC3: up to 2MB of ROM, 32KB of RAM and optionally a real-time clock
+
+use super::mapper::*;
+use super::rtc::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+#[derive(Clone)]
+pub struct Mbc3 {
+    //unclear if this has 16-8/4-32 mode....
+    ram_banks: RamBanks,
+    ram_active: bool, //unsure if this is needed
+    mem_bank_selector: u32,
+    /// 0-3 select a RAM bank, 0x8-0xC an RTC register
+    ram_bank_selector: u32,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(context: &MapperContext) -> Mbc3 {
+        let has_rtc = matches!(context.cartridge_type, 0x0F | 0x10);
+        Mbc3 {
+            ram_banks: RamBanks::new(context.ram_banks),
+            ram_active: false,
+            mem_bank_selector: 1,
+            ram_bank_selector: 0,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+
+    /// The RTC register selected in place of RAM, if any
+    fn rtc_register(&self) -> Option<u8> {
+        match self.ram_bank_selector {
+            rbs @ 0x8..=0xC if self.rtc.is_some() => Some(rbs as u8),
+            _ => None,
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn name(&self) -> &'static str {
+        "MBC3"
+    }
+
+    fn state_tag(&self) -> u8 {
+        3
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => {
+                let adjusted_bank_selector = if self.mem_bank_selector == 0 {
+                    1
+                } else {
+                    self.mem_bank_selector as usize
+                } - 1;
+                (adjusted_bank_selector * 0x4000) + address as usize
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if !self.ram_active {
+            return &OPEN_BUS;
+        }
+        // RTC registers are mapped in place of RAM
+        match (self.rtc_register(), &self.rtc) {
+            (Some(register), Some(rtc)) => rtc.read(register),
+            _ => self.ram_banks.read(self.ram_bank_selector, address),
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_active {
+            return false;
+        }
+        match (self.rtc_register(), &mut self.rtc) {
+            // the clock is saved with RAM
+            (Some(register), Some(rtc)) => {
+                rtc.write(register, value);
+                true
+            }
+            _ => self.ram_banks.write(self.ram_bank_selector, address, value),
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            //RAM activation
+            0x0000..=0x1FFF => {
+                let ram_active = (value & 0xF) == 0xA;
+                if ram_active {
+                    debug!("MBC3: set RAM to active");
+                } else {
+                    debug!("MBC3: set RAM to inactive");
+                }
+
+                self.ram_active = ram_active;
+            }
+            // bank select
+            0x2000..=0x3FFF => {
+                let rom_bank = if (value & 0x7F) == 0 {
+                    1
+                } else {
+                    (value & 0x7F) as u32
+                };
+                debug!("MBC3: Switching to ROM bank {}", rom_bank);
+                self.mem_bank_selector = rom_bank
+            }
+            0x4000..=0x5FFF => {
+                let v = value & 0xF;
+                if v <= 3 {
+                    self.ram_bank_selector = (value & 0x3) as u32;
+                    debug!("MBC3: Suitching to RAM bank {}", value);
+                } else if (0x8..=0xC).contains(&v) {
+                    self.ram_bank_selector = v as u32;
+                    debug!("MBC3: Selecting RTC register {:X}", v);
+                }
+            }
+            _ => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_active = false;
+        self.mem_bank_selector = 1;
+        self.ram_bank_selector = 0;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+
+    fn clock_footer_sizes(&self) -> &'static [usize] {
+        match self.rtc {
+            Some(_) => &[RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_32BIT_TIME],
+            None => &[],
+        }
+    }
+
+    fn clock_footer(&self, unix_time: u64) -> Vec<u8> {
+        match self.rtc {
+            Some(ref rtc) => rtc.to_footer(unix_time).to_vec(),
+            None => vec![],
+        }
+    }
+
+    fn load_clock_footer(&mut self, footer: &[u8], unix_time: u64) -> Result<(), String> {
+        match self.rtc {
+            Some(ref mut rtc) => rtc.load_footer(footer, unix_time),
+            None => Ok(()),
+        }
+    }
+
+    fn step(&mut self, cycles: u32, cycles_per_second: u64) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.step(cycles, cycles_per_second);
+        }
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram_banks.save_state(writer);
+        writer.write_bool(self.ram_active);
+        writer.write_u32(self.mem_bank_selector);
+        writer.write_u32(self.ram_bank_selector);
+        writer.write_bool(self.rtc.is_some());
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_banks.load_state(reader)?;
+        self.ram_active = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        if reader.read_bool()? != self.rtc.is_some() {
+            return Err("Save-state was made with a different cartridge type".to_string());
+        }
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! MBC5: up to 8MB of ROM, 128KB of RAM and optionally a rumble motor
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+#[derive(Clone)]
+pub struct Mbc5 {
+    ram_banks: RamBanks,
+    ram_active: bool,
+    num_rom_banks: u32,
+    mem_bank_selector: u32,
+    ram_bank_selector: u32,
+    /// the cartridge has a rumble motor
+    has_rumble: bool,
+    rumble_active: bool,
+}
+
+impl Mbc5 {
+    pub fn new(context: &MapperContext) -> Mbc5 {
+        Mbc5 {
+            ram_banks: RamBanks::new(context.ram_banks),
+            ram_active: false,
+            num_rom_banks: context.rom_banks(),
+            mem_bank_selector: 1,
+            ram_bank_selector: 0,
+            has_rumble: matches!(context.cartridge_type, 0x1C..=0x1E),
+            rumble_active: false,
+        }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn name(&self) -> &'static str {
+        "MBC5"
+    }
+
+    fn state_tag(&self) -> u8 {
+        4
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => {
+                let m = (0x4000 * self.num_rom_banks as usize) - 1;
+                banked_rom_offset(self.mem_bank_selector, address) & m
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if self.ram_active {
+            self.ram_banks.read(self.ram_bank_selector, address)
+        } else {
+            &OPEN_BUS
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        self.ram_active && self.ram_banks.write(self.ram_bank_selector, address, value)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let ram_active = (value & 0xF) == 0xA;
+                if ram_active != self.ram_active {
+                    if ram_active {
+                        debug!("MBC5: set RAM to active");
+                    } else {
+                        debug!("MBC5: set RAM to inactive");
+                    }
+                }
+
+                self.ram_active = ram_active;
+            }
+            // bank select 1
+            0x2000..=0x2FFF => {
+                self.mem_bank_selector &= !0xFF;
+                self.mem_bank_selector |= value as u32;
+                debug!("MBC5: Switching to ROM bank {}", self.mem_bank_selector);
+            }
+            // bank select 2
+            0x3000..=0x3FFF => {
+                self.mem_bank_selector &= 0xFF;
+                self.mem_bank_selector |= (value as u32 & 1) << 8;
+                debug!("MBC5: Switching to ROM bank {}", self.mem_bank_selector);
+            }
+            // ram select
+            // on rumble cartridges bit 3 drives the motor instead
+            0x4000..=0x5FFF if self.has_rumble => {
+                self.ram_bank_selector = value as u32 & 0x7;
+                self.rumble_active = value & 0x8 == 0x8;
+                debug!("MBC5: Switching to RAM bank {}", self.ram_bank_selector);
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank_selector = value as u32 & 0xF;
+                debug!("MBC5: Switching to RAM bank {}", self.ram_bank_selector);
+            }
+            _ => {
+                // nop
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_active = false;
+        self.mem_bank_selector = 1;
+        self.ram_bank_selector = 0;
+        self.rumble_active = false;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+
+    fn rumble_active(&self) -> bool {
+        self.rumble_active
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram_banks.save_state(writer);
+        writer.write_bool(self.ram_active);
+        writer.write_u32(self.mem_bank_selector);
+        writer.write_u32(self.ram_bank_selector);
+        writer.write_bool(self.rumble_active);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_banks.load_state(reader)?;
+        self.ram_active = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        self.rumble_active = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+//! MBC7 peripherals: the 93LC56 serial EEPROM and the accelerometer
+//!
+//! Both are accessed through registers at 0xA000-0xAFFF once RAM has been
+//! enabled with 0x0A at 0x0000 and 0x40 at 0x4000.  The register is picked
+//! by bits 4-7 of the address.
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// The EEPROM holds 128 16-bit words
+pub const MBC7_EEPROM_SIZE: usize = 256;
+
+/// Accelerometer reading when level
+const ACCELEROMETER_CENTER: f32 = 0x81D0 as f32;
+/// Change in the accelerometer reading per g of tilt
+const ACCELEROMETER_PER_G: f32 = 0x70 as f32;
+
+static MBC7_ZERO: u8 = 0x00;
+static MBC7_OPEN_BUS: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EepromState {
+    /// Waiting for a start bit
+    Idle,
+    /// Shifting in the 2-bit opcode and 8-bit address
+    Command,
+    /// Shifting data out, a word at a time
+    Read,
+    /// Shifting in a word to write to `address`
+    Write,
+    /// Shifting in a word to write everywhere
+    WriteAll,
+}
+
+impl EepromState {
+    fn tag(self) -> u8 {
+        match self {
+            EepromState::Idle => 0,
+            EepromState::Command => 1,
+            EepromState::Read => 2,
+            EepromState::Write => 3,
+            EepromState::WriteAll => 4,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<EepromState, String> {
+        Ok(match tag {
+            0 => EepromState::Idle,
+            1 => EepromState::Command,
+            2 => EepromState::Read,
+            3 => EepromState::Write,
+            4 => EepromState::WriteAll,
+            n => return Err(format!("Invalid MBC7 EEPROM state {}", n)),
+        })
+    }
+}
+
+/// A 93LC56 EEPROM in 16-bit mode, driven a bit at a time
+///
+/// The game sets CS (bit 7), CLK (bit 6) and DI (bit 1) by writing to the
+/// EEPROM register; bits are sampled on the rising edge of CLK and DO is
+/// read back in bit 0.  Commands are a start bit, a 2-bit opcode and an
+/// 8-bit address: 10 reads, 01 writes, 11 erases, and 00 with the top two
+/// address bits 11/00/10/01 enables writes, disables writes, erases
+/// everything or writes everything.
+#[derive(Debug, Clone)]
+pub struct Eeprom {
+    pub data: [u8; MBC7_EEPROM_SIZE],
+    state: EepromState,
+    shift: u16,
+    bit_count: u8,
+    address: u8,
+    write_enabled: bool,
+    /// the last value written to the register, with DO in bit 0
+    register: u8,
+}
+
+impl Eeprom {
+    pub fn new() -> Eeprom {
+        Eeprom {
+            data: [0xFF; MBC7_EEPROM_SIZE],
+            state: EepromState::Idle,
+            shift: 0,
+            bit_count: 0,
+            address: 0,
+            write_enabled: false,
+            register: 0x01,
+        }
+    }
+
+    fn word(&self, address: u8) -> u16 {
+        let i = (address & 0x7F) as usize * 2;
+        u16::from_be_bytes([self.data[i], self.data[i + 1]])
+    }
+
+    /// Writes a word if writes are enabled, returning whether it changed
+    fn set_word(&mut self, address: u8, value: u16) -> bool {
+        if !self.write_enabled || self.word(address) == value {
+            return false;
+        }
+        let i = (address & 0x7F) as usize * 2;
+        self.data[i..i + 2].copy_from_slice(&value.to_be_bytes());
+        true
+    }
+
+    /// Writes `value` to every word, returning whether any changed
+    fn set_all_words(&mut self, value: u16) -> bool {
+        let mut changed = false;
+        for address in 0..0x80 {
+            changed |= self.set_word(address, value);
+        }
+        changed
+    }
+
+    pub fn read(&self) -> &u8 {
+        &self.register
+    }
+
+    /// Writes the register, returning whether a command changed the data
+    pub fn write(&mut self, value: u8) -> bool {
+        let cs = value & 0x80 != 0;
+        let rising_clock = self.register & 0x40 == 0 && value & 0x40 != 0;
+        let mut data_out = self.register & 1;
+        let mut changed = false;
+
+        if !cs {
+            // dropping CS aborts any command; writes finish instantly so
+            // the chip reports ready
+            self.state = EepromState::Idle;
+            data_out = 1;
+        } else if rising_clock {
+            let bit = (value >> 1) & 1;
+            (data_out, changed) = self.clock(bit);
+        }
+        self.register = (value & 0xC2) | data_out;
+        changed
+    }
+
+    /// Handles one clocked bit, returning the new DO and whether the data
+    /// changed
+    fn clock(&mut self, bit: u8) -> (u8, bool) {
+        match self.state {
+            EepromState::Idle => {
+                if bit == 1 {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bit_count = 0;
+                }
+                (1, false)
+            }
+            EepromState::Command => {
+                self.shift = (self.shift << 1) | bit as u16;
+                self.bit_count += 1;
+                if self.bit_count < 10 {
+                    return (1, false);
+                }
+                self.address = self.shift as u8;
+                self.bit_count = 0;
+                self.run_command((self.shift >> 8) as u8)
+            }
+            EepromState::Read => {
+                if self.bit_count == 16 {
+                    // sequential reads continue with the next word
+                    self.address = self.address.wrapping_add(1);
+                    self.shift = self.word(self.address);
+                    self.bit_count = 0;
+                }
+                let out = (self.shift >> 15) as u8;
+                self.shift <<= 1;
+                self.bit_count += 1;
+                (out, false)
+            }
+            EepromState::Write | EepromState::WriteAll => {
+                self.shift = (self.shift << 1) | bit as u16;
+                self.bit_count += 1;
+                if self.bit_count < 16 {
+                    return (1, false);
+                }
+                let changed = if self.state == EepromState::WriteAll {
+                    self.set_all_words(self.shift)
+                } else {
+                    self.set_word(self.address, self.shift)
+                };
+                self.state = EepromState::Idle;
+                (1, changed)
+            }
+        }
+    }
+
+    fn run_command(&mut self, opcode: u8) -> (u8, bool) {
+        self.state = EepromState::Idle;
+        self.shift = 0;
+        let changed = match opcode {
+            0b10 => {
+                self.state = EepromState::Read;
+                self.shift = self.word(self.address);
+                // a dummy 0 bit comes before the data
+                return (0, false);
+            }
+            0b01 => {
+                self.state = EepromState::Write;
+                false
+            }
+            0b11 => self.set_word(self.address, 0xFFFF),
+            _ => match self.address >> 6 {
+                0b11 => {
+                    self.write_enabled = true;
+                    false
+                }
+                0b00 => {
+                    self.write_enabled = false;
+                    false
+                }
+                0b10 => self.set_all_words(0xFFFF),
+                _ => {
+                    self.state = EepromState::WriteAll;
+                    false
+                }
+            },
+        };
+        (1, changed)
+    }
+}
+
+impl Default for Eeprom {
+    fn default() -> Eeprom {
+        Eeprom::new()
+    }
+}
+
+impl SaveState for Eeprom {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+        writer.write_u8(self.state.tag());
+        writer.write_u16(self.shift);
+        writer.write_u8(self.bit_count);
+        writer.write_u8(self.address);
+        writer.write_bool(self.write_enabled);
+        writer.write_u8(self.register);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_bytes(&mut self.data)?;
+        self.state = EepromState::from_tag(reader.read_u8()?)?;
+        self.shift = reader.read_u16()?;
+        self.bit_count = reader.read_u8()?;
+        self.address = reader.read_u8()?;
+        self.write_enabled = reader.read_bool()?;
+        self.register = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/// The MBC7's registers other than ROM banking
+#[derive(Debug, Clone, Default)]
+pub struct Mbc7Registers {
+    pub eeprom: Eeprom,
+    /// The current tilt in g: x is positive to the right, y towards the
+    /// bottom of the screen
+    tilt: (f32, f32),
+    /// X low, X high, Y low, Y high as last latched
+    latched: [u8; 4],
+    /// The latch was erased with 0x55 and is waiting for 0xAA
+    latch_ready: bool,
+}
+
+impl Mbc7Registers {
+    pub fn new() -> Mbc7Registers {
+        Mbc7Registers {
+            eeprom: Eeprom::new(),
+            tilt: (0.0, 0.0),
+            latched: [0x00, 0x80, 0x00, 0x80],
+            latch_ready: false,
+        }
+    }
+
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0));
+    }
+
+    /// Reads the register at `index` in 0xA000-0xAFFF
+    pub fn read(&self, index: u16) -> &u8 {
+        match (index >> 4) & 0xF {
+            0x2..=0x5 => &self.latched[((index >> 4) & 0xF) as usize - 2],
+            0x6 => &MBC7_ZERO,
+            0x8 => self.eeprom.read(),
+            _ => &MBC7_OPEN_BUS,
+        }
+    }
+
+    /// Writes the register at `index` in 0xA000-0xAFFF, returning whether
+    /// the EEPROM's data changed
+    pub fn write(&mut self, index: u16, value: u8) -> bool {
+        match (index >> 4) & 0xF {
+            0x0 if value == 0x55 => {
+                self.latched = [0x00, 0x80, 0x00, 0x80];
+                self.latch_ready = true;
+                false
+            }
+            0x1 if value == 0xAA && self.latch_ready => {
+                // tilting right lowers X, tilting down raises Y
+                let x = (ACCELEROMETER_CENTER - self.tilt.0 * ACCELEROMETER_PER_G) as u16;
+                let y = (ACCELEROMETER_CENTER + self.tilt.1 * ACCELEROMETER_PER_G) as u16;
+                self.latched[0..2].copy_from_slice(&x.to_le_bytes());
+                self.latched[2..4].copy_from_slice(&y.to_le_bytes());
+                self.latch_ready = false;
+                false
+            }
+            0x8 => self.eeprom.write(value),
+            _ => false,
+        }
+    }
+}
+
+impl SaveState for Mbc7Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.eeprom.save_state(writer);
+        writer.write_bytes(&self.latched);
+        writer.write_bool(self.latch_ready);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.eeprom.load_state(reader)?;
+        reader.read_bytes(&mut self.latched)?;
+        self.latch_ready = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The MBC7 mapper: ROM banking in front of `Mbc7Registers`
+#[derive(Clone)]
+pub struct Mbc7 {
+    ram_active: bool,
+    /// 0x40 was written to 0x4000-0x5FFF, the second RAM enable
+    ram_unlocked: bool,
+    mem_bank_selector: u32,
+    registers: Mbc7Registers,
+}
+
+impl Mbc7 {
+    pub fn new() -> Mbc7 {
+        Mbc7 {
+            ram_active: false,
+            ram_unlocked: false,
+            mem_bank_selector: 1,
+            registers: Mbc7Registers::new(),
+        }
+    }
+}
+
+impl Default for Mbc7 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapper for Mbc7 {
+    fn name(&self) -> &'static str {
+        "MBC7"
+    }
+
+    fn state_tag(&self) -> u8 {
+        9
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => banked_rom_offset(self.mem_bank_selector, address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        // the registers need both enables to be set
+        if self.ram_active && self.ram_unlocked && address <= 0xAFFF {
+            self.registers.read(address)
+        } else {
+            &OPEN_BUS
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        // the EEPROM is behind these registers
+        if self.ram_active && self.ram_unlocked && address <= 0xAFFF {
+            self.registers.write(address, value)
+        } else {
+            false
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_active = value == 0x0A,
+            0x2000..=0x3FFF => {
+                self.mem_bank_selector = value as u32 & 0x7F;
+                debug!("MBC7: Switching to ROM bank {}", self.mem_bank_selector);
+            }
+            0x4000..=0x5FFF => self.ram_unlocked = value == 0x40,
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_active = false;
+        self.ram_unlocked = false;
+        self.mem_bank_selector = 1;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.registers.eeprom.data.to_vec()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let end = data.len().min(MBC7_EEPROM_SIZE);
+        self.registers.eeprom.data[..end].copy_from_slice(&data[..end]);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        MBC7_EEPROM_SIZE
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.registers.set_tilt(x, y);
+    }
+}
+
+impl SaveState for Mbc7 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_active);
+        writer.write_bool(self.ram_unlocked);
+        writer.write_u32(self.mem_bank_selector);
+        self.registers.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_active = reader.read_bool()?;
+        self.ram_unlocked = reader.read_bool()?;
+        self.mem_bank_selector = reader.read_u32()?;
+        self.registers.load_state(reader)
+    }
+}
@@ -0,0 +1,165 @@
+//! MMM01: the multicart mapper
+//!
+//! Boots into a menu in the last 32KB of the ROM, which sets up the banks
+//! of the chosen game and then locks them.
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+#[derive(Clone)]
+pub struct Mmm01 {
+    ram_banks: RamBanks,
+    ram_active: bool,
+    /// the dump starts with the menu rather than the first game
+    has_menu: bool,
+    /// false while the menu is running
+    mapped: bool,
+    /// bank bits 0-4
+    rom_bank_low: u32,
+    /// bank bits 5-8, only writable by the menu
+    rom_bank_high: u32,
+    /// low bank bits fixed by the menu
+    rom_bank_mask: u32,
+    ram_bank_selector: u32,
+    num_rom_banks: u32,
+}
+
+impl Mmm01 {
+    /// A full multicart dump, with the menu at the end
+    pub fn new(context: &MapperContext) -> Mmm01 {
+        let mut mmm01 = Mmm01 {
+            ram_banks: RamBanks::new(context.ram_banks),
+            ram_active: false,
+            has_menu: true,
+            mapped: false,
+            rom_bank_low: 0,
+            rom_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_selector: 0,
+            num_rom_banks: context.rom_banks(),
+        };
+        mmm01.reset();
+        mmm01
+    }
+
+    /// A dump that starts with the menu, which is mapped like a game
+    pub fn without_menu(context: &MapperContext) -> Mmm01 {
+        let mut mmm01 = Mmm01::new(context);
+        mmm01.has_menu = false;
+        mmm01.reset();
+        mmm01
+    }
+}
+
+impl Mapper for Mmm01 {
+    fn name(&self) -> &'static str {
+        "MMM01"
+    }
+
+    fn state_tag(&self) -> u8 {
+        6
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF if self.mapped => {
+                (self.rom_bank_high << 5) | (self.rom_bank_low & self.rom_bank_mask)
+            }
+            0x0000..=0x3FFF => self.num_rom_banks.saturating_sub(2),
+            _ if !self.mapped => self.num_rom_banks.saturating_sub(1),
+            _ if self.rom_bank_low & !self.rom_bank_mask & 0x1F == 0 => {
+                (self.rom_bank_high << 5) | self.rom_bank_low | 1
+            }
+            _ => (self.rom_bank_high << 5) | self.rom_bank_low,
+        } % self.num_rom_banks;
+        banked_rom_offset(bank, address)
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        if self.ram_active {
+            self.ram_banks.read(self.ram_bank_selector, address)
+        } else {
+            &OPEN_BUS
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        self.ram_active && self.ram_banks.write(self.ram_bank_selector, address, value)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_active = (value & 0xF) == 0xA;
+                if !self.mapped && value & 0x40 == 0x40 {
+                    debug!("MMM01: mapping game in banks {:X}", self.rom_bank_high << 5);
+                    self.mapped = true;
+                }
+            }
+            0x2000..=0x3FFF => {
+                // bits the menu masked off belong to the game's base bank
+                let writable = !self.rom_bank_mask & 0x1F;
+                self.rom_bank_low = (self.rom_bank_low & !writable) | (value as u32 & writable);
+                if !self.mapped {
+                    self.rom_bank_high = (self.rom_bank_high & !0x3) | ((value as u32 >> 5) & 0x3);
+                }
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank_selector = (self.ram_bank_selector & !0x3) | (value as u32 & 0x3);
+                if !self.mapped {
+                    self.ram_bank_selector = (self.ram_bank_selector & 0x3) | (value as u32 & 0xC);
+                    self.rom_bank_high = (self.rom_bank_high & 0x3) | ((value as u32 >> 2) & 0xC);
+                }
+            }
+            _ => {
+                if !self.mapped {
+                    self.rom_bank_mask = ((value as u32 >> 2) & 0xF) << 1;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_active = false;
+        self.mapped = !self.has_menu;
+        self.rom_bank_low = if self.has_menu { 0 } else { 1 };
+        self.rom_bank_high = 0;
+        self.rom_bank_mask = 0;
+        self.ram_bank_selector = 0;
+    }
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_banks.to_bytes()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.ram_banks.load_bytes(data);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        self.ram_banks.size()
+    }
+}
+
+impl SaveState for Mmm01 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram_banks.save_state(writer);
+        writer.write_bool(self.ram_active);
+        writer.write_bool(self.mapped);
+        writer.write_u32(self.rom_bank_low);
+        writer.write_u32(self.rom_bank_high);
+        writer.write_u32(self.rom_bank_mask);
+        writer.write_u32(self.ram_bank_selector);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ram_banks.load_state(reader)?;
+        self.ram_active = reader.read_bool()?;
+        self.mapped = reader.read_bool()?;
+        self.rom_bank_low = reader.read_u32()?;
+        self.rom_bank_high = reader.read_u32()?;
+        self.rom_bank_mask = reader.read_u32()?;
+        self.ram_bank_selector = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -1,47 +1,80 @@
-use std::ops::{Index, IndexMut};
-use std::path::PathBuf;
-
-use gameboy_rom::header::RomType;
+pub mod camera;
+pub mod header;
+pub mod huc1;
+pub mod huc3;
+pub mod mapper;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod mbc7;
+pub mod mmm01;
+pub mod rom_only;
+pub mod rtc;
 
+use std::ops::{Index, IndexMut};
+use std::path::Path;
+
+use self::camera::*;
+use self::huc1::Huc1;
+use self::huc3::Huc3;
+use self::mapper::*;
+use self::mbc1::Mbc1;
+use self::mbc2::Mbc2;
+use self::mbc3::Mbc3;
+use self::mbc5::Mbc5;
+use self::mbc7::Mbc7;
+use self::mmm01::Mmm01;
+use self::rom_only::RomOnly;
+use self::rtc::*;
 use crate::cpu::constants::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
 
-/// A thing that is like a Cartridge
-pub trait Cartridgey {
-    fn load(rom_data: Vec<u8>) -> Result<Cartridge, String>;
-
-    fn read_rom_value(&self, index: u16) -> byte;
-
-    #[allow(unused_variables)]
-    fn read_ram_value(&self, index: u16) -> byte {
-        panic!("This cartridge type does not provide RAM")
-    }
-
-    #[allow(unused_variables)]
-    fn write_ram_value(&mut self, index: u16, value: byte) {
-        panic!("This cartridge type does not provide RAM")
-    }
-}
-
-/// The things that are constant between all types of cartridges
-/// This also includes things like video ram
-/// Thus this struct is best understood as dealing with any and all things
-/// addressable
+/// The ROM of a cartridge and the mapper in front of it
 ///
-/// TODO: memory locking during certain periods (i.e. the rest of the virtual
-/// memory system...)
-#[derive(Clone, Default)]
+/// Reads and writes of 0x0000-0x7FFF and 0xA000-0xBFFF are passed on to
+/// the `Mapper` picked from the header when the ROM is loaded.
+#[derive(Clone)]
 pub struct Cartridge {
     /// it's safe to assume that the size of this vec is at least 0x4000
     entire_rom_data: Vec<u8>,
-    cart_sub: Option<CartridgeSubType>,
+    mapper: Box<dyn Mapper>,
     // used when indexing into RAM when there's no RAM, etc.
     dummy_value: u8,
     pub gbc: bool,
     pub sgb: bool,
+    /// The header says RAM is kept by a battery, so it is worth saving
+    pub battery: bool,
+    /// RAM has been written to since it was last saved
+    ram_dirty: bool,
 }
 
-impl Cartridgey for Cartridge {
-    fn load(rom_buffer: Vec<u8>) -> Result<Cartridge, String> {
+impl Default for Cartridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The built-in mapper for a cartridge type, if there is one
+fn builtin_mapper(context: &MapperContext) -> Option<Box<dyn Mapper>> {
+    Some(match context.cartridge_type {
+        0x00 | 0x08 | 0x09 => Box::new(RomOnly::new()),
+        0x01..=0x03 => Box::new(Mbc1::new(context)),
+        0x05 | 0x06 => Box::new(Mbc2::new()),
+        // the menu is at the start of this dump, so map like a game
+        0x0B..=0x0D => Box::new(Mmm01::without_menu(context)),
+        0x0F..=0x13 => Box::new(Mbc3::new(context)),
+        0x19..=0x1E => Box::new(Mbc5::new(context)),
+        0x22 => Box::new(Mbc7::new()),
+        0xFC => Box::new(PocketCamera::new(context)),
+        0xFE => Box::new(Huc3::new(context)),
+        0xFF => Box::new(Huc1::new(context)),
+        _ => return None,
+    })
+}
+
+impl Cartridge {
+    pub fn load(rom_buffer: Vec<u8>) -> Result<Cartridge, String> {
         let rom = gameboy_rom::GameBoyRom::new(rom_buffer.as_slice());
         let rom_header = rom.parse_header()?;
 
@@ -59,427 +92,131 @@ impl Cartridgey for Cartridge {
                 rom_buffer.len()
             ));
         }
-
-        match rom_header.rom_type {
-            RomType::RomOnly | RomType::RomRam | RomType::RomRamBattery => Ok(Cartridge {
-                gbc: rom_header.gameboy_color.supports_color(),
-                sgb: rom_header.super_gameboy,
-                entire_rom_data: rom_buffer,
-                cart_sub: Some(CartridgeSubType::RomOnly {
-                    ram_bank: [0u8; 0x2000],
-                }),
-                dummy_value: 0,
-            }),
-            RomType::Mbc1 | RomType::Mbc1Ram | RomType::Mbc1RamBattery => {
-                const RAM_BANK_SIZE: usize = 0x2000;
-                // TODO: figure out why I had this as an assert before
-                //debug_assert_eq!(RAM_BANK_SIZE, rom_header.ram_bank_size as usize);
-                //let ram_active = rom_header.ram_banks > 0;
-                Ok(Cartridge {
-                    cart_sub: Some(CartridgeSubType::Mbc1 {
-                        memory_model: Mbc1Type::SixteenEight,
-                        ram_banks: vec![[0; RAM_BANK_SIZE]; rom_header.ram_banks as usize],
-                        ram_active: false,
-                        mem_bank_selector: 1,
-                        ram_bank_selector: 0,
-                        num_rom_banks: (rom_buffer.len() / 0x4000) as u32,
-                    }),
-                    gbc: rom_header.gameboy_color.supports_color(),
-                    sgb: rom_header.super_gameboy,
-                    dummy_value: 0,
-                    entire_rom_data: rom_buffer,
-                })
+        if let Ok(rom_info) = header::rom_info(&rom_buffer) {
+            for problem in rom_info.problems() {
+                warn!("ROM header: {}", problem);
             }
-            RomType::Mbc3
-            | RomType::Mbc3Ram
-            | RomType::Mbc3RamBattery
-            | RomType::Mbc3TimerRamBattery => {
-                const RAM_BANK_SIZE: usize = 0x2000;
-                //debug_assert!(RAM_BANK_SIZE == rom_header.ram_bank_size as usize);
-
-                Ok(Cartridge {
-                    cart_sub: Some(CartridgeSubType::Mbc3 {
-                        ram_banks: vec![[0; RAM_BANK_SIZE]; rom_header.ram_banks as usize],
-                        ram_active: false,
-                        mem_bank_selector: 1,
-                        ram_bank_selector: 0,
-                    }),
-                    gbc: rom_header.gameboy_color.supports_color(),
-                    sgb: rom_header.super_gameboy,
-                    entire_rom_data: rom_buffer,
-                    dummy_value: 0,
-                })
-            }
-            RomType::Mbc5
-            | RomType::Mbc5Ram
-            | RomType::Mbc5RamBattery
-            | RomType::Mbc5Rumble
-            | RomType::Mbc5RumbleSram
-            | RomType::Mbc5RumbleSramBattery => {
-                const RAM_BANK_SIZE: usize = 0x2000;
-                // TODO: figure out why I had this as an assert before
-                //assert_eq!(RAM_BANK_SIZE, rom_header.ram_bank_size as usize);
-
-                Ok(Cartridge {
-                    cart_sub: Some(CartridgeSubType::Mbc5 {
-                        ram_banks: vec![[0; RAM_BANK_SIZE]; rom_header.ram_banks as usize],
-                        ram_active: false,
-                        mem_bank_selector: 1,
-                        ram_bank_selector: 0,
-                        num_rom_banks: (rom_buffer.len() / 0x4000) as u32,
-                    }),
-                    gbc: rom_header.gameboy_color.supports_color(),
-                    sgb: rom_header.super_gameboy,
-                    entire_rom_data: rom_buffer,
-                    dummy_value: 0,
-                })
-            }
-            otherwise => Err(format!("Cartridge type {:?} is not supported", otherwise)),
         }
+
+        let mut cartridge_type = rom_buffer[0x147];
+        let mut ram_banks = rom_header.ram_banks as usize;
+        let mut gbc = rom_header.gameboy_color.supports_color();
+        let mut sgb = rom_header.super_gameboy;
+
+        // MMM01 dumps start with the first game; the menu and the header
+        // naming the mapper are in the last 32KB
+        let menu_start = rom_buffer.len().saturating_sub(0x8000);
+        let mut mmm01_menu = false;
+        if !matches!(cartridge_type, 0x0B..=0x0D)
+            && matches!(rom_buffer.get(menu_start + 0x147), Some(0x0B..=0x0D))
+        {
+            let menu = gameboy_rom::GameBoyRom::new(&rom_buffer[menu_start..]);
+            let menu_header = menu.parse_header()?;
+            info!("MMM01 multicart: {}", menu_header.game_title);
+            cartridge_type = rom_buffer[menu_start + 0x147];
+            ram_banks = menu_header.ram_banks as usize;
+            gbc = menu_header.gameboy_color.supports_color();
+            sgb = menu_header.super_gameboy;
+            mmm01_menu = true;
+        }
+
+        let context = MapperContext {
+            rom: &rom_buffer,
+            cartridge_type,
+            ram_banks,
+        };
+        let mapper = if let Some(constructor) = registered_mapper(cartridge_type) {
+            constructor(&context)
+        } else if mmm01_menu {
+            Box::new(Mmm01::new(&context))
+        } else {
+            builtin_mapper(&context).unwrap_or_else(|| {
+                warn!(
+                    "Cartridge type 0x{:02X} is not supported, falling back to open bus",
+                    cartridge_type
+                );
+                Box::new(OpenBus)
+            })
+        };
+        info!("Mapper: {}", mapper.name());
+
+        Ok(Cartridge {
+            mapper,
+            gbc,
+            sgb,
+            dummy_value: 0,
+            battery: header::has_battery(cartridge_type),
+            ram_dirty: false,
+            entire_rom_data: rom_buffer,
+        })
     }
 
-    #[allow(unused_variables)]
-    fn read_rom_value(&self, index: u16) -> byte {
-        unimplemented!()
-        //        *self.index(index)
+    /// Reads 0x0000-0x7FFF through the mapper
+    pub fn read_rom_value(&self, index: u16) -> byte {
+        self[index]
     }
 
-    #[allow(unused_variables)]
-    fn read_ram_value(&self, index: u16) -> byte {
-        unimplemented!()
-        //*self.index(index)
-        //panic!("This cartridge type does not provide RAM")
+    /// Reads 0xA000-0xBFFF through the mapper
+    pub fn read_ram_value(&self, index: u16) -> byte {
+        self[index]
     }
 
-    fn write_ram_value(&mut self, index: u16, value: byte) {
-        match self.cart_sub {
-            Some(CartridgeSubType::Mbc1 {
-                memory_model: ref mut mm,
-                mem_bank_selector: ref mut mbs,
-                ram_bank_selector: ref mut rbs,
-                ram_active: ref mut ra,
-                ..
-            }) if index <= 0x7FFF => {
-                match index {
-                    //RAM activation
-                    0x0000..=0x1FFF => {
-                        let ram_active = (value & 0xF) == 0b1010;
-                        if ram_active {
-                            debug!("MBC1: set RAM to active");
-                        } else {
-                            debug!("MBC1: set RAM to inactive");
-                        }
-
-                        *ra = ram_active;
-                    }
-                    // bank select
-                    0x2000..=0x3FFF => {
-                        let rom_bank = if (value & 0x1F) == 0 {
-                            1
-                        } else {
-                            (value & 0x1F) as u32
-                        };
-                        debug!("MBC1: Switching to ROM bank {}", rom_bank);
-                        *mbs = rom_bank
-                    }
-                    // TODO: selecting MSBs of ROM bank in 16/8 mode
-                    0x4000..=0x5FFF => match *mm {
-                        Mbc1Type::FourThirtytwo => {
-                            debug!("MBC1 4-32: selecting ROM bank {}", value & 0x3);
-                            *rbs = (value & 0x3) as u32;
-                        }
-                        Mbc1Type::SixteenEight => {
-                            *rbs = (value & 0x3) as u32;
-                            // TODO: review all this MBS stuff
-                            /*
-                            *mbs &= !(0x3 << 5);
-                            *mbs |= ((value & 0x3) as u32) << 4;
-                            *mbs %= rb.len() as u32;
-                            if *mbs == 0 {
-                                *mbs = 1;
-                            }
-                            debug!("MBC1 16-8: selecting ROM bank {}", *mbs);
-                            */
-                        }
-                    },
-                    // cartridge memory model select
-                    0x6000..=0x7FFF => {
-                        *mm = if (index & 1) == 1 {
-                            debug!("MBC1: Switching to 4-32 mode");
-                            // swap bits of mbs and rbs here
-                            Mbc1Type::FourThirtytwo
-                        } else {
-                            debug!("MBC1: Switching to 16-8 mode");
-                            // swap bits here
-                            Mbc1Type::SixteenEight
-                        }
-                    }
-                    _ => self[index] = value,
-                }
-            }
-            Some(CartridgeSubType::Mbc3 {
-                mem_bank_selector: ref mut mbs,
-                ram_active: ref mut ra,
-                ram_bank_selector: ref mut rbs,
-                ..
-            }) => {
-                match index {
-                    //RAM activation
-                    0x0000..=0x1FFF => {
-                        let ram_active = (value & 0xF) == 0xA;
-                        if ram_active {
-                            debug!("MBC3: set RAM to active");
-                        } else {
-                            debug!("MBC3: set RAM to inactive");
-                        }
-
-                        *ra = ram_active;
-                    }
-                    // bank select
-                    0x2000..=0x3FFF => {
-                        let rom_bank = if (value & 0x7F) == 0 {
-                            1
-                        } else {
-                            (value & 0x7F) as u32
-                        };
-                        debug!("MBC3: Switching to ROM bank {}", rom_bank);
-                        *mbs = rom_bank
-                    }
-                    0x4000..=0x5FFF => {
-                        let v = value & 0xF;
-                        if v <= 3 {
-                            *rbs = (value & 0x3) as u32;
-                            debug!("MBC3: Suitching to RAM bank {}", value);
-                        } else if v >= 0x8 && v <= 0xC {
-                            // TODO: RTC register
-                        }
-                    }
-                    0x6000..=0x7FFF => {
-                        // TODO: latch clock data
-                    }
-                    _ => debug!(
-                        "MBC3 likely not fully implemented!: writing 0x{:X} to 0x{:X}",
-                        value, index
-                    ),
+    /// Writes 0x0000-0x7FFF (the mapper's registers) or 0xA000-0xBFFF
+    pub fn write_ram_value(&mut self, index: u16, value: byte) {
+        match index {
+            0x0000..=0x7FFF => self.mapper.write_register(index, value),
+            0xA000..=0xBFFF => {
+                if self.mapper.write_ram(index, value) {
+                    self.ram_dirty = true;
                 }
             }
-            Some(CartridgeSubType::Mbc5 {
-                mem_bank_selector: ref mut mbs,
-                ram_bank_selector: ref mut rbs,
-                ram_active: ref mut ra,
-                ..
-            }) if index <= 0x7FFF => match index {
-                0x0000..=0x1FFF => {
-                    let ram_active = (value & 0xF) == 0xA;
-                    if ram_active != *ra {
-                        if ram_active {
-                            debug!("MBC5: set RAM to active");
-                        } else {
-                            debug!("MBC5: set RAM to inactive");
-                        }
-                    }
-
-                    *ra = ram_active;
-                }
-                // bank select 1
-                0x2000..=0x2FFF => {
-                    *mbs &= !0xFF;
-                    *mbs |= value as u32;
-                    debug!("MBC5: Switching to ROM bank {}", *mbs);
-                }
-                // bank select 2
-                0x3000..=0x3FFF => {
-                    *mbs &= 0xFF;
-                    *mbs |= (value as u32 & 1) << 8;
-                    debug!("MBC5: Switching to ROM bank {}", *mbs);
-                }
-                // ram select
-                0x4000..=0x5FFF => {
-                    *rbs = value as u32 & 0xF;
-                    debug!("MBC5: Switching to RAM bank {}", *rbs);
-                }
-                0x6000..=0x7FFF => {
-                    // nop
-                }
-                _ => self[index] = value,
-                /*
-                _ => debug!(
-                    "Out of bounds write in MBC5: writing 0x{:X} to 0x:{:X}",
-                    value, index
-                ),
-                */
-            },
-            Some(CartridgeSubType::RomOnly { .. }) | None | _ => self[index] = value,
+            _ => error!("Address 0x{:X} is not on the cartridge", index),
         }
+    }
 
-        //panic!("This cartridge type does not provide RAM")
+    /// The name of the mapper in use, e.g. "MBC3"
+    pub fn mapper_name(&self) -> &'static str {
+        self.mapper.name()
     }
 }
 
-#[derive(Clone)]
-pub enum CartridgeSubType {
-    RomOnly {
-        ram_bank: [byte; 0x2000],
-    },
-    Mbc1 {
-        memory_model: Mbc1Type,
-        //memory_banks: [byte; 0x4000], //(2 << 13) + (2 << 21) - 0x4000],
-        //memory_banks: Vec<[byte; 0x4000]>,
-        ram_banks: Vec<[byte; 0x2000]>,
-        ram_active: bool,
-        // calculated from the rom size, just a cached division
-        num_rom_banks: u32,
-        //top two bits (21 & 22?) used for selecting RAM in 4_32 mode
-        mem_bank_selector: u32,
-        ram_bank_selector: u32,
-    },
-    Mbc3 {
-        //unclear if this has 16-8/4-32 mode....
-        //memory_banks: Vec<[byte; 0x4000]>,
-        ram_banks: Vec<[byte; 0x2000]>,
-        ram_active: bool, //unsure if this is needed
-        mem_bank_selector: u32,
-        ram_bank_selector: u32,
-    },
-    Mbc5 {
-        ram_banks: Vec<[byte; 0x2000]>,
-        ram_active: bool,
-        num_rom_banks: u32,
-        mem_bank_selector: u32,
-        ram_bank_selector: u32,
-    },
+/// Every size of clock footer a `.sav` file may end with
+const CLOCK_FOOTER_SIZES: [usize; 3] = [
+    RTC_FOOTER_SIZE,
+    RTC_FOOTER_SIZE_32BIT_TIME,
+    HUC3_RTC_FOOTER_SIZE,
+];
+
+/// How a `.sav` file is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveLayout {
+    /// Just the contents of cartridge RAM
+    Raw,
+    /// Cartridge RAM followed by a clock footer of this many bytes
+    RtcFooter(usize),
 }
 
-#[derive(Clone, Copy)]
-pub enum Mbc1Type {
-    SixteenEight,
-    FourThirtytwo,
+impl Cartridge {
+    /// Where in the ROM a read of 0x0000-0x7FFF ends up
+    fn rom_index(&self, index: u16) -> usize {
+        // constructor guarantees the ROM isn't empty
+        self.mapper.rom_offset(index) % self.entire_rom_data.len()
+    }
 }
 
 //for reading and writing
 impl IndexMut<u16> for Cartridge {
     fn index_mut(&mut self, ind: u16) -> &mut byte {
         trace!("indexmut: {:X}", ind);
-        // 0x0000-0x7FFF shouldn't be writable, TODO: handle this
         match ind {
-            0x0000..=0x3FFF => {
-                // constructor guarantees this to be true
-                debug_assert!(self.entire_rom_data.len() >= 0x4000);
-                &mut self.entire_rom_data[ind as usize]
-            }
-            0x4000..=0x7FFF => {
-                match self.cart_sub {
-                    Some(CartridgeSubType::RomOnly { .. }) => {
-                        &mut self.entire_rom_data[ind as usize]
-                    }
-                    Some(CartridgeSubType::Mbc1 { memory_model: Mbc1Type::SixteenEight,
-                                                  mem_bank_selector: bank_selector,
-                                                  num_rom_banks,
-                                             .. }) => {
-                        let adjusted_bank_selector = if bank_selector == 0 { 1 } else { bank_selector as usize } - 1;
-                        let m = (0x4000 * num_rom_banks as usize) - 1;
-                        let idx = ((adjusted_bank_selector * 0x4000) + ind as usize) & m;
-                        &mut self.entire_rom_data[idx]
-                    }
-                    Some(CartridgeSubType::Mbc1 { .. /*memory_model: Mbc1Type::FourThirtytwo,
-                                             memory_banks: ref mb,
-                                             //ram_active: ra,
-                                             mem_bank_selector: index*/ }) => unimplemented!(),
-                    Some(CartridgeSubType::Mbc5 {  mem_bank_selector: bank_selector,
-                                                  num_rom_banks,
-                                             .. }) => {
-                        let m = (0x4000 * num_rom_banks as usize) - 1;
-                        let adj_ind = ind - 0x4000;
-                        let idx = ((bank_selector as usize * 0x4000) + adj_ind as usize) & m;
-                        &mut self.entire_rom_data[idx]
-                    }
-
-                    _ => unimplemented!(),
-                }
-            }
-            // Video RAM:
-            0x8000..=0x9FFF => {
-                //TODO: block needs to handle if reads should be blocked
-                panic!("At access video ram");
-            }
-            // switchable RAM bank
-            0xA000..=0xBFFF => {
-                match self.cart_sub {
-                    Some(CartridgeSubType::RomOnly {
-                        ram_bank: ref mut rambank,
-                        ..
-                    }) => {
-                        //error!("Writing to 0x{:X} does not do anything on a ROM only cartridge",
-                        //      ind);
-                        &mut rambank[(ind - 0xA000) as usize]
-                    }
-                    Some(CartridgeSubType::Mbc1 {
-                        ram_banks: ref mut rb,
-                        ram_active: true,
-                        ram_bank_selector: rbs,
-                        ..
-                    }) => {
-                        if (rbs as usize) < rb.len() {
-                            &mut rb[rbs as usize][(ind - 0xA000) as usize]
-                        } else {
-                            &mut self.dummy_value
-                        }
-                    }
-                    Some(CartridgeSubType::Mbc3 {
-                        ram_banks: ref mut rb,
-                        ram_active: true,
-                        ram_bank_selector: rbs,
-                        ..
-                    }) => {
-                        if (rbs as usize) < rb.len() {
-                            &mut rb[rbs as usize][(ind - 0xA000) as usize]
-                        } else {
-                            &mut self.dummy_value
-                        }
-                    }
-
-                    Some(CartridgeSubType::Mbc5 {
-                        ram_banks: ref mut rb,
-                        ram_active: true,
-                        ram_bank_selector: rbs,
-                        ..
-                    }) => {
-                        if (rbs as usize) < rb.len() {
-                            &mut rb[rbs as usize][(ind - 0xA000) as usize]
-                        } else {
-                            &mut self.dummy_value
-                        }
-                    }
-                    Some(CartridgeSubType::Mbc1 {
-                        ram_active: false, ..
-                    }) => &mut self.dummy_value,
-                    Some(CartridgeSubType::Mbc3 {
-                        ram_active: false, ..
-                    }) => &mut self.dummy_value,
-                    Some(CartridgeSubType::Mbc5 {
-                        ram_active: false, ..
-                    }) => &mut self.dummy_value,
-                    _ => panic!("at switchable ram bank"),
-                }
-            }
-            //internal ram
-            /*0xC000...0xDFFF => &self.internal_ram[(ind - 0xC000) as usize],
-            //echo of internal ram
-            0xE000...0xFDFF => &self.internal_ram[(ind - 0xE000) as usize],
-            // OAM
-            0xFE00...0xFF9F => &self.oam[(ind - 0xFE00) as usize],
-            // IO ports
-            0xFF00...0xFF4B => {
-                //TODO:
-                unimplemented!()
+            // 0x0000-0x7FFF shouldn't be writable, TODO: handle this
+            0x0000..=0x7FFF => {
+                let idx = self.rom_index(ind);
+                &mut self.entire_rom_data[idx]
             }
-            //more internal RAM
-            0xFF80...0xFFFE => &self.internal_ram2[(ind - 0xFF80) as usize],
-            //interrupt flag
-            0xFFFF => {
-                //TODO:
-                &self.interrupt_flag
-            }*/
+            // RAM is only reachable through `write_ram_value`, which lets
+            // the mapper decide what a write does
+            0xA000..=0xBFFF => &mut self.dummy_value,
             _ => {
                 panic!("Address 0x{:X} cannot be read from", ind);
             }
@@ -487,141 +224,14 @@ impl IndexMut<u16> for Cartridge {
     }
 }
 
-static NO_RAM_BUS_NOISE: u8 = 0xFF;
-
 //for reading
 impl Index<u16> for Cartridge {
     type Output = byte;
 
-    fn index<'a>(&'a self, ind: u16) -> &'a byte {
+    fn index(&self, ind: u16) -> &byte {
         match ind {
-            0x0000..=0x3FFF => {
-                // constructor guarantees this to be true
-                debug_assert!(self.entire_rom_data.len() >= 0x4000);
-                &self.entire_rom_data[ind as usize]
-            }
-            0x4000..=0x7FFF => match self.cart_sub {
-                Some(CartridgeSubType::RomOnly { .. }) => &self.entire_rom_data[ind as usize],
-                Some(CartridgeSubType::Mbc1 {
-                    memory_model: Mbc1Type::SixteenEight,
-                    mem_bank_selector: bank_selector,
-                    num_rom_banks,
-                    ..
-                }) => {
-                    let adjusted_bank_selector = if bank_selector == 0 {
-                        1
-                    } else {
-                        bank_selector as usize
-                    } - 1;
-                    let m = (0x4000 * num_rom_banks as usize) - 1;
-                    let idx = ((adjusted_bank_selector * 0x4000) + ind as usize) & m;
-                    &self.entire_rom_data[idx]
-                }
-                Some(CartridgeSubType::Mbc1 {
-                    memory_model: Mbc1Type::FourThirtytwo,
-                    mem_bank_selector: bank_selector,
-                    ..
-                }) => {
-                    let adjusted_bank_selector = if bank_selector == 0 {
-                        1
-                    } else {
-                        bank_selector as usize
-                    } - 1;
-                    &self.entire_rom_data[(adjusted_bank_selector * 0x4000) + ind as usize]
-                }
-                Some(CartridgeSubType::Mbc3 {
-                    mem_bank_selector: bank_selector,
-                    ..
-                }) => {
-                    let adjusted_bank_selector = if bank_selector == 0 {
-                        1
-                    } else {
-                        bank_selector as usize
-                    } - 1;
-                    &self.entire_rom_data[(adjusted_bank_selector * 0x4000) + ind as usize]
-                }
-                Some(CartridgeSubType::Mbc5 {
-                    mem_bank_selector: bank_selector,
-                    num_rom_banks,
-                    ..
-                }) => {
-                    let m = (0x4000 * num_rom_banks as usize) - 1;
-                    let adj_ind = ind - 0x4000;
-                    let idx = ((bank_selector as usize * 0x4000) + adj_ind as usize) & m;
-                    &self.entire_rom_data[idx]
-                }
-                _ => panic!("Indexing {:X}", ind),
-            },
-            // Video RAM:
-            0x8000..=0x9FFF => {
-                //TODO: block needs to handle if reads should be blocked
-                panic!("At access video ram");
-            }
-            // switchable RAM bank
-            0xA000..=0xBFFF => match self.cart_sub {
-                Some(CartridgeSubType::RomOnly {
-                    ram_bank: ref rambank,
-                    ..
-                }) => &rambank[(ind - 0xA000) as usize],
-                Some(CartridgeSubType::Mbc1 {
-                    ram_active: true,
-                    ram_banks: ref ram_vec,
-                    ram_bank_selector: rbs,
-                    ..
-                }) => {
-                    if (rbs as usize) < ram_vec.len() {
-                        &ram_vec[rbs as usize][(ind as u32 - 0xA000) as usize]
-                    } else {
-                        &NO_RAM_BUS_NOISE
-                    }
-                }
-                Some(CartridgeSubType::Mbc3 {
-                    ram_banks: ref ram_vec,
-                    ram_bank_selector: rbs,
-                    ram_active: true,
-                    ..
-                }) => &ram_vec[rbs as usize][(ind as u32 - 0xA000) as usize],
-                Some(CartridgeSubType::Mbc5 {
-                    ram_active: true,
-                    ram_banks: ref ram_vec,
-                    ram_bank_selector: rbs,
-                    ..
-                }) => {
-                    if (rbs as usize) < ram_vec.len() {
-                        &ram_vec[rbs as usize][(ind as u32 - 0xA000) as usize]
-                    } else {
-                        &NO_RAM_BUS_NOISE
-                    }
-                }
-                Some(CartridgeSubType::Mbc1 {
-                    ram_active: false, ..
-                }) => &NO_RAM_BUS_NOISE,
-                Some(CartridgeSubType::Mbc3 {
-                    ram_active: false, ..
-                }) => &NO_RAM_BUS_NOISE,
-                Some(CartridgeSubType::Mbc5 {
-                    ram_active: false, ..
-                }) => &NO_RAM_BUS_NOISE,
-                _ => panic!("at switchable ram bank"),
-            },
-            //internal ram
-            /*0xC000...0xDFFF => &self.internal_ram[(ind - 0xC000) as usize],
-            //echo of internal ram
-            0xE000...0xFDFF => &self.internal_ram[(ind - 0xE000) as usize],
-            // OAM
-            0xFE00...0xFF9F => &self.oam[(ind - 0xFE00) as usize],
-            // IO ports
-            0xFF00...0xFF4B => {
-                //TODO:
-                unimplemented!()
-            }
-            //more internal RAM
-            0xFF80...0xFFFE => &self.internal_ram2[(ind - 0xFF80) as usize],
-            //interrupt flag
-            0xFFFF => {
-                //TODO:
-                &self.interrupt_flag
-            }*/
+            0x0000..=0x7FFF => &self.entire_rom_data[self.rom_index(ind)],
+            0xA000..=0xBFFF => self.mapper.read_ram(ind),
             _ => {
                 panic!("Address 0x{:X} cannot be read from", ind);
             }
@@ -630,147 +240,192 @@ impl Index<u16> for Cartridge {
 }
 
 impl Cartridge {
-    pub fn load_ram(&mut self, path: &PathBuf) {
-        use std::fs::File;
-        use std::io::Read;
+    /// Sets the tilt the accelerometer of an MBC7 cartridge reads, in g
+    /// from -1 to 1 on each axis: x is positive to the right, y towards the
+    /// bottom of the screen
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.mapper.set_tilt(x, y);
+    }
 
-        let mut file = match File::open(path) {
-            Ok(f) => f,
-            _ => return,
-        };
+    /// Whether the cartridge's rumble motor is currently running
+    pub fn rumble_active(&self) -> bool {
+        self.mapper.rumble_active()
+    }
 
-        match self.cart_sub {
-            Some(CartridgeSubType::Mbc1 {
-                ram_banks: ref mut ra,
-                ..
-            }) => {
-                for ref mut b in ra {
-                    file.read_exact(&mut b[..]).unwrap();
-                }
+    /// Advances anything on the cartridge that runs on its own, like the
+    /// MBC3 clock
+    pub fn step(&mut self, cycles: u32, cycles_per_second: u64) {
+        self.mapper.step(cycles, cycles_per_second);
+    }
+
+    /// Once a Pocket Camera capture has finished, takes the picture from
+    /// `source` and stores the processed photo in RAM
+    pub fn develop_photo(&mut self, source: &mut dyn CameraSource) {
+        self.mapper.develop_photo(source);
+    }
+
+    /// Size of the RAM (or EEPROM) part of a save file for this cartridge
+    pub fn sram_size(&self) -> usize {
+        self.mapper.battery_ram_size()
+    }
+
+    /// Works out how a save file of `size` bytes is laid out, with a
+    /// warning if it doesn't fit this cartridge
+    pub fn detect_save_layout(&self, size: usize) -> (SaveLayout, Option<String>) {
+        let sram_size = self.sram_size();
+        let extra = size.saturating_sub(sram_size);
+        if size == sram_size {
+            (SaveLayout::Raw, None)
+        } else if self.mapper.clock_footer_sizes().contains(&extra) {
+            (SaveLayout::RtcFooter(extra), None)
+        } else if size < sram_size {
+            (
+                SaveLayout::Raw,
+                Some(format!(
+                    "save file is {} bytes but the cartridge has {} bytes of RAM; \
+                     the rest is left blank",
+                    size, sram_size
+                )),
+            )
+        } else if CLOCK_FOOTER_SIZES.contains(&extra) {
+            (
+                SaveLayout::Raw,
+                Some(format!(
+                    "save file has a {} byte clock footer but the cartridge has no clock; \
+                     ignoring it",
+                    extra
+                )),
+            )
+        } else {
+            (
+                SaveLayout::Raw,
+                Some(format!(
+                    "save file is {} bytes but the cartridge has {} bytes of RAM; \
+                     ignoring the last {} bytes",
+                    size, sram_size, extra
+                )),
+            )
+        }
+    }
+
+    /// Restores RAM and the clock from the contents of a save file,
+    /// returning how it was laid out and anything that didn't fit
+    pub fn import_ram(&mut self, data: &[u8]) -> (SaveLayout, Vec<String>) {
+        let (layout, warning) = self.detect_save_layout(data.len());
+        let mut warnings: Vec<String> = warning.into_iter().collect();
+        let sram_end = data.len().min(self.sram_size());
+        self.mapper.load_battery_ram(&data[..sram_end]);
+
+        if let SaveLayout::RtcFooter(_) = layout {
+            let footer = &data[sram_end..];
+            if let Err(e) = self.mapper.load_clock_footer(footer, unix_time_now()) {
+                warnings.push(format!("could not restore the cartridge clock: {}", e));
             }
-            _ => (),
         }
+        (layout, warnings)
     }
 
-    pub fn save_ram(&self, path: &PathBuf) {
-        use std::fs::File;
+    /// Loads a save file if there is one.  Saves that don't match the
+    /// cartridge are loaded as far as they fit, with a warning.
+    pub fn load_ram(&mut self, path: &Path) {
+        let data = match std::fs::read(path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("Could not read save file {:?}: {}", path, e);
+                return;
+            }
+        };
+        if !self.battery {
+            warn!(
+                "Ignoring save file {:?}: the cartridge has no battery",
+                path
+            );
+            return;
+        }
+
+        let (layout, warnings) = self.import_ram(&data);
+        for warning in warnings {
+            warn!("Save file {:?}: {}", path, warning);
+        }
+        info!("Loaded save file {:?} ({:?})", path, layout);
+    }
+
+    /// Whether RAM has been written to since the last `save_ram`
+    pub fn ram_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+
+    /// Writes the save file if the cartridge has a battery.  The file is
+    /// written next to `path` first and then renamed over it, so a crash
+    /// part way through leaves the old save intact.
+    pub fn save_ram(&mut self, path: &Path) {
         use std::io::Write;
 
-        let mut file = match File::create(path) {
-            Ok(f) => f,
-            _ => return,
-        };
+        if !self.battery {
+            return;
+        }
+        self.ram_dirty = false;
+        let mut data = self.mapper.battery_ram();
+        data.extend(self.mapper.clock_footer(unix_time_now()));
+        if data.is_empty() {
+            return;
+        }
 
-        match self.cart_sub {
-            Some(CartridgeSubType::Mbc1 {
-                ram_banks: ref ra, ..
-            }) => {
-                for b in ra {
-                    match file.write(&b[..]) {
-                        Ok(_) => (),
-                        Err(e) => error!("Error saving ram: {:?}", e),
-                    }
-                }
-            }
-            _ => (),
+        let temp_path = path.with_extension("sav.tmp");
+        let result = std::fs::File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&temp_path, path));
+        if let Err(e) = result {
+            error!("Error saving ram to {:?}: {:?}", path, e);
+            // try again at the next flush
+            self.ram_dirty = true;
         }
     }
 
+    /// Puts the mapper's registers back to how they are at power on
     pub fn reset(&mut self) {
-        panic!("at reset");
-        /*self.mem[0xFF05] = 0x00;
-        self.mem[0xFF06] = 0x00;
-        self.mem[0xFF07] = 0x00;
-        self.mem[0xFF10] = 0x80;
-        self.mem[0xFF11] = 0xBF;
-        self.mem[0xFF12] = 0xF3;
-        self.mem[0xFF14] = 0xBF;
-        self.mem[0xFF16] = 0x3F;
-        self.mem[0xFF17] = 0x00;
-        self.mem[0xFF19] = 0xBF;
-        self.mem[0xFF1A] = 0x7F;
-        self.mem[0xFF1B] = 0xFF;
-        self.mem[0xFF1C] = 0x9F;
-        self.mem[0xFF1E] = 0xBF;
-        self.mem[0xFF20] = 0xFF;
-        self.mem[0xFF21] = 0x00;
-        self.mem[0xFF22] = 0x00;
-        self.mem[0xFF23] = 0xBF;
-        self.mem[0xFF24] = 0x77;
-        self.mem[0xFF25] = 0xF3;
-        self.mem[0xFF26] = 0xF1; //F1 for GB // TODOA:
-        self.mem[0xFF40] = 0x91;
-        self.mem[0xFF42] = 0x00;
-        self.mem[0xFF43] = 0x00;
-        self.mem[0xFF45] = 0x00;
-        self.mem[0xFF47] = 0xFC;
-        self.mem[0xFF48] = 0xFF;
-        self.mem[0xFF49] = 0xFF;
-        self.mem[0xFF4A] = 0x00;
-        self.mem[0xFF4B] = 0x00;
-        self.mem[0xFFFF] = 0x00;
-        */
+        self.mapper.reset();
     }
 
     pub fn new() -> Cartridge {
         Cartridge {
             entire_rom_data: vec![0; 0x4000],
-            cart_sub: None,
+            mapper: Box::new(OpenBus),
             gbc: false,
             sgb: false,
             dummy_value: 0,
+            battery: false,
+            ram_dirty: false,
         }
     }
+}
 
-    /*    pub fn index_set(&mut self, ind: u16, val: u8) {
-        match self.cart_sub {
-            CartridgeSubType::ROM_only { memory_bank1: membank1 } => {
-                match ind as usize {
-                    0xFF80...0xFFFE => {
-                        self.internal_ram2[(ind - 0xFF80) as usize] = val;
-                    }
-                    //internal ram
-                    0xC000...0xDFFF => {
-                        self.internal_ram[(ind - 0xC000) as usize] = val;
-                    }
-                    //echo of internal ram
-                    0xE000...0xFDFF => {
-                        self.internal_ram[(ind - 0xE000) as usize] = val;
-                    }
-                    0x0000...0x7FFF => {
-                        error!("Cannot write to address 0x{:X} of a ROM-only cartridge",
-                               ind);
-                    }
-                    addr => {
-                        unimplemented!();
-                    }
-                }
-            }
-            CartridgeSubType::MBC1 { memory_model: MBC1_type::sixteen_eight,
-                                     memory_banks: mb,
-                                     ram_active: ra,
-                                     mem_bank_selector: mut index } => {
-                match ind as usize {
-                    0x2000...0x3FFF => {
-                        //take the lower 5 bits to select the 2nd ROM bank
-                        let bank_select = if (val & 0x1F) == 0 { 1 } else { val & 0x1F };
-                        index = bank_select as u32;
-                        debug!("MBC1 switching second ROM bank to ROM bank {}", bank_select);
-                    }
-                    0x6000...0x7FFF => {
-                        if (val & 0x1) == 1 {
-                            debug!("MBC1 switching to 4-32 mode");
-                            unimplemented!();
-                        } else {
-                            debug!("MBC1: already in 16-8 mode");
-                        }
-                    }
-                    _ => unimplemented!(),
-                }
-            }
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.dummy_value);
+        writer.write_u8(self.mapper.state_tag());
+        self.mapper.save_state(writer);
+    }
 
-            _ => unimplemented!(),
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        // whatever RAM held before has been replaced
+        self.ram_dirty = true;
+        self.dummy_value = reader.read_u8()?;
+        if reader.read_u8()? != self.mapper.state_tag() {
+            return Err("Save-state was made with a different cartridge type".to_string());
         }
+        self.mapper.load_state(reader)
     }
-    */
+}
+
+/// Seconds since the UNIX epoch, as stored in RTC footers
+fn unix_time_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
@@ -0,0 +1,76 @@
+//! Cartridges without a mapper: 32KB of ROM and optionally 8KB of RAM
+
+use super::mapper::*;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+#[derive(Clone)]
+pub struct RomOnly {
+    ram_bank: [u8; RAM_BANK_SIZE],
+}
+
+impl RomOnly {
+    pub fn new() -> RomOnly {
+        RomOnly {
+            ram_bank: [0; RAM_BANK_SIZE],
+        }
+    }
+}
+
+impl Default for RomOnly {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapper for RomOnly {
+    fn name(&self) -> &'static str {
+        "ROM ONLY"
+    }
+
+    fn state_tag(&self) -> u8 {
+        1
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn read_ram(&self, address: u16) -> &u8 {
+        &self.ram_bank[(address - 0xA000) as usize]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        let byte = &mut self.ram_bank[(address - 0xA000) as usize];
+        let changed = *byte != value;
+        *byte = value;
+        changed
+    }
+
+    // there is nothing to write to
+    fn write_register(&mut self, _address: u16, _value: u8) {}
+
+    fn reset(&mut self) {}
+
+    fn battery_ram(&self) -> Vec<u8> {
+        self.ram_bank.to_vec()
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let end = data.len().min(RAM_BANK_SIZE);
+        self.ram_bank[..end].copy_from_slice(&data[..end]);
+    }
+
+    fn battery_ram_size(&self) -> usize {
+        RAM_BANK_SIZE
+    }
+}
+
+impl SaveState for RomOnly {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram_bank[..]);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_bytes(&mut self.ram_bank[..])
+    }
+}
@@ -0,0 +1,412 @@
+//! Cartridge real-time clocks (MBC3 and HuC3)
+//!
+//! The clock is advanced by emulated time so that it stays in step with
+//! save-states and fast-forwarding.  Time that passes while the emulator
+//! is closed is added when the clock is restored from a save file, using
+//! the timestamp in the RTC footer.
+
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// MBC3 RTC register numbers as selected through 0x4000-0x5FFF
+pub const RTC_SECONDS: u8 = 0x08;
+pub const RTC_MINUTES: u8 = 0x09;
+pub const RTC_HOURS: u8 = 0x0A;
+pub const RTC_DAY_LOW: u8 = 0x0B;
+/// bit 0: day bit 8, bit 6: halt, bit 7: day counter carry
+pub const RTC_DAY_HIGH: u8 = 0x0C;
+
+const DAY_HIGH_HALT: u8 = 0x40;
+const DAY_HIGH_CARRY: u8 = 0x80;
+
+/// Size of the footer other emulators append to `.sav` files
+pub const RTC_FOOTER_SIZE: usize = 48;
+/// Older version of the footer with a 32-bit timestamp
+pub const RTC_FOOTER_SIZE_32BIT_TIME: usize = 44;
+
+/// The MBC3 real-time clock
+#[derive(Debug, Clone, Default)]
+pub struct Rtc {
+    /// seconds, minutes, hours, day low, day high as the clock counts
+    registers: [u8; 5],
+    /// what the game reads, copied from `registers` when latched
+    latched: [u8; 5],
+    /// the last value written to the latch register
+    latch_value: u8,
+    /// CPU cycles counted towards the next second
+    cycles: u64,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            registers: [0; 5],
+            latched: [0; 5],
+            latch_value: 0xFF,
+            cycles: 0,
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.registers[4] & DAY_HIGH_HALT != 0
+    }
+
+    /// Advances the clock by `cycles` CPU cycles
+    pub fn step(&mut self, cycles: u32, cycles_per_second: u64) {
+        if self.halted() {
+            return;
+        }
+        self.cycles += cycles as u64;
+        while self.cycles >= cycles_per_second {
+            self.cycles -= cycles_per_second;
+            self.tick();
+        }
+    }
+
+    /// Advances the clock by whole seconds, e.g. the time the emulator
+    /// was closed
+    pub fn advance_seconds(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        // out of range values don't carry normally, so count those off
+        // one second at a time first
+        while seconds > 0 && !self.in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        let [s, m, h, day_low, day_high] = self.registers;
+        let days = ((day_high as u64 & 1) << 8) | day_low as u64;
+        let total = s as u64 + 60 * (m as u64 + 60 * (h as u64 + 24 * days)) + seconds;
+
+        let days = total / (24 * 60 * 60);
+        if days >= 512 {
+            self.registers[4] |= DAY_HIGH_CARRY;
+        }
+        let days = days % 512;
+        self.registers[0] = (total % 60) as u8;
+        self.registers[1] = (total / 60 % 60) as u8;
+        self.registers[2] = (total / (60 * 60) % 24) as u8;
+        self.registers[3] = days as u8;
+        self.registers[4] = (self.registers[4] & !1) | (days >> 8) as u8;
+    }
+
+    fn in_range(&self) -> bool {
+        self.registers[0] < 60 && self.registers[1] < 60 && self.registers[2] < 24
+    }
+
+    /// Counts one second.  Registers written with out of range values
+    /// count up to their bit width and wrap without carrying, as on
+    /// hardware.
+    fn tick(&mut self) {
+        let [seconds, minutes, hours, day_low, day_high] = &mut self.registers;
+        *seconds = (*seconds + 1) & 0x3F;
+        if *seconds != 60 {
+            return;
+        }
+        *seconds = 0;
+        *minutes = (*minutes + 1) & 0x3F;
+        if *minutes != 60 {
+            return;
+        }
+        *minutes = 0;
+        *hours = (*hours + 1) & 0x1F;
+        if *hours != 24 {
+            return;
+        }
+        *hours = 0;
+        let (new_low, overflow) = day_low.overflowing_add(1);
+        *day_low = new_low;
+        if overflow {
+            if *day_high & 1 == 1 {
+                *day_high = (*day_high & !1) | DAY_HIGH_CARRY;
+            } else {
+                *day_high |= 1;
+            }
+        }
+    }
+
+    /// Called on writes to 0x6000-0x7FFF: writing 0 then 1 latches the clock
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_value == 0 && value == 1 {
+            self.latched = self.registers;
+        }
+        self.latch_value = value;
+    }
+
+    /// The latched value of an RTC register (0x08-0x0C)
+    pub fn read(&self, register: u8) -> &u8 {
+        &self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    /// Sets an RTC register (0x08-0x0C)
+    pub fn write(&mut self, register: u8, value: u8) {
+        let (idx, mask) = match register {
+            RTC_SECONDS => {
+                // writing the seconds restarts the current second
+                self.cycles = 0;
+                (0, 0x3F)
+            }
+            RTC_MINUTES => (1, 0x3F),
+            RTC_HOURS => (2, 0x1F),
+            RTC_DAY_LOW => (3, 0xFF),
+            RTC_DAY_HIGH => (4, DAY_HIGH_CARRY | DAY_HIGH_HALT | 1),
+            _ => return,
+        };
+        self.registers[idx] = value & mask;
+        self.latched[idx] = value & mask;
+    }
+
+    /// Writes the 48-byte footer used by other emulators: the clock and
+    /// latched registers as 32-bit values followed by a 64-bit UNIX timestamp
+    pub fn to_footer(&self, unix_time: u64) -> [u8; RTC_FOOTER_SIZE] {
+        let mut out = [0u8; RTC_FOOTER_SIZE];
+        for (i, reg) in self.registers.iter().chain(self.latched.iter()).enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        out[40..48].copy_from_slice(&unix_time.to_le_bytes());
+        out
+    }
+
+    /// Restores the clock from a 44 or 48-byte footer, adding the time that
+    /// passed between the footer's timestamp and `unix_time`
+    pub fn load_footer(&mut self, footer: &[u8], unix_time: u64) -> Result<(), String> {
+        let saved_time = match footer.len() {
+            RTC_FOOTER_SIZE => u64::from_le_bytes([
+                footer[40], footer[41], footer[42], footer[43], footer[44], footer[45], footer[46],
+                footer[47],
+            ]),
+            RTC_FOOTER_SIZE_32BIT_TIME => {
+                u32::from_le_bytes([footer[40], footer[41], footer[42], footer[43]]) as u64
+            }
+            n => return Err(format!("RTC footer has unexpected size {}", n)),
+        };
+        let read_reg = |i: usize| footer[i * 4];
+        for i in 0..5 {
+            self.registers[i] = read_reg(i);
+            self.latched[i] = read_reg(i + 5);
+        }
+        self.cycles = 0;
+        self.advance_seconds(unix_time.saturating_sub(saved_time));
+        Ok(())
+    }
+}
+
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.registers);
+        writer.write_bytes(&self.latched);
+        writer.write_u8(self.latch_value);
+        writer.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_bytes(&mut self.registers)?;
+        reader.read_bytes(&mut self.latched)?;
+        self.latch_value = reader.read_u8()?;
+        self.cycles = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+/// Size of the HuC3 clock footer appended to `.sav` files
+pub const HUC3_RTC_FOOTER_SIZE: usize = 17;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Returned for reads while the HuC3 clock is finishing a command
+pub static HUC3_RTC_READY: u8 = 1;
+
+/// The HuC3 real-time clock
+///
+/// Unlike the MBC3 clock it is driven through a small command interface:
+/// writes in RTC command mode (0xB) take a command in the upper nibble and
+/// an argument in the lower one, reading in mode 0xC returns the result.
+/// The clock counts minutes of the day and days, and its memory is
+/// addressed in nibbles: 0-2 are the minutes, 3-6 the days and 0x58-0x5F
+/// the alarm.
+#[derive(Debug, Clone, Default)]
+pub struct Huc3Rtc {
+    minutes: u16,
+    days: u16,
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+    /// the nibble address the next read or write command uses
+    access_index: u8,
+    access_flags: u8,
+    /// result of the last read command
+    read: u8,
+    seconds: u8,
+    /// CPU cycles counted towards the next second
+    cycles: u64,
+}
+
+impl Huc3Rtc {
+    pub fn new() -> Huc3Rtc {
+        Huc3Rtc::default()
+    }
+
+    /// Advances the clock by `cycles` CPU cycles
+    pub fn step(&mut self, cycles: u32, cycles_per_second: u64) {
+        self.cycles += cycles as u64;
+        while self.cycles >= cycles_per_second {
+            self.cycles -= cycles_per_second;
+            self.advance_seconds(1);
+        }
+    }
+
+    /// Advances the clock by whole seconds
+    pub fn advance_seconds(&mut self, seconds: u64) {
+        let seconds = self.seconds as u64 + seconds;
+        self.seconds = (seconds % 60) as u8;
+        let minutes = self.minutes as u64 + seconds / 60;
+        self.minutes = (minutes % MINUTES_PER_DAY as u64) as u16;
+        let days = self.days as u64 + minutes / MINUTES_PER_DAY as u64;
+        self.days = days as u16;
+    }
+
+    /// Runs a command written in RTC command mode, returning whether it
+    /// changed the clock's memory
+    pub fn command(&mut self, value: u8) -> bool {
+        let argument = value & 0xF;
+        match value >> 4 {
+            // read the nibble at the access index and advance
+            0x1 => {
+                self.read = self.nibble(self.access_index);
+                self.access_index = self.access_index.wrapping_add(1);
+                false
+            }
+            // write the nibble at the access index, 3 also advances
+            0x2 | 0x3 => {
+                let before = self.nibble(self.access_index);
+                self.set_nibble(self.access_index, argument);
+                let changed = self.nibble(self.access_index) != before;
+                if value >> 4 == 0x3 {
+                    self.access_index = self.access_index.wrapping_add(1);
+                }
+                changed
+            }
+            0x4 => {
+                self.access_index = (self.access_index & 0xF0) | argument;
+                false
+            }
+            0x5 => {
+                self.access_index = (self.access_index & 0x0F) | (argument << 4);
+                false
+            }
+            0x6 => {
+                self.access_flags = argument;
+                false
+            }
+            otherwise => {
+                debug!("HuC3: unknown RTC command {:X}", otherwise);
+                false
+            }
+        }
+    }
+
+    /// The value read in RTC response mode
+    pub fn read(&self) -> &u8 {
+        if self.access_flags == 0x2 {
+            &HUC3_RTC_READY
+        } else {
+            &self.read
+        }
+    }
+
+    fn nibble(&self, index: u8) -> u8 {
+        let index = index as u32;
+        let value = match index {
+            0..=2 => self.minutes >> (index * 4),
+            3..=6 => self.days >> ((index - 3) * 4),
+            0x58..=0x5A => self.alarm_minutes >> ((index - 0x58) * 4),
+            0x5B..=0x5E => self.alarm_days >> ((index - 0x5B) * 4),
+            0x5F => self.alarm_enabled as u16,
+            _ => 0,
+        };
+        (value & 0xF) as u8
+    }
+
+    fn set_nibble(&mut self, index: u8, value: u8) {
+        fn set(field: &mut u16, nibble: u32, value: u8) {
+            *field &= !(0xF << (nibble * 4));
+            *field |= (value as u16) << (nibble * 4);
+        }
+        let index = index as u32;
+        match index {
+            0..=2 => set(&mut self.minutes, index, value),
+            3..=6 => set(&mut self.days, index - 3, value),
+            0x58..=0x5A => set(&mut self.alarm_minutes, index - 0x58, value),
+            0x5B..=0x5E => set(&mut self.alarm_days, index - 0x5B, value),
+            0x5F => self.alarm_enabled = value & 1 == 1,
+            _ => debug!("HuC3: write to unknown RTC nibble {:X}", index),
+        }
+    }
+
+    /// Writes the footer used by other emulators: a 64-bit UNIX timestamp
+    /// followed by the minutes, days, alarm minutes and alarm days as
+    /// 16-bit values and the alarm enable flag
+    pub fn to_footer(&self, unix_time: u64) -> [u8; HUC3_RTC_FOOTER_SIZE] {
+        let mut out = [0u8; HUC3_RTC_FOOTER_SIZE];
+        out[0..8].copy_from_slice(&unix_time.to_le_bytes());
+        out[8..10].copy_from_slice(&self.minutes.to_le_bytes());
+        out[10..12].copy_from_slice(&self.days.to_le_bytes());
+        out[12..14].copy_from_slice(&self.alarm_minutes.to_le_bytes());
+        out[14..16].copy_from_slice(&self.alarm_days.to_le_bytes());
+        out[16] = self.alarm_enabled as u8;
+        out
+    }
+
+    /// Restores the clock from a footer, adding the time that passed
+    /// between the footer's timestamp and `unix_time`
+    pub fn load_footer(&mut self, footer: &[u8], unix_time: u64) -> Result<(), String> {
+        if footer.len() != HUC3_RTC_FOOTER_SIZE {
+            return Err(format!(
+                "HuC3 RTC footer has unexpected size {}",
+                footer.len()
+            ));
+        }
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&footer[0..8]);
+        let saved_time = u64::from_le_bytes(time);
+        let read_u16 = |i: usize| u16::from_le_bytes([footer[i], footer[i + 1]]);
+        self.minutes = read_u16(8) % MINUTES_PER_DAY;
+        self.days = read_u16(10);
+        self.alarm_minutes = read_u16(12);
+        self.alarm_days = read_u16(14);
+        self.alarm_enabled = footer[16] & 1 == 1;
+        self.seconds = 0;
+        self.cycles = 0;
+        self.advance_seconds(unix_time.saturating_sub(saved_time));
+        Ok(())
+    }
+}
+
+impl SaveState for Huc3Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.minutes);
+        writer.write_u16(self.days);
+        writer.write_u16(self.alarm_minutes);
+        writer.write_u16(self.alarm_days);
+        writer.write_bool(self.alarm_enabled);
+        writer.write_u8(self.access_index);
+        writer.write_u8(self.access_flags);
+        writer.write_u8(self.read);
+        writer.write_u8(self.seconds);
+        writer.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.minutes = reader.read_u16()?;
+        self.days = reader.read_u16()?;
+        self.alarm_minutes = reader.read_u16()?;
+        self.alarm_days = reader.read_u16()?;
+        self.alarm_enabled = reader.read_bool()?;
+        self.access_index = reader.read_u8()?;
+        self.access_flags = reader.read_u8()?;
+        self.read = reader.read_u8()?;
+        self.seconds = reader.read_u8()?;
+        self.cycles = reader.read_u64()?;
+        Ok(())
+    }
+}
@@ -37,6 +37,11 @@ pub const OAM_END: usize = 0xFE9F;
 /// TODO: List what STAT contains
 pub const STAT_ADDR: usize = 0xFF41;
 
+/// Size of the DMG, MGB and SGB boot ROMs, mapped at 0x0000-0x00FF
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+/// Size of the CGB boot ROM, mapped at 0x0000-0x00FF and 0x0200-0x08FF
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
 /// The state of the CPU's execution
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CpuState {
@@ -114,7 +119,8 @@ pub enum CartridgeType {
     RomMBC5RamBatt = 0x1B,
     RomMBC5RumbleSRam = 0x1D,
     RomMBC5RumbleSRamBatt = 0x1E,
-    PocketCamera = 0x1F,
+    RomMBC7SensorEepromBatt = 0x22,
+    PocketCamera = 0xFC,
     BandaiTAMA5 = 0xFD,
     HudsonHuC3 = 0xFE,
     HudsonHuC1 = 0xFF,
@@ -142,7 +148,8 @@ pub fn to_cartridge_type(v: u8) -> Option<CartridgeType> {
         0x1B => Some(CartridgeType::RomMBC5RamBatt),
         0x1D => Some(CartridgeType::RomMBC5RumbleSRam),
         0x1E => Some(CartridgeType::RomMBC5RumbleSRamBatt),
-        0x1F => Some(CartridgeType::PocketCamera),
+        0x22 => Some(CartridgeType::RomMBC7SensorEepromBatt),
+        0xFC => Some(CartridgeType::PocketCamera),
         0xFD => Some(CartridgeType::BandaiTAMA5),
         0xFE => Some(CartridgeType::HudsonHuC3),
         0xFF => Some(CartridgeType::HudsonHuC1),
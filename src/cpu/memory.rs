@@ -4,16 +4,26 @@
 
 use std::iter::Iterator;
 use std::ops::{Index, IndexMut};
-use std::path::PathBuf;
+use std::path::Path;
 
+use crate::cpu::cartridge::camera::CameraSource;
 use crate::cpu::cartridge::*;
 use crate::cpu::constants::*;
 use crate::cpu::memvis::cpumemvis::*;
+use crate::cpu::model::Model;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
 
 #[derive(Clone)]
 pub struct Memory {
     cartridge: Box<Cartridge>,
 
+    /// The boot ROM, empty if there isn't one
+    /// 0x0000-0x00FF, and 0x0200-0x08FF on CGB
+    boot_rom: Vec<u8>,
+
+    /// The boot ROM is mapped over the cartridge until 0xFF50 is written
+    boot_rom_mapped: bool,
+
     /// 8kb video ram
     /// 0x8000-0x9FFF
     pub video_ram: [[byte; 0x2000]; 2],
@@ -67,6 +77,8 @@ impl Memory {
     pub fn new() -> Memory {
         Memory {
             cartridge: Box::new(Cartridge::new()),
+            boot_rom: vec![],
+            boot_rom_mapped: false,
             video_ram: [[0u8; 0x2000]; 2],
             internal_ram: [[0u8; 0x1000]; 8],
             empty: [0u8; 0x6F],
@@ -95,20 +107,64 @@ impl Memory {
         *self.cartridge = Cartridge::load(rom_bytes).expect("Could not load ROM");
     }
 
-    pub fn load_saved_ram(&mut self, mut path: PathBuf, game_name: &str) {
-        path.push(game_name);
-        path.set_extension("savedgame");
+    /// Replaces the boot ROM; an empty one means the game starts at 0x100
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = boot_rom;
+        self.boot_rom_mapped = false;
+    }
 
-        self.cartridge.load_ram(&path);
+    pub fn boot_rom(&self) -> &[u8] {
+        &self.boot_rom
     }
 
-    pub fn save_ram(&self, data_path: PathBuf, game_name: &str) {
-        let mut path = data_path.clone();
+    pub fn map_boot_rom(&mut self) {
+        self.boot_rom_mapped = !self.boot_rom.is_empty();
+    }
 
-        path.push(game_name);
-        path.set_extension("savedgame");
+    pub fn unmap_boot_rom(&mut self) {
+        self.boot_rom_mapped = false;
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    /// Whether a read of `index` goes to the boot ROM rather than the
+    /// cartridge; 0x0100-0x01FF always shows the cartridge header
+    fn in_boot_rom(&self, index: u16) -> bool {
+        self.boot_rom_mapped
+            && (index as usize) < self.boot_rom.len()
+            && !(0x0100..=0x01FF).contains(&index)
+    }
+
+    pub fn load_saved_ram(&mut self, path: &Path) {
+        self.cartridge.load_ram(path);
+    }
+
+    pub fn save_ram(&mut self, path: &Path) {
+        self.cartridge.save_ram(path);
+    }
+
+    pub fn ram_dirty(&self) -> bool {
+        self.cartridge.ram_dirty()
+    }
+
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cartridge.set_tilt(x, y);
+    }
 
-        self.cartridge.save_ram(&data_path);
+    pub fn rumble_active(&self) -> bool {
+        self.cartridge.rumble_active()
+    }
+
+    /// Advances hardware on the cartridge, like the MBC3 clock
+    pub fn cartridge_step(&mut self, cycles: u32, cycles_per_second: u64) {
+        self.cartridge.step(cycles, cycles_per_second);
+    }
+
+    /// Stores a finished Pocket Camera photo, taken from `source`
+    pub fn develop_photo(&mut self, source: &mut dyn CameraSource) {
+        self.cartridge.develop_photo(source);
     }
 
     pub fn gbc_mode(&self) -> bool {
@@ -144,10 +200,12 @@ impl Memory {
         self.logger = Some(DeqCpuEventLogger::new(Some(&mem_buffer[..])));
     }
 
-    pub fn reset(&mut self, _sgb_mode: bool) {
+    /// Sets the IO registers as the boot ROM of `model` leaves them
+    pub fn reset(&mut self, model: Model) {
+        let cgb = model.is_cgb();
         self[0xFF00] = 0xCF;
         self[0xFF01] = 0x00;
-        self[0xFF02] = 0x7E;
+        self[0xFF02] = if cgb { 0x7F } else { 0x7E };
         self[0xFF04] = 0xAB;
         self[0xFF05] = 0x00;
         self[0xFF06] = 0x00;
@@ -183,16 +241,27 @@ impl Memory {
         self[0xFF43] = 0x00;
         self[0xFF44] = 0x00;
         self[0xFF45] = 0x00;
-        self[0xFF46] = 0xFF;
+        self[0xFF46] = if cgb { 0x00 } else { 0xFF };
         self[0xFF47] = 0xFC;
         self[0xFF48] = 0xFF;
         self[0xFF49] = 0xFF;
         self[0xFF4A] = 0x00;
         self[0xFF4B] = 0x00;
+        // registers that only exist on CGB hardware read 0xFF elsewhere
+        self[0xFF4D] = if cgb { 0x7E } else { 0xFF };
+        self[0xFF4F] = if cgb { 0xFE } else { 0xFF };
+        for address in 0xFF51..=0xFF55 {
+            self[address] = 0xFF;
+        }
+        self[0xFF56] = if cgb { 0x3E } else { 0xFF };
+        self[0xFF70] = if cgb { 0xF8 } else { 0xFF };
         self[0xFFFF] = 0x00;
+        self.gbc_vram_bank = 0;
+        self.gbc_wram_bank = 1;
 
         self.gbc_background_color_palette = [0xFF; 0x40];
         self.gbc_sprite_color_palette = [0xFF; 0x40];
+        self.cartridge.reset();
     }
 }
 
@@ -201,6 +270,7 @@ impl Index<u16> for Memory {
 
     fn index(&self, index: u16) -> &u8 {
         match index {
+            0x0000..=0x08FF if self.in_boot_rom(index) => &self.boot_rom[index as usize],
             0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.index(index), //self.cartridge[index as u16],
             0x8000..=0x9FFF => {
                 &self.video_ram[self.gbc_vram_bank as usize][(index - 0x8000) as usize]
@@ -245,19 +315,7 @@ impl IndexMut<usize> for Memory {
             0xF000..=0xFDFF => &mut self.internal_ram[self.gbc_wram_bank as usize][index - 0xF000],
             0xFE00..=0xFE9F => &mut self.oam[index - 0xFE00],
             0xFEA0..=0xFEFF => &mut self.empty[index - 0xFEA0],
-            0xFF00..=0xFF7F => {
-                match index {
-                    // link port synchronization
-                    // hack for now
-                    0xFF02 => {
-                        //self.io_ports[0x02] = 0x7E;
-                        // interrupt handler should be called, but let's try not doing it for now
-                        print!("{}", self.io_ports[0x01] as char);
-                        &mut self.io_ports[0x02]
-                    }
-                    _ => &mut self.io_ports[index - 0xFF00],
-                }
-            }
+            0xFF00..=0xFF7F => &mut self.io_ports[index - 0xFF00],
             0xFF80..=0xFFFE => &mut self.hram[index - 0xFF80],
             0xFFFF => &mut self.interrupt_flag,
             _ => panic!("Address out of bounds!"),
@@ -277,3 +335,46 @@ impl Iterator for Memory {
         }
     }
 }
+
+impl SaveState for Memory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cartridge.save_state(writer);
+        writer.write_bool(self.boot_rom_mapped);
+        for bank in self.video_ram.iter() {
+            writer.write_bytes(&bank[..]);
+        }
+        writer.write_u8(self.gbc_vram_bank);
+        for bank in self.internal_ram.iter() {
+            writer.write_bytes(&bank[..]);
+        }
+        writer.write_u8(self.gbc_wram_bank);
+        writer.write_bytes(&self.gbc_background_color_palette);
+        writer.write_bytes(&self.gbc_sprite_color_palette);
+        writer.write_bytes(&self.empty);
+        writer.write_bytes(&self.oam);
+        writer.write_bytes(&self.io_ports);
+        writer.write_bytes(&self.hram);
+        writer.write_u8(self.interrupt_flag);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.cartridge.load_state(reader)?;
+        self.boot_rom_mapped = reader.read_bool()? && !self.boot_rom.is_empty();
+        for bank in self.video_ram.iter_mut() {
+            reader.read_bytes(&mut bank[..])?;
+        }
+        self.gbc_vram_bank = reader.read_u8()? & 1;
+        for bank in self.internal_ram.iter_mut() {
+            reader.read_bytes(&mut bank[..])?;
+        }
+        self.gbc_wram_bank = reader.read_u8()? & 7;
+        reader.read_bytes(&mut self.gbc_background_color_palette)?;
+        reader.read_bytes(&mut self.gbc_sprite_color_palette)?;
+        reader.read_bytes(&mut self.empty)?;
+        reader.read_bytes(&mut self.oam)?;
+        reader.read_bytes(&mut self.io_ports)?;
+        reader.read_bytes(&mut self.hram)?;
+        self.interrupt_flag = reader.read_u8()?;
+        Ok(())
+    }
+}
@@ -9,16 +9,29 @@ pub mod cartridge;
 pub mod constants;
 pub mod memory;
 pub mod memvis;
+pub mod model;
+pub mod pixel_fifo;
+pub mod ppu;
+pub mod savestate;
+pub mod serial;
 mod tests;
+pub mod timer;
 
 use std::num::Wrapping;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use self::apu::Apu;
+use self::cartridge::camera::{BlankCamera, CameraSource};
 use self::constants::*;
 use self::memory::*;
 use self::memvis::cpumemvis::*;
+use self::model::Model;
+use self::ppu::Ppu;
+use self::savestate::*;
+use self::serial::Serial;
+use self::timer::TimaState;
 use crate::disasm::*;
+use crate::io::constants::CPU_CYCLES_PER_SECOND;
 
 #[inline]
 pub fn byte_to_u16(low_byte: u8, high_byte: u8) -> u16 {
@@ -31,6 +44,26 @@ pub fn add_u16_i8(word: u16, sbyte: i8) -> u16 {
     (Wrapping(word as i32) + Wrapping(sbyte as i32)).0 as u16
 }
 
+/// Bytes in the instruction starting with `opcode`, including the opcode
+fn instruction_length(opcode: u8) -> u8 {
+    match opcode {
+        0xCB => 2,
+        // LD r, n
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => 2,
+        // JR
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 2,
+        // ALU A, n
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => 2,
+        0xE0 | 0xE8 | 0xF0 | 0xF8 => 2,
+        // LD rr, nn and LD (nn), SP
+        0x01 | 0x08 | 0x11 | 0x21 | 0x31 => 3,
+        // JP, CALL
+        0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2 | 0xD4 | 0xDA | 0xDC => 3,
+        0xEA | 0xFA => 3,
+        _ => 1,
+    }
+}
+
 /// The CPU itself.
 ///
 ///Currently contains memory (including the ROM (which is not
@@ -55,6 +88,15 @@ pub struct Cpu {
 
     pub apu: Apu,
 
+    /// The LCD controller
+    pub ppu: Ppu,
+
+    /// The link port
+    pub serial: Serial,
+
+    /// What a Pocket Camera cartridge's sensor sees
+    camera: Box<dyn CameraSource>,
+
     /// Whether or not the CPU is running, waiting for input, or stopped
     pub state: CpuState,
 
@@ -63,13 +105,33 @@ pub struct Cpu {
 
     /*    /// Log of events, used in `MemVis`
     pub event_logger: Option<DeqCpuEventLogger>,*/
-    /// TODO: document this
+    /// Clock cycles run since reset; advanced 4 at a time by `tick`
     pub cycles: CycleCount,
+    /// The counter DIV is the top byte of
+    div_counter: u16,
+    /// Where TIMA is in reloading from TMA after an overflow
+    tima_state: TimaState,
+    /// Source address and bytes copied so far of a running OAM DMA
+    oam_dma: Option<(MemAddr, u8)>,
+    /// M-cycles the current instruction has ticked so far
+    instruction_mcycles: u8,
     interrupt_next_inst: bool,
     pub gbc_mode: bool,
-    sgb_mode: bool,
+    /// The hardware being emulated
+    model: Model,
+    /// The model asked for, rather than picked from the cartridge header
+    forced_model: Option<Model>,
     /// for CGB, run at double speed
     pub double_speed: bool,
+    /// A CGB boot ROM switched to DMG mode and left colors for the game in
+    /// the palette RAM
+    pub dmg_compat_palettes: bool,
+    /// File name of the loaded ROM, without its extension; saves are named
+    /// after it
+    rom_name: Option<String>,
+    /// `LD B, B` has run since reset; test ROMs use it as a breakpoint
+    /// once they have their result
+    pub ld_b_b_executed: bool,
 }
 
 /// Used for save-states and reverting to old CPU on resets
@@ -89,15 +151,27 @@ impl Clone for Cpu {
             pc: self.pc,
             mem: self.mem.clone(),
             apu: self.apu.clone(),
+            ppu: self.ppu.clone(),
+            serial: self.serial.clone(),
+            // there is only one real camera, like the link port
+            camera: Box::new(BlankCamera),
             state: self.state,
             input_state: self.input_state,
 
             //event_logger: self.event_logger.clone(),
             cycles: self.cycles,
+            div_counter: self.div_counter,
+            tima_state: self.tima_state,
+            oam_dma: self.oam_dma,
+            instruction_mcycles: 0,
             interrupt_next_inst: false,
             gbc_mode: self.gbc_mode,
-            sgb_mode: self.sgb_mode,
+            model: self.model,
+            forced_model: self.forced_model,
             double_speed: false,
+            dmg_compat_palettes: self.dmg_compat_palettes,
+            rom_name: self.rom_name.clone(),
+            ld_b_b_executed: self.ld_b_b_executed,
         }
     }
 }
@@ -125,15 +199,26 @@ impl Cpu {
             pc: 0,
             mem: Memory::new(),
             apu: Apu::new(),
+            ppu: Ppu::default(),
+            serial: Serial::new(),
+            camera: Box::new(BlankCamera),
             state: CpuState::Normal,
             input_state: 0xFF,
 
             //event_logger: None,//Some(DeqCpuEventLogger::new(None)),
             cycles: 0,
+            div_counter: 0,
+            tima_state: TimaState::Running,
+            oam_dma: None,
+            instruction_mcycles: 0,
             interrupt_next_inst: false,
             gbc_mode: false,
-            sgb_mode: false,
+            model: Model::Dmg,
+            forced_model: None,
             double_speed: false,
+            dmg_compat_palettes: false,
+            rom_name: None,
+            ld_b_b_executed: false,
         };
 
         // The reset state is the default state of the CPU
@@ -142,24 +227,161 @@ impl Cpu {
         new_cpu
     }
 
-    /// Sets the CPU to as it would be after the boot rom has executed
+    /// Sets the CPU to as it would be after the boot rom has executed, or
+    /// to power on if there is a boot ROM to run
     pub fn reset(&mut self) {
+        if self.has_boot_rom() {
+            self.power_on();
+            return;
+        }
         self.state = CpuState::Normal;
-        self.a = if self.gbc_mode { 0x11 } else { 0x01 }; // gameboy pocket needs 0xFF
-        self.b = 0;
-        self.c = if self.gbc_mode { 0 } else { 0x13 };
-        self.d = if self.gbc_mode { 0xFF } else { 0 };
-        self.e = if self.gbc_mode { 0x56 } else { 0xD8 };
-        self.f = if self.gbc_mode { 0x80 } else { 0xB0 };
-        self.h = if self.gbc_mode { 0 } else { 0x01 };
-        self.l = if self.gbc_mode { 0x0D } else { 0x4D };
+        self.ld_b_b_executed = false;
+        // CGB games only run in color on CGB hardware
+        self.gbc_mode = self.model.is_cgb() && self.mem.gbc_mode();
+        let [a, f, b, c, d, e, h, l] = self.model.boot_registers(self.gbc_mode);
+        self.a = a;
+        self.f = f;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
         self.sp = 0xFFFE;
         self.pc = 0x100;
         self.cycles = 0;
+        self.double_speed = false;
+        self.dmg_compat_palettes = false;
 
-        info!("reset {}", if self.gbc_mode { "GBC" } else { "GB" });
-        self.mem.reset(self.sgb_mode);
-        self.apu.reset(self.sgb_mode);
+        info!(
+            "reset {} in {} mode",
+            self.model,
+            if self.gbc_mode { "GBC" } else { "GB" }
+        );
+        self.mem.reset(self.model);
+        if self.gbc_mode {
+            self.mem.set_gbc_mode();
+        }
+        self.apu.reset(self.model);
+        self.serial.reset();
+        self.reset_clocks(self.model.boot_divider());
+    }
+
+    /// Restarts the timers from `div_counter`, and DMA and the LCD from the
+    /// top of a frame
+    fn reset_clocks(&mut self, div_counter: u16) {
+        self.div_counter = div_counter;
+        self.mem[0xFF04] = self.get_div();
+        self.tima_state = TimaState::Running;
+        self.oam_dma = None;
+        self.ppu = Ppu::new(self.ppu.render_mode());
+        self.set_oam_lock();
+        if !self.lcdc_on() {
+            self.lcd_off();
+        }
+    }
+
+    /// The hardware being emulated
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Emulates `model` from now on, or picks the model from the cartridge
+    /// header with `None`, and resets
+    pub fn set_model(&mut self, model: Option<Model>) {
+        self.forced_model = model;
+        self.model = model.unwrap_or_else(|| Model::for_cartridge(self.mem.gbc_mode()));
+        if self.has_boot_rom() && self.mem.boot_rom().len() != self.model.boot_rom_size() {
+            warn!(
+                "The boot ROM is not for a {}, starting without it",
+                self.model
+            );
+            self.mem.set_boot_rom(vec![]);
+        }
+        self.reset();
+    }
+
+    /// Uses `boot_rom` at every reset from now on, and resets.  Without a
+    /// model asked for, the size picks one: 256 bytes for a DMG, 2304 for
+    /// a CGB
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        if self.forced_model.is_none() {
+            match boot_rom.len() {
+                CGB_BOOT_ROM_SIZE => self.model = Model::Cgb,
+                DMG_BOOT_ROM_SIZE if self.model.is_cgb() => self.model = Model::Dmg,
+                _ => (),
+            }
+        }
+        if boot_rom.len() != self.model.boot_rom_size() {
+            return Err(format!(
+                "Boot ROM for {} should be {} bytes, found {}",
+                self.model,
+                self.model.boot_rom_size(),
+                boot_rom.len()
+            ));
+        }
+        self.mem.set_boot_rom(boot_rom);
+        self.reset();
+        Ok(())
+    }
+
+    pub fn has_boot_rom(&self) -> bool {
+        !self.mem.boot_rom().is_empty()
+    }
+
+    /// Power on state for running the boot ROM, which sets up everything
+    /// `reset` sets without one
+    fn power_on(&mut self) {
+        self.state = CpuState::Normal;
+        self.ld_b_b_executed = false;
+        self.a = 0;
+        self.b = 0;
+        self.c = 0;
+        self.d = 0;
+        self.e = 0;
+        self.f = 0;
+        self.h = 0;
+        self.l = 0;
+        self.sp = 0;
+        self.ime = false;
+        self.pc = 0;
+        self.cycles = 0;
+        self.double_speed = false;
+        self.dmg_compat_palettes = false;
+        // the boot ROM decides whether the game runs in color
+        self.gbc_mode = self.model.is_cgb();
+
+        info!("power on {}", self.model);
+        self.mem.reset(self.model);
+        if self.gbc_mode {
+            self.mem.set_gbc_mode();
+        }
+        // the boot ROM turns the screen on once the logo is in VRAM
+        self.mem[0xFF40] = 0;
+        self.mem[0xFF4C] = 0;
+        self.mem.map_boot_rom();
+        self.apu.reset(self.model);
+        self.serial.reset();
+        self.reset_clocks(0);
+    }
+
+    /// Handles the write to 0xFF50 that ends the boot ROM
+    fn unmap_boot_rom(&mut self) {
+        if !self.mem.boot_rom_mapped() {
+            return;
+        }
+        self.mem.unmap_boot_rom();
+        // the CGB boot ROM sets KEY0 bit 2 for games without CGB support,
+        // after picking colors for them
+        if self.gbc_mode && self.mem[0xFF4C_u16] & 0x04 == 0x04 {
+            info!("Boot ROM finished, running in DMG compatibility mode");
+            self.gbc_mode = false;
+            self.dmg_compat_palettes = true;
+            self.mem.gbc_vram_bank = 0;
+            self.mem.gbc_wram_bank = 1;
+        } else {
+            info!("Boot ROM finished");
+        }
     }
 
     pub fn reinit_logger(&mut self) {
@@ -179,60 +401,87 @@ impl Cpu {
         }
     }
 
-    ///FF04 Div
-    ///
-    /// This needs to be called 16384 (~16779 on SGB) times a second
-    ///
-    pub fn inc_div(&mut self) {
-        let old_val = self.mem[0xFF04_u16];
-        self.mem[0xFF04] = old_val.wrapping_add(1);
+    /// Advances any serial transfer; call this with the cycles each
+    /// instruction took
+    pub fn serial_step(&mut self, cycles: u32) {
+        let sb = self.mem[0xFF01_u16];
+        if let Some(incoming) = self.serial.step(cycles, sb) {
+            self.mem[0xFF01] = incoming;
+            let sc = self.mem[0xFF02_u16];
+            self.mem[0xFF02] = sc & 0x7F;
+            self.set_serial_io_interrupt_bit();
+        }
     }
-    pub fn get_div(&self) -> u8 {
-        self.mem[0xFF04_u16]
+
+    /// Advances hardware on the cartridge that keeps its own time, like the
+    /// MBC3 real-time clock
+    pub fn cartridge_step(&mut self, cycles: u32, cycles_per_second: u64) {
+        self.mem.cartridge_step(cycles, cycles_per_second);
+        self.mem.develop_photo(self.camera.as_mut());
     }
 
-    /// The speed at which the timer runs, settable by the program by
-    /// writing to 0xFF07
-    pub fn timer_frequency_hz(&self) -> u32 {
-        // NOTE these values differ for SGB
-        match self.mem[0xFF07_u16] & 0x3 {
-            0 => 4096,
-            1 => 262_144,
-            2 => 65_536,
-            3 => 16_384,
-            _ => unreachable!("The impossible happened!"),
-        }
+    /// Sets what the sensor of a Pocket Camera cartridge sees
+    pub fn set_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        self.camera = source;
     }
 
-    /// Abstracts the logic of the timer
-    /// Call this from the loop when the timer should be incremented
-    /// NOTE: does not appear to take timer frequency into account...
-    pub fn timer_cycle(&mut self) {
-        if self.is_timer_on() {
-            self.inc_timer();
-        }
+    /// Sets the tilt read by a cartridge's accelerometer, in g from -1 to
+    /// 1: x is positive when tilted right, y when tilted towards the bottom
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.mem.set_tilt(x, y);
     }
 
-    fn is_timer_on(&self) -> bool {
-        (self.mem[0xFF07_u16] & 0x4) >> 2 == 1
+    /// Whether the cartridge's rumble motor is running
+    pub fn rumble_active(&self) -> bool {
+        self.mem.rumble_active()
     }
 
-    fn inc_timer(&mut self) {
-        let old_val = self.mem[0xFF05_u16];
-        // TMA; value which is to be set on overflow
-        let new_val = self.mem[0xFF06_u16];
+    /// The bytes sent over the link port, if the endpoint records them
+    pub fn serial_output(&self) -> &[u8] {
+        self.serial.endpoint().captured().unwrap_or(&[])
+    }
 
-        self.mem[0xFF05] = if old_val.wrapping_add(1) == 0 {
-            // on overflow...
-            self.set_timer_interrupt_bit();
-            // this seems wrong, but things break without it
-            if self.state == CpuState::Halt {
-                self.state = CpuState::Normal;
-            }
-            new_val
+    /// CPU cycles per second, doubled in CGB double speed mode
+    pub fn cycles_per_second(&self) -> u64 {
+        if self.gbc_mode && self.double_speed {
+            CPU_CYCLES_PER_SECOND * 2
         } else {
-            old_val.wrapping_add(1)
-        };
+            CPU_CYCLES_PER_SECOND
+        }
+    }
+
+    /// Runs everything but the CPU for one M-cycle (4 clock cycles).
+    ///
+    /// Called once for every memory access and internal delay of an
+    /// instruction, so the rest of the system sees the CPU's reads and
+    /// writes at the right time within it.
+    pub fn tick(&mut self) {
+        self.instruction_mcycles = self.instruction_mcycles.wrapping_add(1);
+        self.cycles = (Wrapping(self.cycles) + Wrapping(4)).0;
+        let cycles_per_second = self.cycles_per_second();
+
+        self.timer_step();
+        self.serial_step(4);
+        self.cartridge_step(4, cycles_per_second);
+        self.oam_dma_step();
+        // the LCD doesn't speed up in double speed mode
+        self.ppu_step(if self.gbc_mode && self.double_speed {
+            2
+        } else {
+            4
+        });
+    }
+
+    /// A memory access that takes an M-cycle
+    fn read_cycle(&mut self, address: MemAddr) -> byte {
+        self.tick();
+        self.get_mem(address)
+    }
+
+    /// A memory write that takes an M-cycle
+    fn write_cycle(&mut self, address: MemAddr, value: byte) {
+        self.tick();
+        self.set_mem(address, value);
     }
 
     set_interrupt_bit!(set_vblank_interrupt_bit, 0x1);
@@ -385,12 +634,6 @@ impl Cpu {
     pub fn inc_ly(&mut self) {
         let v = self.ly().wrapping_add(1) % 154;
         self.mem[0xFF44] = v as byte;
-        // interrupt should only be thrown on the rising edge (when ly
-        // turns to 144)
-        //TODO: verify that this should only be done if the interrupt is enabled
-        if v == 144 && self.get_interrupts_enabled() && self.get_vblank_interrupt_enabled() {
-            self.set_vblank_interrupt_bit();
-        }
         //LY check is done any time LY is updated
         self.lyc_compare();
     }
@@ -405,35 +648,46 @@ impl Cpu {
 
         if ly == lyc {
             self.set_coincidence_flag();
-            if self.get_interrupts_enabled()
-                && self.get_lcdc_interrupt_enabled()
-                && self.get_coincidence_interrupt()
-            {
-                // interrupts are only triggered on a rising edge
-                if !self.get_lcdc_interrupt_bit() {
-                    self.set_lcdc_interrupt_bit();
-                }
-            }
         } else {
             self.unset_coincidence_flag();
         }
+        self.update_stat_line();
     }
 
     /// Direct memory access, lets the CPU copy memory without being
     /// directly involve
     ///
-    /// Should take 160 microseconds
-    ///
-    /// During DMA, everything but high memory should be blocked
+    /// Starts a copy into OAM, which `oam_dma_step` runs a byte per
+    /// M-cycle after a 1 M-cycle delay
     fn dma(&mut self) {
-        let addr = (self.mem[0xFF46_u16] as MemAddr) << 8;
+        let mut addr = (self.mem[0xFF46_u16] as MemAddr) << 8;
+        // E000-FFFF reads from the work RAM behind the echo
+        if addr >= 0xE000 {
+            addr -= 0x2000;
+        }
+        self.oam_dma = Some((addr, 0));
+    }
 
-        for i in 0..=0x9F {
-            let val = self.mem[addr + i];
-            self.mem.oam[i as usize] = val;
+    /// Copies the next byte of a running OAM DMA
+    fn oam_dma_step(&mut self) {
+        if let Some((addr, copied)) = self.oam_dma {
+            if copied > 0 {
+                let i = copied as MemAddr - 1;
+                self.mem.oam[i as usize] = self.mem[addr + i];
+            }
+            self.oam_dma = if copied as usize == self.mem.oam.len() {
+                None
+            } else {
+                Some((addr, copied + 1))
+            };
         }
     }
 
+    /// While OAM DMA is copying, the CPU can't access OAM
+    fn oam_dma_active(&self) -> bool {
+        matches!(self.oam_dma, Some((_, copied)) if copied > 0)
+    }
+
     /// GBC only
     fn vram_dma(&mut self, suspend_resume_mode: bool) {
         if suspend_resume_mode {
@@ -589,6 +843,11 @@ impl Cpu {
         self.mem[0x147_u16]
     }
 
+    /// The global checksum from the cartridge header, used to tell ROMs apart
+    pub fn get_global_checksum(&self) -> u16 {
+        byte_to_u16(self.mem[0x14F_u16], self.mem[0x14E_u16])
+    }
+
     fn enable_interrupts(&mut self) {
         self.ime = true;
     }
@@ -605,6 +864,20 @@ impl Cpu {
         self.ime = false;
     }
 
+    /// A one line summary of the registers, for logs and test output
+    pub fn register_dump(&self) -> String {
+        format!(
+            "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} IME={}",
+            self.af(),
+            self.bc(),
+            self.de(),
+            self.hl(),
+            self.sp,
+            self.pc,
+            self.ime as u8
+        )
+    }
+
     fn af(&self) -> u16 {
         byte_to_u16(self.f, self.a)
     }
@@ -706,6 +979,7 @@ impl Cpu {
                 }
                 */
             }
+            OAM_START..=OAM_END if self.oam_dma_active() => 0xFF,
             OAM_START..=OAM_END => {
                 self.mem[address]
                 /*match self.mem[STAT_ADDR] & 3 {
@@ -751,6 +1025,7 @@ impl Cpu {
                     self.mem[v] = value as byte;
                 }*/
             }
+            OAM_START..=OAM_END if self.oam_dma_active() => (),
             v @ OAM_START..=OAM_END => {
                 //if OAM is in use
                 self.mem[v] = value as byte;
@@ -777,17 +1052,15 @@ impl Cpu {
                     self.mem[0xFF00] = value | (self.input_state & 0x0F);
                 }
             }
-            0xFF04 => {
-                let old_div_val = self.mem[0xFF04_u16];
-                self.mem[0xFF04] = 0;
-
-                let div_bit = if self.double_speed { 5 } else { 4 };
-                // Update APU-DIV
-                if (old_div_val >> div_bit) & 1 == 1 {
-                    // falling edge
-                    self.apu.step();
-                }
+            0xFF02 => {
+                // unused bits read back as 1; bit 1 (clock speed) only exists on CGB
+                self.mem[0xFF02] = value | if self.gbc_mode { 0x7C } else { 0x7E };
+                self.serial.write_control(value, self.gbc_mode);
             }
+            0xFF04 => self.reset_div(),
+            0xFF05 => self.write_tima(value),
+            0xFF06 => self.write_tma(value),
+            0xFF07 => self.write_tac(value),
             // TODO: Check whether vblank should be turned off on
             // writes to 0xFF44
 
@@ -795,6 +1068,8 @@ impl Cpu {
             0xFF10..=0xFF3F => {
                 self.apu.set_mem(address as u16, value);
             }
+            0xFF40 => self.write_lcdc(value),
+            0xFF41 => self.write_stat(value),
             0xFF44 => {
                 // cannot write to LY
                 //dbg!(self.mem[0xFF44] = 0),
@@ -802,12 +1077,23 @@ impl Cpu {
             0xFF45 => {
                 //LY check is done every time LY or LYC value is updated
                 self.mem[0xFF45] = value;
-                //self.lyc_compare();
+                self.lyc_compare();
             }
             0xFF46 => {
                 self.mem[0xFF46] = value;
                 self.dma();
             }
+            // KEY0, only writable by the CGB boot ROM
+            0xFF4C => {
+                if self.mem.boot_rom_mapped() {
+                    self.mem[0xFF4C] = value;
+                }
+            }
+            0xFF50 => {
+                if value != 0 {
+                    self.unmap_boot_rom();
+                }
+            }
             0xFF4F => {
                 self.mem[0xFF4F] = value;
                 if self.gbc_mode {
@@ -879,6 +1165,16 @@ impl Cpu {
         }
     }
 
+    /// `access_register` for instructions: reading (HL) takes an M-cycle
+    fn read_register(&mut self, reg: CpuRegister) -> Option<byte> {
+        if reg == CpuRegister::HL {
+            let hl = self.hl();
+            Some(self.read_cycle(hl))
+        } else {
+            self.access_register(reg)
+        }
+    }
+
     fn set_register(&mut self, reg: CpuRegister, val: byte) {
         match reg {
             CpuRegister::A => self.a = val,
@@ -890,7 +1186,7 @@ impl Cpu {
             CpuRegister::L => self.l = val,
             CpuRegister::HL => {
                 let hlv = self.hl();
-                self.set_mem(hlv, val);
+                self.write_cycle(hlv, val);
             }
             _ => panic!("Cannot set non-8bit values"),
         }
@@ -901,19 +1197,19 @@ impl Cpu {
     }
 
     fn ldr1r2(&mut self, r1: CpuRegister, r2: CpuRegister) {
-        let val = self.access_register(r2).expect("Invalid register");
+        let val = self.read_register(r2).expect("Invalid register");
         self.set_register(r1, val);
     }
 
     fn ldan16(&mut self, n: CpuRegister16) {
         let addr = self.access_register16(n);
-        let val = self.get_mem(addr);
+        let val = self.read_cycle(addr);
 
         self.set_register(CpuRegister::A, val);
     }
 
     fn ldan16c(&mut self, b1: u8, b2: u8) {
-        let val = self.get_mem(byte_to_u16(b1, b2));
+        let val = self.read_cycle(byte_to_u16(b1, b2));
         self.set_register(CpuRegister::A, val);
     }
 
@@ -923,32 +1219,32 @@ impl Cpu {
             .expect("Invalid register");
         let addr = self.access_register16(n);
 
-        self.set_mem(addr, val);
+        self.write_cycle(addr, val);
     }
 
     fn ldna16c(&mut self, b1: u8, b2: u8) {
         let val = self
             .access_register(CpuRegister::A)
             .expect("Invalid register");
-        self.set_mem(byte_to_u16(b1, b2), val);
+        self.write_cycle(byte_to_u16(b1, b2), val);
     }
 
     fn ldac(&mut self) {
         let reg_c = self.c;
         // TODO check if C should be unsigned
-        let val = self.get_mem(0xFF00u16 + (reg_c as u16));
+        let val = self.read_cycle(0xFF00u16 + (reg_c as u16));
         self.set_register(CpuRegister::A, val);
     }
 
     fn ldca(&mut self) {
         let addr = 0xFF00u16 + (self.c as u16);
         let val = self.a;
-        self.set_mem(addr, val);
+        self.write_cycle(addr, val);
     }
 
     fn lddahl(&mut self) {
         let addr = self.hl();
-        let val = self.get_mem(addr);
+        let val = self.read_cycle(addr);
 
         self.set_register(CpuRegister::A, val);
         self.dec16(CpuRegister16::HL);
@@ -958,13 +1254,13 @@ impl Cpu {
         let val = self.a;
         let addr = self.hl();
 
-        self.set_mem(addr, val);
+        self.write_cycle(addr, val);
         self.dec16(CpuRegister16::HL);
     }
 
     fn ldiahl(&mut self) {
         let addr = self.hl();
-        let val = self.get_mem(addr);
+        let val = self.read_cycle(addr);
 
         self.set_register(CpuRegister::A, val);
         self.inc16(CpuRegister16::HL);
@@ -974,17 +1270,17 @@ impl Cpu {
         let val = self.a;
         let addr = self.hl();
 
-        self.set_mem(addr, val);
+        self.write_cycle(addr, val);
         self.inc16(CpuRegister16::HL);
     }
 
     fn ldhna(&mut self, n: u8) {
         let val = self.a;
-        self.set_mem(0xFF00u16 + (n as u16), val);
+        self.write_cycle(0xFF00u16 + (n as u16), val);
     }
 
     fn ldhan(&mut self, n: u8) {
-        let val = self.get_mem(0xFF00u16 + (n as u16));
+        let val = self.read_cycle(0xFF00u16 + (n as u16));
         self.set_register(CpuRegister::A, val);
     }
 
@@ -1009,8 +1305,8 @@ impl Cpu {
         let old_sp = self.sp;
         let addr = byte_to_u16(b1, b2);
         // TODO function to write word (16 bit) to memory
-        self.set_mem(addr, old_sp as u8);
-        self.set_mem(addr.wrapping_add(1), (old_sp >> 8) as u8);
+        self.write_cycle(addr, old_sp as u8);
+        self.write_cycle(addr.wrapping_add(1), (old_sp >> 8) as u8);
     }
 
     // fn pushnn(&mut self, nn: CpuRegister16) {
@@ -1025,24 +1321,25 @@ impl Cpu {
     // }
 
     //TODO: rename this awfully named function
-    fn alu_dispatch<F>(&self, reg: CpuRegister, f: F) -> i16
+    fn alu_dispatch<F>(&mut self, reg: CpuRegister, f: F) -> i16
     where
         F: FnOnce(byte, byte) -> i16,
     {
-        f(
-            self.a,
-            match reg {
-                CpuRegister::A => self.a,
-                CpuRegister::B => self.b,
-                CpuRegister::C => self.c,
-                CpuRegister::D => self.d,
-                CpuRegister::E => self.e,
-                CpuRegister::H => self.h,
-                CpuRegister::L => self.l,
-                CpuRegister::HL => self.get_mem(self.hl()),
-                CpuRegister::Num(i) => i,
-            },
-        )
+        let val = match reg {
+            CpuRegister::A => self.a,
+            CpuRegister::B => self.b,
+            CpuRegister::C => self.c,
+            CpuRegister::D => self.d,
+            CpuRegister::E => self.e,
+            CpuRegister::H => self.h,
+            CpuRegister::L => self.l,
+            CpuRegister::HL => {
+                let hl = self.hl();
+                self.read_cycle(hl)
+            }
+            CpuRegister::Num(i) => i,
+        };
+        f(self.a, val)
     }
 
     //TODO: rename this awfully named function
@@ -1064,7 +1361,7 @@ impl Cpu {
     }
 
     fn reg_or_const(&mut self, reg: CpuRegister) -> i8 {
-        if let Some(r) = self.access_register(reg) {
+        if let Some(r) = self.read_register(reg) {
             r as i8
         } else if let CpuRegister::Num(v) = reg {
             v as i8
@@ -1185,9 +1482,9 @@ impl Cpu {
 
     fn inc(&mut self, reg: CpuRegister) {
         let old_c = (self.f & CL) == CL;
-        let old_3bit = self.access_register(reg).expect("invalid register") & 0x8;
+        let old_val: i16 = self.read_register(reg).expect("invalid register") as i16;
+        let old_3bit = old_val as byte & 0x8;
 
-        let old_val: i16 = self.access_register(reg).expect("invalid register") as i16;
         let new_val = (old_val + 1) as byte;
         self.set_register(reg, new_val);
         self.set_flags(
@@ -1202,7 +1499,7 @@ impl Cpu {
     fn dec(&mut self, reg: CpuRegister) {
         let old_c = (self.f & CL) == CL;
 
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
 
         let new_val: byte = reg_val.wrapping_sub(1) as byte;
         self.set_register(reg, new_val);
@@ -1307,7 +1604,7 @@ impl Cpu {
     fn swap(&mut self, reg: CpuRegister) {
         //Potentially can bitmask hl which is 16bit value
         let val = self
-            .access_register(reg)
+            .read_register(reg)
             .expect("couldn't access register value");
         let top = val & 0xF0u8;
         let bot = val & 0x0Fu8;
@@ -1381,15 +1678,7 @@ impl Cpu {
             self.double_speed = speed_mode;
             self.mem[0xFF4D] = (speed_mode as u8) << 7;
         } else {
-            let old_div_val = self.mem[0xFF04_u16];
-            let div_bit = if self.double_speed { 5 } else { 4 };
-            // reset div
-            self.mem[0xFF04] = 0;
-            // Update APU-DIV
-            if (old_div_val >> div_bit) & 1 == 1 {
-                // falling edge
-                self.apu.step();
-            }
+            self.reset_div();
 
             self.state = CpuState::Stop;
         }
@@ -1442,7 +1731,7 @@ impl Cpu {
     }
 
     fn rlc(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit7 = (reg_val >> 7) & 1;
 
         let new_reg = ((reg_val << 1) & 0xFEu8) | old_bit7; // | old_carry;
@@ -1452,7 +1741,7 @@ impl Cpu {
     }
 
     fn rl(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit7 = (reg_val >> 7) & 1;
         let old_flags = ((self.f & CL) >> 4) & 0xF;
 
@@ -1463,7 +1752,7 @@ impl Cpu {
     }
 
     fn rrc(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit0 = reg_val & 1;
 
         let new_val = ((reg_val >> 1) & 0x7F) | (old_bit0 << 7);
@@ -1474,7 +1763,7 @@ impl Cpu {
 
     /// Rotate n right through Carry flag.
     fn rr(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit0 = reg_val & 1;
         let old_flags = (self.f & CL) << 3;
 
@@ -1485,7 +1774,7 @@ impl Cpu {
     }
 
     fn sla(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit7 = (reg_val >> 7) & 1;
         self.set_register(reg, reg_val << 1);
 
@@ -1493,7 +1782,7 @@ impl Cpu {
     }
 
     fn sra(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit0 = reg_val & 1;
         let old_bit7 = reg_val & 0x80;
         self.set_register(reg, (reg_val >> 1) | old_bit7);
@@ -1502,7 +1791,7 @@ impl Cpu {
     }
 
     fn srl(&mut self, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_bit0 = reg_val & 1;
 
         self.set_register(reg, (reg_val >> 1) as byte);
@@ -1511,19 +1800,19 @@ impl Cpu {
     }
 
     fn bit(&mut self, b: u8, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         let old_flags = (self.f & CL) >> 4;
 
         self.set_flags(((reg_val >> b) & 1) != 1, false, true, (old_flags & 1) == 1);
     }
 
     fn set(&mut self, b: u8, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         self.set_register(reg, reg_val | (1 << b));
     }
 
     fn res(&mut self, b: u8, reg: CpuRegister) {
-        let reg_val = self.access_register(reg).expect("invalid register");
+        let reg_val = self.read_register(reg).expect("invalid register");
         self.set_register(reg, reg_val & (!(1 << b)));
     }
 
@@ -1603,11 +1892,13 @@ impl Cpu {
         let first_half = ((nn >> 8) & 0xFF) as byte;
         let second_half = (nn & 0xFF) as byte;
 
+        // SP is decremented before the first write
+        self.tick();
         let mut sp_idx = Wrapping(self.sp);
         sp_idx -= Wrapping(1);
-        self.set_mem(sp_idx.0, first_half);
+        self.write_cycle(sp_idx.0, first_half);
         sp_idx -= Wrapping(1);
-        self.set_mem(sp_idx.0, second_half);
+        self.write_cycle(sp_idx.0, second_half);
 
         self.sp = (Wrapping(self.sp) - Wrapping(2)).0;
     }
@@ -1638,9 +1929,9 @@ impl Cpu {
 
     fn pop_from_stack(&mut self) -> u16 {
         let mut sp_idx = Wrapping(self.sp as MemAddr);
-        let second_half = self.get_mem(sp_idx.0);
+        let second_half = self.read_cycle(sp_idx.0);
         sp_idx += Wrapping(1);
-        let first_half = self.get_mem(sp_idx.0);
+        let first_half = self.read_cycle(sp_idx.0);
 
         self.sp = (Wrapping(self.sp) + Wrapping(2)).0;
         byte_to_u16(second_half, first_half)
@@ -1656,6 +1947,8 @@ impl Cpu {
     }
 
     fn retcc(&mut self, cc: Cc) -> bool {
+        // checking the condition takes an M-cycle
+        self.tick();
         if 1 == match cc {
             Cc::NZ => (!(self.f >> 7)) & 1,
             Cc::Z => (self.f >> 7) & 1,
@@ -1690,13 +1983,19 @@ impl Cpu {
         self.pc = self.pc.wrapping_add(1);
     }
 
-    fn handle_interrupts(&mut self) {
+    /// Jumps to the handler of a pending interrupt; returns whether one was
+    /// handled, which takes 5 M-cycles
+    fn handle_interrupts(&mut self) -> bool {
+        // any requested interrupt that is enabled in IE ends HALT, even
+        // with IME off; it is then only serviced if IME is on
+        let requested = self.mem[0xFF0F_u16] & self.mem[0xFFFF_u16] & 0x1F != 0;
+        if requested && self.state == CpuState::Halt {
+            self.state = CpuState::Normal;
+        }
         if !self.get_interrupts_enabled() {
-            return;
+            return false;
         }
-        if self.state == CpuState::Halt {
-            self.state = CpuState::Normal;
-        } else if self.state == CpuState::Stop {
+        if self.state == CpuState::Stop {
             //TODO: handle interrupt on stop
             //self.state = CpuState::Normal;
         }
@@ -1705,56 +2004,52 @@ impl Cpu {
         if self.get_vblank_interrupt_enabled() && self.get_vblank_interrupt_bit() {
             //handle vblank interrupt
             trace!("INT: handle vblank interrupt");
-            let old_pc = self.pc;
-
             self.disable_interrupts();
             self.unset_vblank_interrupt_bit();
-            self.push_onto_stack(old_pc);
-
-            self.pc = VBLANK_INTERRUPT_ADDRESS;
+            self.jump_to_interrupt(VBLANK_INTERRUPT_ADDRESS);
+            true
         } else if self.get_lcdc_interrupt_enabled() && self.get_lcdc_interrupt_bit() {
             //handle lcdc interrupt
             trace!("INT: handle lcdc interrupt");
-            let old_pc = self.pc;
-
             self.disable_interrupts();
             self.unset_lcdc_interrupt_bit();
-            self.push_onto_stack(old_pc);
-
-            self.pc = LCDC_INTERRUPT_ADDRESS;
+            self.jump_to_interrupt(LCDC_INTERRUPT_ADDRESS);
+            true
         } else if self.get_timer_interrupt_enabled() && self.get_timer_interrupt_bit() {
             //handle timer interrupt
             trace!("INT: handle timer interrupt");
-            let old_pc = self.pc;
-
             self.disable_interrupts();
             self.unset_timer_interrupt_bit();
-            self.push_onto_stack(old_pc);
-
-            self.pc = TIMER_OVERFLOW_INTERRUPT_ADDRESS;
+            self.jump_to_interrupt(TIMER_OVERFLOW_INTERRUPT_ADDRESS);
+            true
         } else if self.get_serial_io_interrupt_enabled() && self.get_serial_io_interrupt_bit() {
             //handle serial interrupt
             trace!("INT: handle serial inturrupt");
-            let old_pc = self.pc;
-
             self.disable_interrupts();
             self.unset_serial_io_interrupt_bit();
-            self.push_onto_stack(old_pc);
-
-            self.pc = SERIAL_TRANSFER_INTERRUPT_ADDRESS;
+            self.jump_to_interrupt(SERIAL_TRANSFER_INTERRUPT_ADDRESS);
+            true
         } else if self.get_input_interrupt_enabled() && self.get_input_interrupt_bit() {
             debug!("INT: handle input");
 
-            let old_pc = self.pc;
-
             self.disable_interrupts();
             self.unset_input_interrupt_bit();
-            self.push_onto_stack(old_pc);
-
-            self.pc = P1013_INTERRUPT_ADDRESS;
+            self.jump_to_interrupt(P1013_INTERRUPT_ADDRESS);
+            true
+        } else {
+            false
         }
     }
 
+    /// Pushes PC and jumps to an interrupt handler
+    fn jump_to_interrupt(&mut self, address: MemAddr) {
+        self.tick();
+        self.tick();
+        let old_pc = self.pc;
+        self.push_onto_stack(old_pc);
+        self.pc = address;
+    }
+
     /*
     Handles running opcodes
     including handling of interrupts
@@ -1771,8 +2066,9 @@ impl Cpu {
         if self.state == CpuState::Crashed {
             panic!("Attempt to run a crashed cpu PC={}", self.pc);
         }
+        self.instruction_mcycles = 0;
         // This may change PC, so should be called before fetching instruction
-        self.handle_interrupts();
+        let interrupted = self.handle_interrupts();
 
         let mut inst_time = 4;
         let (first_byte, second_byte, third_byte, _) = self.read_instruction();
@@ -1784,11 +2080,21 @@ impl Cpu {
         #[allow(clippy::if_same_then_else)]
         if self.state == CpuState::Halt {
             //TODO: Needs extra handling with interupts
-            return inst_time; //unsure of this
+            self.tick();
+            return self.instruction_mcycles * 4; //unsure of this
         } else if self.state == CpuState::Stop {
-            return inst_time; //unsure of this
+            self.tick();
+            return self.instruction_mcycles * 4; //unsure of this
         } //otherwise it's in normal state:
 
+        // the opcode and its operands are fetched an M-cycle a byte
+        for _ in 0..instruction_length(first_byte) {
+            self.tick();
+        }
+        if first_byte == 0x40 {
+            self.ld_b_b_executed = true;
+        }
+
         {
             let cur_pc = self.pc;
             if let Some(ref mut logger) = self.mem.logger {
@@ -2226,10 +2532,21 @@ impl Cpu {
 
         self.inc_pc();
 
-        self.cycles = (Wrapping(self.cycles) + Wrapping(inst_time as u64)).0;
+        // whatever the instruction didn't tick itself is spent internally
+        let mcycles = inst_time / 4 + if interrupted { 5 } else { 0 };
+        debug_assert!(
+            self.instruction_mcycles <= mcycles,
+            "opcode {:02X} took {} M-cycles, expected {}",
+            first_byte,
+            self.instruction_mcycles,
+            mcycles
+        );
+        while self.instruction_mcycles < mcycles {
+            self.tick();
+        }
         self.maybe_disable_interrupts();
 
-        inst_time
+        self.instruction_mcycles * 4
     }
 
     pub fn crash(&mut self, info: String) {
@@ -2237,38 +2554,144 @@ impl Cpu {
         self.state = CpuState::Crashed;
     }
 
-    /// Loads the ROM with saved RAM if its available
-    pub fn load_rom(&mut self, rom_bytes: Vec<u8> /*, data_path: Option<PathBuf>*/) {
+    /// Loads the ROM; its saved RAM is restored with `load_saved_ram`
+    pub fn load_rom(&mut self, rom_bytes: Vec<u8>) {
         trace!("Loading ROM");
         self.mem.load(rom_bytes);
-        self.gbc_mode = self.mem.gbc_mode();
-        if self.gbc_mode {
-            self.mem.set_gbc_mode();
+        self.rom_name = None;
+        if self.forced_model.is_none() {
+            // a boot ROM only runs on the hardware it was made for
+            self.model = match self.mem.boot_rom().len() {
+                CGB_BOOT_ROM_SIZE => Model::Cgb,
+                DMG_BOOT_ROM_SIZE => Model::Dmg,
+                _ => Model::for_cartridge(self.mem.gbc_mode()),
+            };
         }
-        self.sgb_mode = self.mem.gbc_mode();
         self.reset();
 
-        // TODO: revisit where this code should live when properly implementing
-        // resuming from saves / savestates.
-        /*
-        // load RAM if it exists
-        if let Some(data_location) = data_path {
-            let game_name = self.get_game_name();
-            self.mem.load_saved_ram(data_location, game_name.as_ref());
-        }
-        */
-
         // disable for now
         //self.mem.initialize_logger();
     }
 
-    pub fn save_ram(&self, data_path: Option<PathBuf>) {
+    /// Names saves after the file the ROM was loaded from, e.g. the ROM
+    /// inside an archive, rather than the game's title
+    pub fn set_rom_name(&mut self, file_name: &str) {
+        self.rom_name = Path::new(file_name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .filter(|stem| !stem.is_empty());
+    }
+
+    /// What the loaded game's saves are called: the ROM's file name if it
+    /// is known, otherwise its title, without what can't go in a file
+    /// name, and its global checksum, so that games sharing a title (or
+    /// with none) don't share saves
+    fn save_name(&self) -> String {
+        if let Some(ref rom_name) = self.rom_name {
+            return rom_name.clone();
+        }
+        // 0x143 is the CGB flag on newer cartridges
+        let title: String = (0x134..0x143_u16)
+            .map(|address| self.mem[address])
+            .take_while(|&c| c != 0)
+            .filter(|c| (c.is_ascii_graphic() || *c == b' ') && !matches!(c, b'/' | b'\\'))
+            .map(char::from)
+            .collect();
+        format!("{} {:04X}", title.trim(), self.get_global_checksum())
+    }
+
+    /// Where the current ROM's battery save lives by default
+    pub fn save_file_path(&self, mut data_path: PathBuf) -> PathBuf {
+        // not `set_extension`, titles can have dots in them
+        data_path.push(format!("{}.sav", self.save_name()));
+        data_path
+    }
+
+    /// Restores battery backed cartridge RAM from a `.sav` file, either
+    /// plain RAM or RAM followed by a clock footer
+    pub fn load_saved_ram(&mut self, save_file: &Path) {
+        trace!("Loading RAM");
+        self.mem.load_saved_ram(save_file);
+    }
+
+    /// Writes battery backed cartridge RAM to `save_file`
+    pub fn save_ram(&mut self, save_file: &Path) {
         trace!("Saving RAM");
+        self.mem.save_ram(save_file);
+    }
+
+    /// Like `save_ram`, but only if RAM has changed since the last save
+    pub fn flush_ram(&mut self, save_file: &Path) {
+        if self.mem.ram_dirty() {
+            self.save_ram(save_file);
+        }
+    }
+
+    /// Serializes the entire machine into a save-state
+    pub fn save_state_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        write_header(
+            &mut writer,
+            &self.get_game_name(),
+            self.get_global_checksum(),
+        );
+        self.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Restores a save-state made by `save_state_bytes`.
+    ///
+    /// The state is loaded into a copy first so that a bad or truncated
+    /// state leaves the running emulator untouched.
+    pub fn load_state_bytes(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data);
+        check_header(
+            &mut reader,
+            &self.get_game_name(),
+            self.get_global_checksum(),
+        )?;
+
+        let mut new_cpu = self.clone();
+        new_cpu.load_state(&mut reader)?;
+        if !reader.is_empty() {
+            return Err("Save-state has trailing data".to_string());
+        }
+        // keep whatever is plugged into the link port
+        new_cpu.serial.set_endpoint(self.serial.take_endpoint());
+        new_cpu.camera = std::mem::replace(&mut self.camera, Box::new(BlankCamera));
+        *self = new_cpu;
+        Ok(())
+    }
+
+    /// Where the save-state for `slot` of the current ROM lives
+    pub fn save_state_path(&self, mut data_path: PathBuf, slot: u8) -> PathBuf {
+        data_path.push("states");
+        data_path.push(format!("{}.ss{}", self.save_name(), slot));
+        data_path
+    }
 
-        if let Some(data_location) = data_path {
-            let game_name = self.get_game_name();
-            self.mem.save_ram(data_location, game_name.as_ref());
+    pub fn save_state_to_slot(&self, data_path: PathBuf, slot: u8) -> Result<PathBuf, String> {
+        use std::io::Write;
+
+        let path = self.save_state_path(data_path, slot);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Could not create save-state directory: {}", e))?;
         }
+        let mut file = std::fs::File::create(&path)
+            .map_err(|e| format!("Could not create save-state {:?}: {}", path, e))?;
+        file.write_all(&self.save_state_bytes())
+            .map_err(|e| format!("Could not write save-state {:?}: {}", path, e))?;
+
+        Ok(path)
+    }
+
+    pub fn load_state_from_slot(&mut self, data_path: PathBuf, slot: u8) -> Result<(), String> {
+        let path = self.save_state_path(data_path, slot);
+        let data = std::fs::read(&path)
+            .map_err(|e| format!("Could not read save-state {:?}: {}", path, e))?;
+
+        self.load_state_bytes(&data)
     }
 
     pub fn remove_old_events(&mut self) {
@@ -2290,3 +2713,98 @@ impl Cpu {
         }
     }
 }
+
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.a);
+        writer.write_u8(self.b);
+        writer.write_u8(self.c);
+        writer.write_u8(self.d);
+        writer.write_u8(self.e);
+        writer.write_u8(self.f);
+        writer.write_u8(self.h);
+        writer.write_u8(self.l);
+        writer.write_u16(self.sp);
+        writer.write_bool(self.ime);
+        writer.write_u16(self.pc);
+        writer.write_u8(match self.state {
+            CpuState::Normal => 0,
+            CpuState::Halt => 1,
+            CpuState::Stop => 2,
+            CpuState::Crashed => 3,
+        });
+        writer.write_u8(self.input_state);
+        writer.write_u64(self.cycles);
+        writer.write_u16(self.div_counter);
+        writer.write_u8(self.tima_state.id());
+        writer.write_bool(self.oam_dma.is_some());
+        let (dma_source, dma_copied) = self.oam_dma.unwrap_or((0, 0));
+        writer.write_u16(dma_source);
+        writer.write_u8(dma_copied);
+        writer.write_bool(self.interrupt_next_inst);
+        writer.write_bool(self.gbc_mode);
+        writer.write_u8(self.model.id());
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.dmg_compat_palettes);
+
+        self.mem.save_state(writer);
+        self.apu.save_state(writer);
+        self.ppu.save_state(writer);
+        self.serial.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.a = reader.read_u8()?;
+        self.b = reader.read_u8()?;
+        self.c = reader.read_u8()?;
+        self.d = reader.read_u8()?;
+        self.e = reader.read_u8()?;
+        self.f = reader.read_u8()?;
+        self.h = reader.read_u8()?;
+        self.l = reader.read_u8()?;
+        self.sp = reader.read_u16()?;
+        self.ime = reader.read_bool()?;
+        self.pc = reader.read_u16()?;
+        self.state = match reader.read_u8()? {
+            0 => CpuState::Normal,
+            1 => CpuState::Halt,
+            2 => CpuState::Stop,
+            3 => CpuState::Crashed,
+            otherwise => return Err(format!("Invalid CPU state {} in save-state", otherwise)),
+        };
+        self.input_state = reader.read_u8()?;
+        self.cycles = reader.read_u64()?;
+        self.div_counter = reader.read_u16()?;
+        let tima_state = reader.read_u8()?;
+        self.tima_state = TimaState::from_id(tima_state)
+            .ok_or_else(|| format!("Invalid timer state {} in save-state", tima_state))?;
+        let dma_running = reader.read_bool()?;
+        let dma_source = reader.read_u16()?;
+        let dma_copied = reader.read_u8()?;
+        if dma_copied as usize > self.mem.oam.len() {
+            return Err(format!(
+                "Invalid OAM DMA progress {} in save-state",
+                dma_copied
+            ));
+        }
+        self.oam_dma = if dma_running {
+            Some((dma_source, dma_copied))
+        } else {
+            None
+        };
+        self.interrupt_next_inst = reader.read_bool()?;
+        self.gbc_mode = reader.read_bool()?;
+        let model = reader.read_u8()?;
+        self.model = Model::from_id(model)
+            .ok_or_else(|| format!("Invalid model {} in save-state", model))?;
+        self.double_speed = reader.read_bool()?;
+        self.dmg_compat_palettes = reader.read_bool()?;
+
+        self.mem.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.ppu.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.restart_drawing();
+        Ok(())
+    }
+}
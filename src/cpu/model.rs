@@ -0,0 +1,148 @@
+//! The Game Boy models that can be emulated
+//!
+//! The model decides which registers exist, what they hold after the boot
+//! ROM and which boot ROM is expected.  Games check some of these values
+//! to tell the hardware apart, e.g. `A` is 0x11 on a Game Boy Color and
+//! bit 0 of `B` is set on a Game Boy Advance.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cpu::constants::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    /// The original Game Boy
+    Dmg,
+    /// Game Boy Pocket and Game Boy Light
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Game Boy Color
+    Cgb,
+    /// Game Boy Advance, running Game Boy Color games
+    Agb,
+}
+
+/// What `--model` accepts
+pub const MODEL_NAMES: [&str; 5] = ["dmg", "mgb", "sgb", "cgb", "agb"];
+
+/// Registers as the boot ROM leaves them: A, F, B, C, D, E, H, L
+pub type BootRegisters = [u8; 8];
+
+impl Model {
+    /// The model a game is best run on: a Game Boy Color if it has color
+    /// support, an original Game Boy otherwise
+    pub fn for_cartridge(gbc: bool) -> Model {
+        if gbc {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    /// Whether the model has the Game Boy Color hardware (double speed,
+    /// color palettes, banked VRAM and WRAM)
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Model::Sgb
+    }
+
+    /// Registers after the boot ROM; on CGB hardware the boot ROM leaves
+    /// different values for games without color support
+    pub fn boot_registers(self, cgb_game: bool) -> BootRegisters {
+        match (self, cgb_game) {
+            (Model::Dmg, _) => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            (Model::Mgb, _) => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            (Model::Sgb, _) => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            (Model::Cgb, true) => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            (Model::Cgb, false) => [0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C],
+            (Model::Agb, true) => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            (Model::Agb, false) => [0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C],
+        }
+    }
+
+    /// DIV and the counter bits below it as the boot ROM leaves them.
+    /// Only the DMG's value is known for sure; the other boot ROMs take
+    /// a time that depends on the cartridge header, so they start the same
+    pub fn boot_divider(self) -> u16 {
+        0xABCC
+    }
+
+    /// Size of this model's boot ROM
+    pub fn boot_rom_size(self) -> usize {
+        if self.is_cgb() {
+            CGB_BOOT_ROM_SIZE
+        } else {
+            DMG_BOOT_ROM_SIZE
+        }
+    }
+
+    /// What this model's boot ROM is called in the user config path
+    pub fn boot_rom_file_name(self) -> &'static str {
+        match self {
+            Model::Dmg => "dmg_boot.bin",
+            Model::Mgb => "mgb_boot.bin",
+            Model::Sgb => "sgb_boot.bin",
+            Model::Cgb => "cgb_boot.bin",
+            Model::Agb => "agb_boot.bin",
+        }
+    }
+
+    /// Identifies the model in save-states and the library API
+    pub fn id(self) -> u8 {
+        match self {
+            Model::Dmg => 1,
+            Model::Mgb => 2,
+            Model::Sgb => 3,
+            Model::Cgb => 4,
+            Model::Agb => 5,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Model> {
+        match id {
+            1 => Some(Model::Dmg),
+            2 => Some(Model::Mgb),
+            3 => Some(Model::Sgb),
+            4 => Some(Model::Cgb),
+            5 => Some(Model::Agb),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!(
+                "Unknown model {}, expected one of {}",
+                s,
+                MODEL_NAMES.join(", ")
+            )),
+        }
+    }
+}
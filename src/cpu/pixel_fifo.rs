@@ -0,0 +1,470 @@
+//! The pixel FIFO renderer
+//!
+//! Draws mode 3 a dot at a time the way the hardware does: a fetcher reads
+//! a tile row from VRAM every 6 dots into the background FIFO, which shifts
+//! out a pixel per dot.  Sprites are fetched into their own FIFO when the
+//! output reaches them, stalling the pixels while they are read, and the
+//! window restarts the fetcher.  So mode 3 gets longer with fine scrolling,
+//! the window and sprites, and registers changed during mode 3 show up from
+//! the pixel they were changed at.
+//!
+//! Only whether the window has started this frame is kept in save-states;
+//! a state saved during mode 3 starts the line over from a clean FIFO.
+
+use std::collections::VecDeque;
+
+use crate::cpu::constants::MemAddr;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+use crate::cpu::Cpu;
+use crate::io::constants::*;
+
+/// Dots each fetcher step (tile number, low byte, high byte) takes
+const FETCH_STEP_DOTS: u8 = 2;
+/// Dots reading a sprite's tile row stalls the pixel output
+const SPRITE_FETCH_DOTS: u8 = 6;
+/// At most this many sprites are drawn on a line
+const SPRITES_PER_LINE: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+struct BgPixel {
+    color: u8,
+    /// CGB palette number
+    palette: u8,
+    /// CGB attribute bit 7: drawn over sprites
+    priority: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SpritePixel {
+    color: u8,
+    /// OBP0/OBP1 on DMG, the palette number on CGB
+    palette: u8,
+    /// OAM flag bit 7: only drawn over background color 0
+    behind_bg: bool,
+    oam_index: u8,
+}
+
+/// A sprite picked during the OAM scan
+#[derive(Debug, Clone, Copy)]
+struct LineSprite {
+    x: u8,
+    y: u8,
+    tile: u8,
+    flags: u8,
+    oam_index: u8,
+    fetched: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    /// Waits for the background FIFO to empty
+    Push,
+}
+
+#[derive(Debug, Clone)]
+pub struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    /// Lined up with `bg`: the front is drawn over the next pixel
+    sprites: VecDeque<SpritePixel>,
+    step: FetchStep,
+    step_dots: u8,
+    /// The first fetch of a line is thrown away
+    first_fetch: bool,
+    /// Tile column the fetcher is on, relative to SCX or the window
+    fetch_x: u8,
+    tile: u8,
+    attributes: u8,
+    data_low: u8,
+    data_high: u8,
+    /// Pixels drawn on this line
+    x: u8,
+    /// Pixels still to drop for fine scrolling
+    discard: u8,
+    /// The fetcher is drawing the window
+    window: bool,
+    /// LY has matched WY this frame, so the window can start
+    window_line: bool,
+    line_sprites: Vec<LineSprite>,
+    /// Index into `line_sprites` and dots spent reading it
+    sprite_fetch: Option<(usize, u8)>,
+}
+
+impl Default for PixelFifo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        PixelFifo {
+            bg: VecDeque::with_capacity(16),
+            sprites: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            first_fetch: true,
+            fetch_x: 0,
+            tile: 0,
+            attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            x: 0,
+            discard: 0,
+            window: false,
+            window_line: false,
+            line_sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            sprite_fetch: None,
+        }
+    }
+
+    /// Whether the window was drawn on the line
+    pub fn drew_window(&self) -> bool {
+        self.window
+    }
+
+    fn restart_fetch(&mut self) {
+        self.step = FetchStep::Tile;
+        self.step_dots = 0;
+        self.fetch_x = 0;
+    }
+}
+
+impl SaveState for PixelFifo {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.window_line);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        *self = PixelFifo::new();
+        self.window_line = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Cpu {
+    /// Picks the sprites on the line and gets ready for mode 3
+    pub(crate) fn fifo_start_line(&mut self) {
+        let ly = self.ly();
+        let height = if self.lcdc_sprite_size() { 16 } else { 8 };
+        if ly == 0 {
+            self.ppu.fifo.window_line = false;
+        }
+        if ly == self.window_y_pos() {
+            self.ppu.fifo.window_line = true;
+        }
+
+        let mut line_sprites = std::mem::take(&mut self.ppu.fifo.line_sprites);
+        line_sprites.clear();
+        for oam_index in 0..40u8 {
+            let offset = oam_index as usize * OBJECT_ATTRIBUTE_BLOCK_SIZE as usize;
+            let y = self.mem.oam[offset];
+            let top = ly.wrapping_add(16);
+            if top >= y && top < y.wrapping_add(height) {
+                line_sprites.push(LineSprite {
+                    x: self.mem.oam[offset + 1],
+                    y,
+                    tile: self.mem.oam[offset + 2],
+                    flags: self.mem.oam[offset + 3],
+                    oam_index,
+                    fetched: false,
+                });
+                if line_sprites.len() == SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+        if !self.gbc_mode {
+            // on DMG the sprite further left wins, so fetch it first
+            line_sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        let scx = self.scx();
+        let fifo = &mut self.ppu.fifo;
+        fifo.line_sprites = line_sprites;
+        fifo.bg.clear();
+        fifo.sprites.clear();
+        fifo.restart_fetch();
+        fifo.first_fetch = true;
+        fifo.x = 0;
+        fifo.discard = scx & 0x7;
+        fifo.window = false;
+        fifo.sprite_fetch = None;
+    }
+
+    /// Runs mode 3 for a dot; returns whether the line is finished
+    pub(crate) fn fifo_dot(&mut self) -> bool {
+        if self.ppu.fifo.x as usize >= GB_SCREEN_WIDTH {
+            return true;
+        }
+
+        if self.ppu.fifo.sprite_fetch.is_none()
+            && self.ppu.fifo.discard == 0
+            && self.lcdc_sprite_display()
+        {
+            let x = self.ppu.fifo.x as u16;
+            self.ppu.fifo.sprite_fetch = self
+                .ppu
+                .fifo
+                .line_sprites
+                .iter()
+                .position(|sprite| !sprite.fetched && sprite.x as u16 <= x + 8)
+                .map(|i| (i, 0));
+        }
+        if let Some((i, dots)) = self.ppu.fifo.sprite_fetch {
+            // the background fetch in progress is finished first
+            if self.ppu.fifo.step != FetchStep::Push || self.ppu.fifo.bg.is_empty() {
+                self.fetcher_dot();
+            } else if dots + 1 < SPRITE_FETCH_DOTS {
+                self.ppu.fifo.sprite_fetch = Some((i, dots + 1));
+            } else {
+                self.fetch_sprite(i);
+                self.ppu.fifo.sprite_fetch = None;
+            }
+            return false;
+        }
+
+        let wx = self.window_x_pos();
+        if !self.ppu.fifo.window
+            && self.ppu.fifo.window_line
+            && self.lcdc_window_on()
+            && self.ppu.fifo.x as u16 + 7 >= wx as u16
+        {
+            let fifo = &mut self.ppu.fifo;
+            fifo.window = true;
+            fifo.bg.clear();
+            fifo.restart_fetch();
+            // the window isn't scrolled, but is cut off left of WX 7
+            fifo.discard = 7u8.saturating_sub(wx);
+            return false;
+        }
+
+        self.fetcher_dot();
+
+        let bg = match self.ppu.fifo.bg.pop_front() {
+            Some(bg) => bg,
+            None => return false,
+        };
+        if self.ppu.fifo.discard > 0 {
+            self.ppu.fifo.discard -= 1;
+            return false;
+        }
+        let sprite = self.ppu.fifo.sprites.pop_front();
+        let color = self.fifo_color(bg, sprite);
+        let (x, y) = (self.ppu.fifo.x as usize, self.ly() as usize);
+        if y < GB_SCREEN_HEIGHT {
+            self.ppu.frame[y][x] = color;
+        }
+        self.ppu.fifo.x += 1;
+        self.ppu.fifo.x as usize == GB_SCREEN_WIDTH
+    }
+
+    /// Advances the background/window fetcher by a dot
+    fn fetcher_dot(&mut self) {
+        if self.ppu.fifo.step == FetchStep::Push {
+            if self.ppu.fifo.bg.is_empty() {
+                self.push_tile_row();
+            }
+            return;
+        }
+        self.ppu.fifo.step_dots += 1;
+        if self.ppu.fifo.step_dots < FETCH_STEP_DOTS {
+            return;
+        }
+        self.ppu.fifo.step_dots = 0;
+        match self.ppu.fifo.step {
+            FetchStep::Tile => {
+                self.fetch_tile();
+                self.ppu.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                self.ppu.fifo.data_low = self.fetch_tile_data(0);
+                self.ppu.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                self.ppu.fifo.data_high = self.fetch_tile_data(1);
+                let fifo = &mut self.ppu.fifo;
+                fifo.step = if fifo.first_fetch {
+                    fifo.first_fetch = false;
+                    FetchStep::Tile
+                } else {
+                    FetchStep::Push
+                };
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
+
+    /// Where in the tile map the fetcher is, and which line of the tile
+    fn fetcher_position(&self) -> (MemAddr, u8) {
+        let fifo = &self.ppu.fifo;
+        if fifo.window {
+            let map = if self.lcdc_tile_map() {
+                TILE_MAP_2_START
+            } else {
+                TILE_MAP_1_START
+            };
+            let row = self.ppu.window_counter >> 3;
+            let col = (fifo.fetch_x & 31) as u16;
+            (map + (row << 5) + col, (self.ppu.window_counter & 7) as u8)
+        } else {
+            let map = if self.lcdc_bg_tile_map() {
+                TILE_MAP_2_START
+            } else {
+                TILE_MAP_1_START
+            };
+            let y = self.ly().wrapping_add(self.scy());
+            let col = ((self.scx() >> 3).wrapping_add(fifo.fetch_x) & 31) as u16;
+            (map + ((y as u16 >> 3) << 5) + col, y & 7)
+        }
+    }
+
+    fn fetch_tile(&mut self) {
+        let (map_addr, _) = self.fetcher_position();
+        let map_index = (map_addr - 0x8000) as usize;
+        self.ppu.fifo.tile = self.mem.video_ram[0][map_index];
+        self.ppu.fifo.attributes = if self.gbc_mode {
+            self.mem.video_ram[1][map_index]
+        } else {
+            0
+        };
+    }
+
+    /// Byte `half` (0 low, 1 high) of the current tile's row
+    fn fetch_tile_data(&self, half: u16) -> u8 {
+        let (_, mut line) = self.fetcher_position();
+        let attributes = self.ppu.fifo.attributes;
+        if attributes & 0x40 != 0 {
+            line = 7 - line;
+        }
+        let bank = ((attributes >> 3) & 1) as usize;
+        let tile_start = self.get_nth_background_tile_idx(self.ppu.fifo.tile as u16);
+        self.mem.video_ram[bank][(tile_start + line as u16 * 2 + half) as usize]
+    }
+
+    fn push_tile_row(&mut self) {
+        let fifo = &mut self.ppu.fifo;
+        let x_flip = fifo.attributes & 0x20 != 0;
+        for px in 0..8 {
+            let bit = if x_flip { px } else { 7 - px };
+            fifo.bg.push_back(BgPixel {
+                color: (((fifo.data_high >> bit) & 1) << 1) | ((fifo.data_low >> bit) & 1),
+                palette: fifo.attributes & 0x7,
+                priority: fifo.attributes & 0x80 != 0,
+            });
+        }
+        fifo.fetch_x = fifo.fetch_x.wrapping_add(1);
+        fifo.step = FetchStep::Tile;
+    }
+
+    /// Reads a sprite's row and mixes it into the sprite FIFO
+    fn fetch_sprite(&mut self, i: usize) {
+        let sprite = self.ppu.fifo.line_sprites[i];
+        self.ppu.fifo.line_sprites[i].fetched = true;
+
+        let height = if self.lcdc_sprite_size() { 16 } else { 8 };
+        let mut line = self.ly().wrapping_add(16).wrapping_sub(sprite.y) & (height - 1);
+        if sprite.flags & 0x40 != 0 {
+            line = height - 1 - line;
+        }
+        let tile = if height == 16 {
+            sprite.tile & !1
+        } else {
+            sprite.tile
+        };
+        let bank = if self.gbc_mode {
+            ((sprite.flags >> 3) & 1) as usize
+        } else {
+            0
+        };
+        let row = tile as usize * 16 + line as usize * 2;
+        let data_low = self.mem.video_ram[bank][row];
+        let data_high = self.mem.video_ram[bank][row + 1];
+        let palette = if self.gbc_mode {
+            sprite.flags & 0x7
+        } else {
+            (sprite.flags >> 4) & 1
+        };
+        let x_flip = sprite.flags & 0x20 != 0;
+
+        // sprites left of the screen edge are cut off
+        let hidden = 8u8.saturating_sub(sprite.x);
+        let gbc_mode = self.gbc_mode;
+        let fifo = &mut self.ppu.fifo;
+        for (slot, px) in (hidden..8).enumerate() {
+            let bit = if x_flip { px } else { 7 - px };
+            let pixel = SpritePixel {
+                color: (((data_high >> bit) & 1) << 1) | ((data_low >> bit) & 1),
+                palette,
+                behind_bg: sprite.flags & 0x80 != 0,
+                oam_index: sprite.oam_index,
+            };
+            match fifo.sprites.get_mut(slot) {
+                // on CGB the lower OAM index wins, on DMG the sprite
+                // fetched first
+                Some(old)
+                    if old.color == 0
+                        || (gbc_mode && pixel.color != 0 && pixel.oam_index < old.oam_index) =>
+                {
+                    *old = pixel
+                }
+                Some(_) => (),
+                None => fifo.sprites.push_back(pixel),
+            }
+        }
+    }
+
+    /// The color of a pixel from the background and sprite FIFOs
+    fn fifo_color(&self, bg: BgPixel, sprite: Option<SpritePixel>) -> (u8, u8, u8) {
+        let sprite = sprite.filter(|sprite| sprite.color != 0 && self.lcdc_sprite_display());
+        if self.gbc_mode {
+            // LCDC bit 0 takes away the background's priority on CGB
+            let sprite = sprite.filter(|sprite| {
+                !(self.lcdc_bg_win_display() && bg.color != 0 && (bg.priority || sprite.behind_bg))
+            });
+            match sprite {
+                Some(sprite) => {
+                    self.sprite_color_palette_info(sprite.palette * 8 + sprite.color * 2)
+                }
+                None => self.background_color_palette_info(bg.palette * 8 + bg.color * 2),
+            }
+        } else {
+            let bg_color = if self.lcdc_bg_win_display() {
+                bg.color
+            } else {
+                0
+            };
+            let sprite = sprite.filter(|sprite| !(sprite.behind_bg && bg_color != 0));
+            let (shade, cgb_palette) = match sprite {
+                Some(sprite) => {
+                    let (c0, c1, c2, c3) = if sprite.palette == 0 {
+                        self.obp0()
+                    } else {
+                        self.obp1()
+                    };
+                    (
+                        [c0, c1, c2, c3][sprite.color as usize],
+                        Some(sprite.palette),
+                    )
+                }
+                // the background is blank while it's off
+                None if !self.lcdc_bg_win_display() => (0, None),
+                None => {
+                    let (c0, c1, c2, c3) = self.bgp();
+                    ([c0, c1, c2, c3][bg_color as usize], None)
+                }
+            };
+            if self.dmg_compat_palettes {
+                // the shades index the colors the CGB boot ROM picked
+                match cgb_palette {
+                    Some(palette) => self.sprite_color_palette_info(palette * 8 + shade * 2),
+                    None => self.background_color_palette_info(shade * 2),
+                }
+            } else {
+                TILE_PALETTE[shade as usize]
+            }
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! The LCD controller's mode machine
+//!
+//! Stepped from `Cpu::tick` one M-cycle at a time, so the modes in STAT,
+//! LY and the LCD interrupts change between the CPU's memory accesses.
+//! While LCDC bit 7 is clear the LCD is off: LY and the mode stay at 0 and
+//! the screen is blank until it is turned back on, which starts line 0
+//! over from its first dot.
+//! Lines are drawn either by the deferred renderer, all at once when HBlank
+//! starts, or by the pixel FIFO during mode 3.
+//!
+//! The LCDC STAT interrupt is requested by a single line: the mode 0, 1 and
+//! 2 and LY=LYC sources enabled in STAT ORed together.  Only the line going
+//! high requests the interrupt, so a source that becomes true while another
+//! one already holds the line up is "blocked" and requests nothing.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cpu::constants::STAT_ADDR;
+use crate::cpu::pixel_fifo::PixelFifo;
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+use crate::cpu::Cpu;
+use crate::io::constants::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH, TILE_PALETTE};
+use crate::io::deferred_renderer::deferred_renderer_draw_scanline;
+
+/// A full screen of RGB pixels
+pub type Frame = [[(u8, u8, u8); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT];
+
+/// Dots spent in mode 2 on every visible line
+pub const OAM_SCAN_DOTS: u32 = 80;
+/// Dots spent in mode 3 by the deferred renderer
+pub const DRAWING_DOTS: u32 = 168;
+/// Dots in a whole line, including HBlank
+pub const LINE_DOTS: u32 = 456;
+/// Lines in a frame, including the 10 lines of VBlank
+pub const LINES_PER_FRAME: u8 = 154;
+/// Dots into line 153 after which LY reads 0
+pub const LY_153_DOTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuMode {
+    /// Mode 2
+    OamScan,
+    /// Mode 3
+    Drawing,
+    /// Mode 0
+    HBlank,
+    /// Mode 1
+    VBlank,
+}
+
+/// How lines are drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderMode {
+    /// A line at a time from the registers at the end of mode 3; fast,
+    /// but misses changes made during the line
+    #[default]
+    Deferred,
+    /// A dot at a time with the pixel FIFO, with mode 3 taking as long as
+    /// it does on hardware
+    Fifo,
+}
+
+/// What `--renderer` accepts
+pub const RENDER_MODE_NAMES: [&str; 2] = ["deferred", "fifo"];
+
+impl fmt::Display for RenderMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RenderMode::Deferred => "deferred",
+            RenderMode::Fifo => "fifo",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RenderMode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "deferred" => Ok(RenderMode::Deferred),
+            "fifo" => Ok(RenderMode::Fifo),
+            _ => Err(format!(
+                "Unknown renderer {}, expected one of {}",
+                s,
+                RENDER_MODE_NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Ppu {
+    render_mode: RenderMode,
+    mode: PpuMode,
+    /// Dots since the start of the current line
+    line_dots: u32,
+    /// Lines of the window drawn so far this frame
+    pub(crate) window_counter: u16,
+    pub(crate) frame: Box<Frame>,
+    /// `frame` is complete and hasn't been taken yet
+    frame_ready: bool,
+    /// The STAT interrupt line, high while any enabled source is
+    stat_line: bool,
+    /// The first line after the LCD is turned on, which has no OAM scan
+    first_line: bool,
+    /// Dots since the LCD was turned off or last showed a blank frame
+    off_dots: u32,
+    pub(crate) fifo: PixelFifo,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new(RenderMode::default())
+    }
+}
+
+impl Ppu {
+    pub fn new(render_mode: RenderMode) -> Ppu {
+        Ppu {
+            render_mode,
+            mode: PpuMode::OamScan,
+            line_dots: 0,
+            window_counter: 0,
+            frame: Box::new([[(0, 0, 0); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT]),
+            frame_ready: false,
+            stat_line: false,
+            first_line: false,
+            off_dots: 0,
+            fifo: PixelFifo::new(),
+        }
+    }
+
+    pub fn mode(&self) -> PpuMode {
+        self.mode
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+}
+
+impl Cpu {
+    /// Draws lines with `render_mode` from now on
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.ppu.render_mode = render_mode;
+    }
+
+    /// Advances the LCD controller by `dots`
+    pub(crate) fn ppu_step(&mut self, dots: u32) {
+        for _ in 0..dots {
+            self.ppu_dot();
+        }
+    }
+
+    fn ppu_dot(&mut self) {
+        if !self.lcdc_on() {
+            // the screen stays blank, but frames still come at the same rate
+            self.ppu.off_dots += 1;
+            if self.ppu.off_dots >= LINE_DOTS * LINES_PER_FRAME as u32 {
+                self.ppu.off_dots = 0;
+                self.ppu.frame_ready = true;
+            }
+            return;
+        }
+        self.ppu.line_dots += 1;
+        match self.ppu.mode {
+            PpuMode::OamScan if self.ppu.line_dots >= OAM_SCAN_DOTS => self.start_drawing(),
+            // STAT reads mode 0 instead of 2 on the first line
+            PpuMode::HBlank if self.ppu.first_line && self.ppu.line_dots >= OAM_SCAN_DOTS => {
+                self.ppu.first_line = false;
+                self.start_drawing();
+            }
+            PpuMode::Drawing => {
+                let line_drawn = match self.ppu.render_mode {
+                    RenderMode::Deferred => self.ppu.line_dots >= OAM_SCAN_DOTS + DRAWING_DOTS,
+                    RenderMode::Fifo => self.fifo_dot(),
+                };
+                if line_drawn {
+                    self.start_hblank();
+                }
+            }
+            PpuMode::HBlank | PpuMode::VBlank if self.ppu.line_dots >= LINE_DOTS => {
+                self.ppu.line_dots -= LINE_DOTS;
+                self.next_line();
+            }
+            // LY only reads 153 for the first M-cycle of the last line, then
+            // 0 for the rest of it, and is compared with LYC as 0 already
+            PpuMode::VBlank
+                if self.ly() == LINES_PER_FRAME - 1 && self.ppu.line_dots == LY_153_DOTS =>
+            {
+                self.mem[0xFF44] = 0;
+                self.lyc_compare();
+            }
+            _ => (),
+        }
+    }
+
+    /// Starts mode 3 over after loading a state saved during it, as the
+    /// pixel FIFO it was in the middle of isn't saved
+    pub(crate) fn restart_drawing(&mut self) {
+        if self.ppu.mode == PpuMode::Drawing && self.ppu.render_mode == RenderMode::Fifo {
+            self.ppu.line_dots = OAM_SCAN_DOTS;
+            self.fifo_start_line();
+        }
+    }
+
+    /// Ends mode 2
+    fn start_drawing(&mut self) {
+        self.ppu.mode = PpuMode::Drawing;
+        self.set_oam_and_display_lock();
+        self.update_stat_line();
+        if self.ppu.render_mode == RenderMode::Fifo {
+            self.fifo_start_line();
+        }
+    }
+
+    /// Ends mode 3
+    fn start_hblank(&mut self) {
+        self.ppu.mode = PpuMode::HBlank;
+        self.set_hblank();
+        match self.ppu.render_mode {
+            RenderMode::Deferred => self.draw_line(),
+            RenderMode::Fifo => {
+                if self.ppu.fifo.drew_window() {
+                    self.ppu.window_counter += 1;
+                }
+            }
+        }
+        self.update_stat_line();
+    }
+
+    /// Draws the line in LY into the frame
+    fn draw_line(&mut self) {
+        let y = self.ly();
+        if y as usize >= GB_SCREEN_HEIGHT {
+            return;
+        }
+        let mut window_counter = self.ppu.window_counter;
+        let scanline = deferred_renderer_draw_scanline(y, self, &mut window_counter);
+        self.ppu.window_counter = window_counter;
+        self.ppu.frame[y as usize] = scanline;
+    }
+
+    /// Moves on to the next line at the end of HBlank or of a VBlank line
+    fn next_line(&mut self) {
+        if self.ppu.mode == PpuMode::VBlank && self.ly() == 0 {
+            // the end of line 153, where LY already went back to 0: the
+            // frame is done
+            self.ppu.frame_ready = true;
+            self.ppu.window_counter = 0;
+            self.ppu.mode = PpuMode::OamScan;
+            self.set_oam_lock();
+            self.lyc_compare();
+            return;
+        }
+        self.inc_ly();
+        let ly = self.ly();
+        if ly == GB_SCREEN_HEIGHT as u8 {
+            self.ppu.mode = PpuMode::VBlank;
+            self.set_vblank();
+            self.set_vblank_interrupt_bit();
+        } else if ly < GB_SCREEN_HEIGHT as u8 {
+            self.ppu.mode = PpuMode::OamScan;
+            self.set_oam_lock();
+        }
+        self.update_stat_line();
+    }
+
+    /// FF40 LCDC; turning bit 7 off stops the LCD and turning it on
+    /// starts it again from the top of the screen
+    pub(crate) fn write_lcdc(&mut self, value: u8) {
+        let was_on = self.lcdc_on();
+        self.mem[0xFF40] = value;
+        match (was_on, self.lcdc_on()) {
+            (true, false) => self.lcd_off(),
+            (false, true) => self.lcd_on(),
+            _ => (),
+        }
+    }
+
+    pub(crate) fn lcd_off(&mut self) {
+        self.ppu.mode = PpuMode::HBlank;
+        self.ppu.line_dots = 0;
+        self.ppu.off_dots = 0;
+        self.ppu.first_line = false;
+        self.ppu.window_counter = 0;
+        *self.ppu.frame = [[TILE_PALETTE[0]; GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT];
+        self.mem[0xFF44] = 0;
+        self.set_hblank();
+        self.lyc_compare();
+    }
+
+    fn lcd_on(&mut self) {
+        self.ppu.mode = PpuMode::HBlank;
+        self.ppu.line_dots = 0;
+        self.ppu.first_line = true;
+        self.set_hblank();
+        self.lyc_compare();
+    }
+
+    /// Whether any source enabled in `stat` holds the STAT interrupt line up
+    fn stat_sources(&self, stat: u8) -> bool {
+        let mode_source = match self.ppu.mode {
+            PpuMode::HBlank => stat & 0x08 != 0,
+            PpuMode::VBlank => stat & 0x10 != 0,
+            PpuMode::OamScan => stat & 0x20 != 0,
+            PpuMode::Drawing => false,
+        };
+        let lyc_source = stat & 0x40 != 0 && stat & 0x04 != 0;
+        self.lcdc_on() && (mode_source || lyc_source)
+    }
+
+    /// Recomputes the STAT interrupt line after a mode, LY, LYC or STAT
+    /// change, requesting the interrupt if it went high
+    pub(crate) fn update_stat_line(&mut self) {
+        let stat = self.mem[STAT_ADDR];
+        self.set_stat_line(self.stat_sources(stat));
+    }
+
+    fn set_stat_line(&mut self, line: bool) {
+        if line && !self.ppu.stat_line {
+            self.set_lcdc_interrupt_bit();
+        }
+        self.ppu.stat_line = line;
+    }
+
+    /// FF41 STAT; the mode and coincidence bits can't be written
+    pub(crate) fn write_stat(&mut self, value: u8) {
+        if !self.model().is_cgb() {
+            // the DMG briefly sees every source enabled while STAT is
+            // written, so the write itself can request the interrupt
+            let stat = self.mem[STAT_ADDR] | 0x78;
+            self.set_stat_line(self.stat_sources(stat));
+        }
+        self.mem[STAT_ADDR] = 0x80 | (value & 0x78) | (self.mem[STAT_ADDR] & 0x07);
+        self.update_stat_line();
+    }
+
+    /// The last finished frame, once per frame
+    pub fn take_frame(&mut self) -> Option<Frame> {
+        if self.ppu.frame_ready {
+            self.ppu.frame_ready = false;
+            Some(*self.ppu.frame)
+        } else {
+            None
+        }
+    }
+}
+
+impl SaveState for Ppu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(match self.mode {
+            PpuMode::HBlank => 0,
+            PpuMode::VBlank => 1,
+            PpuMode::OamScan => 2,
+            PpuMode::Drawing => 3,
+        });
+        writer.write_u32(self.line_dots);
+        writer.write_u16(self.window_counter);
+        writer.write_bool(self.stat_line);
+        writer.write_bool(self.first_line);
+        writer.write_u32(self.off_dots);
+        self.fifo.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.mode = match reader.read_u8()? {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            3 => PpuMode::Drawing,
+            otherwise => return Err(format!("Invalid PPU mode {} in save-state", otherwise)),
+        };
+        self.line_dots = reader.read_u32()?;
+        if self.line_dots >= LINE_DOTS {
+            return Err(format!(
+                "Invalid PPU position {} in save-state",
+                self.line_dots
+            ));
+        }
+        self.window_counter = reader.read_u16()?;
+        self.stat_line = reader.read_bool()?;
+        self.first_line = reader.read_bool()?;
+        self.off_dots = reader.read_u32()?;
+        if self.off_dots >= LINE_DOTS * LINES_PER_FRAME as u32 {
+            return Err(format!(
+                "Invalid blank LCD position {} in save-state",
+                self.off_dots
+            ));
+        }
+        self.fifo.load_state(reader)?;
+        self.frame_ready = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+//! Binary save-state format
+//!
+//! A save-state is a small header followed by the state of every
+//! component, written in a fixed order.  All multi-byte values are
+//! little-endian.
+//!
+//! The header contains a magic number, the format version and enough of
+//! the ROM header to tell which game the state belongs to.  Any change to
+//! what a component writes must bump `SAVE_STATE_VERSION` so that older
+//! states are rejected instead of being loaded into the wrong fields.
+
+/// Identifies a file as a rusty-boy save-state
+pub const SAVE_STATE_MAGIC: [u8; 4] = *b"RBSS";
+/// Bump this whenever the layout of any component's state changes
+pub const SAVE_STATE_VERSION: u16 = 11;
+/// The number of save-state slots available per ROM
+pub const SAVE_STATE_SLOTS: u8 = 10;
+
+/// Something whose state can be written to and restored from a save-state
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String>;
+}
+
+/// Accumulates the bytes of a save-state
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: vec![] }
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Writes a fixed-size block; the reader must know the length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Writes a length-prefixed block of bytes
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads the bytes of a save-state back, failing cleanly on truncated data
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(format!(
+                "Save-state is truncated: needed {} bytes at offset {}",
+                len, self.pos
+            ));
+        }
+        let out = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(out)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let b = self.take(8)?;
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(b);
+        Ok(u64::from_le_bytes(arr))
+    }
+
+    /// Fills `out` with exactly `out.len()` bytes
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), String> {
+        let b = self.take(out.len())?;
+        out.copy_from_slice(b);
+        Ok(())
+    }
+
+    /// Reads a block written with `StateWriter::write_vec`
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Whether every byte of the state has been consumed
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/// Writes the save-state header
+pub fn write_header(writer: &mut StateWriter, game_name: &str, global_checksum: u16) {
+    writer.write_bytes(&SAVE_STATE_MAGIC);
+    writer.write_u16(SAVE_STATE_VERSION);
+    writer.write_u16(global_checksum);
+    writer.write_vec(game_name.as_bytes());
+}
+
+/// Checks the save-state header, rejecting states from other versions or other games
+pub fn check_header(
+    reader: &mut StateReader,
+    game_name: &str,
+    global_checksum: u16,
+) -> Result<(), String> {
+    let mut magic = [0u8; 4];
+    reader.read_bytes(&mut magic)?;
+    if magic != SAVE_STATE_MAGIC {
+        return Err("Not a rusty-boy save-state".to_string());
+    }
+    let version = reader.read_u16()?;
+    if version != SAVE_STATE_VERSION {
+        return Err(format!(
+            "Save-state version {} is not supported (expected version {})",
+            version, SAVE_STATE_VERSION
+        ));
+    }
+    let checksum = reader.read_u16()?;
+    let name = reader.read_vec()?;
+    if checksum != global_checksum || name != game_name.as_bytes() {
+        return Err(format!(
+            "Save-state was made with a different ROM ({})",
+            String::from_utf8_lossy(&name)
+        ));
+    }
+    Ok(())
+}
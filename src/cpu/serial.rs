@@ -0,0 +1,359 @@
+//! Serial port (link cable) logic
+//!
+//! A transfer is started by writing to SC (0xFF02) with bit 7 set.  With
+//! the internal clock (SC bit 0 set) this Game Boy drives the clock and
+//! the byte in SB (0xFF01) is shifted out over 8 clocks; with the external
+//! clock the transfer only completes once the other side clocks it.
+//!
+//! Bytes are exchanged a whole byte at a time through a `SerialEndpoint`,
+//! which is whatever is plugged into the link port.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+/// CPU cycles per bit with the normal internal clock (8192Hz)
+pub const SERIAL_CYCLES_PER_BIT: u32 = 512;
+/// CPU cycles per bit with the CGB fast internal clock (262144Hz)
+pub const SERIAL_FAST_CYCLES_PER_BIT: u32 = 16;
+
+/// Whatever is on the other end of the link cable
+pub trait SerialEndpoint {
+    /// This Game Boy clocked out `outgoing`; returns the byte shifted in
+    /// from the other side at the same time.
+    fn exchange(&mut self, outgoing: u8) -> u8;
+
+    /// This Game Boy is waiting on the external clock with `outgoing` in
+    /// SB.  Returns the incoming byte if the other side has clocked a
+    /// transfer, in which case `outgoing` was sent to it.
+    fn poll_external(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+
+    /// The bytes sent through this endpoint, if it keeps them
+    fn captured(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Nothing is plugged in: every bit read is high
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DisconnectedEndpoint;
+
+impl SerialEndpoint for DisconnectedEndpoint {
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Records every byte sent, as used by test ROMs to report their results
+#[derive(Debug, Default, Clone)]
+pub struct CaptureEndpoint {
+    pub buffer: Vec<u8>,
+}
+
+impl CaptureEndpoint {
+    pub fn new() -> CaptureEndpoint {
+        CaptureEndpoint { buffer: vec![] }
+    }
+}
+
+impl SerialEndpoint for CaptureEndpoint {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.buffer.push(outgoing);
+        0xFF
+    }
+
+    fn captured(&self) -> Option<&[u8]> {
+        Some(&self.buffer)
+    }
+}
+
+/// A link cable to another emulator over TCP
+///
+/// Each transfer is one byte in each direction: the side driving the
+/// clock sends its byte and waits for the reply, the other side answers
+/// with its SB as soon as it has a transfer waiting on the external clock.
+///
+/// Bytes travel in two-byte frames: a tag, saying whether the frame is a
+/// request, a reply or a cancelled request and which transfer it belongs
+/// to, then the byte.  A clocking side that gives up waiting cancels its
+/// request, so neither side ever takes a late byte for a newer transfer.
+#[derive(Debug)]
+pub struct TcpEndpoint {
+    stream: TcpStream,
+    /// Bytes received that don't make a whole frame yet
+    inbox: Vec<u8>,
+    /// Sequence number of the last request sent
+    sequence: u8,
+    /// The newest request from the other side not yet answered: its
+    /// sequence number and byte
+    pending_request: Option<(u8, u8)>,
+}
+
+/// How long the clocking side waits for the other Game Boy to answer
+const TCP_EXCHANGE_TIMEOUT: Duration = Duration::from_millis(500);
+
+const FRAME_REQUEST: u8 = 0x00;
+const FRAME_REPLY: u8 = 0x40;
+const FRAME_CANCEL: u8 = 0x80;
+const FRAME_KIND_MASK: u8 = 0xC0;
+const FRAME_SEQUENCE_MASK: u8 = 0x3F;
+
+impl TcpEndpoint {
+    /// Waits for another instance to connect to `addr`
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> Result<TcpEndpoint, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("Could not listen for link: {}", e))?;
+        info!("Waiting for link cable connection");
+        let (stream, peer) = listener
+            .accept()
+            .map_err(|e| format!("Could not accept link: {}", e))?;
+        info!("Link cable connected to {}", peer);
+        TcpEndpoint::from_stream(stream)
+    }
+
+    /// Connects to another instance listening on `addr`
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpEndpoint, String> {
+        let stream =
+            TcpStream::connect(addr).map_err(|e| format!("Could not connect link: {}", e))?;
+        info!("Link cable connected");
+        TcpEndpoint::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<TcpEndpoint, String> {
+        stream
+            .set_nodelay(true)
+            .and_then(|()| stream.set_nonblocking(true))
+            .map_err(|e| format!("Could not configure link: {}", e))?;
+        Ok(TcpEndpoint {
+            stream,
+            inbox: vec![],
+            sequence: 0,
+            pending_request: None,
+        })
+    }
+
+    fn send(&mut self, tag: u8, byte: u8) -> bool {
+        if let Err(e) = self
+            .stream
+            .set_nonblocking(false)
+            .and_then(|()| self.stream.write_all(&[tag, byte]))
+            .and_then(|()| self.stream.set_nonblocking(true))
+        {
+            error!("Link cable write failed: {}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Reads what has arrived into the inbox, waiting up to `timeout` for
+    /// something if given; returns false once nothing more will come
+    fn receive(&mut self, timeout: Option<Duration>) -> bool {
+        let mut buf = [0u8; 64];
+        let result = match timeout {
+            Some(timeout) => self
+                .stream
+                .set_nonblocking(false)
+                .and_then(|()| self.stream.set_read_timeout(Some(timeout)))
+                .and_then(|()| self.stream.read(&mut buf)),
+            None => self.stream.read(&mut buf),
+        };
+        let _ = self.stream.set_nonblocking(true);
+        match result {
+            Ok(0) => {
+                error!("Link cable disconnected");
+                false
+            }
+            Ok(n) => {
+                self.inbox.extend_from_slice(&buf[..n]);
+                true
+            }
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                true
+            }
+            Err(e) => {
+                error!("Link cable read failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Handles the whole frames in the inbox; returns the byte of the
+    /// reply to `awaited`, if it is among them
+    fn process_frames(&mut self, awaited: Option<u8>) -> Option<u8> {
+        let mut reply = None;
+        let frames = self.inbox.len() / 2 * 2;
+        for frame in self.inbox.drain(..frames).collect::<Vec<u8>>().chunks(2) {
+            let (tag, byte) = (frame[0], frame[1]);
+            let sequence = tag & FRAME_SEQUENCE_MASK;
+            match tag & FRAME_KIND_MASK {
+                // an older request the other side still waits on is stale
+                FRAME_REQUEST => self.pending_request = Some((sequence, byte)),
+                FRAME_CANCEL => {
+                    if self.pending_request.map(|(s, _)| s) == Some(sequence) {
+                        self.pending_request = None;
+                    }
+                }
+                FRAME_REPLY if awaited == Some(sequence) => reply = Some(byte),
+                _ => debug!("Dropping stale link cable frame {:02X} {:02X}", tag, byte),
+            }
+        }
+        reply
+    }
+}
+
+impl SerialEndpoint for TcpEndpoint {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.sequence = (self.sequence + 1) & FRAME_SEQUENCE_MASK;
+        let sequence = self.sequence;
+        if !self.send(FRAME_REQUEST | sequence, outgoing) {
+            return 0xFF;
+        }
+        let deadline = std::time::Instant::now() + TCP_EXCHANGE_TIMEOUT;
+        loop {
+            if let Some(incoming) = self.process_frames(Some(sequence)) {
+                return incoming;
+            }
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() || !self.receive(Some(remaining)) {
+                break;
+            }
+        }
+        debug!("No reply over link cable");
+        self.send(FRAME_CANCEL | sequence, 0);
+        0xFF
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        self.receive(None);
+        self.process_frames(None);
+        let (sequence, incoming) = self.pending_request.take()?;
+        self.send(FRAME_REPLY | sequence, outgoing);
+        Some(incoming)
+    }
+}
+
+/// The serial transfer state
+pub struct Serial {
+    /// A transfer has been started and not finished
+    transfer_active: bool,
+    /// The transfer is clocked by this Game Boy
+    internal_clock: bool,
+    /// Cycles until the transfer finishes (internal clock) or until the
+    /// endpoint is next polled (external clock)
+    cycles_remaining: u32,
+    endpoint: Box<dyn SerialEndpoint>,
+}
+
+/// Copies get a disconnected cable: there is only one real link port
+impl Clone for Serial {
+    fn clone(&self) -> Serial {
+        Serial {
+            transfer_active: self.transfer_active,
+            internal_clock: self.internal_clock,
+            cycles_remaining: self.cycles_remaining,
+            endpoint: Box::new(DisconnectedEndpoint),
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            transfer_active: false,
+            internal_clock: false,
+            cycles_remaining: 0,
+            endpoint: Box::new(DisconnectedEndpoint),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.transfer_active = false;
+        self.internal_clock = false;
+        self.cycles_remaining = 0;
+    }
+
+    pub fn set_endpoint(&mut self, endpoint: Box<dyn SerialEndpoint>) {
+        self.endpoint = endpoint;
+    }
+
+    /// Takes the endpoint out, leaving the cable disconnected
+    pub fn take_endpoint(&mut self) -> Box<dyn SerialEndpoint> {
+        std::mem::replace(&mut self.endpoint, Box::new(DisconnectedEndpoint))
+    }
+
+    pub fn endpoint(&self) -> &dyn SerialEndpoint {
+        self.endpoint.as_ref()
+    }
+
+    /// Called on writes to SC (0xFF02)
+    pub fn write_control(&mut self, value: u8, gbc_mode: bool) {
+        self.transfer_active = value & 0x80 == 0x80;
+        self.internal_clock = value & 0x01 == 0x01;
+        let cycles_per_bit = if gbc_mode && value & 0x02 == 0x02 {
+            SERIAL_FAST_CYCLES_PER_BIT
+        } else {
+            SERIAL_CYCLES_PER_BIT
+        };
+        self.cycles_remaining = if self.internal_clock {
+            cycles_per_bit * 8
+        } else {
+            SERIAL_CYCLES_PER_BIT
+        };
+    }
+
+    /// Advances the transfer by `cycles` CPU cycles.  Returns the received
+    /// byte when a transfer with outgoing byte `sb` finishes; the caller
+    /// updates SB and SC and requests the serial interrupt.
+    pub fn step(&mut self, cycles: u32, sb: u8) -> Option<u8> {
+        if !self.transfer_active {
+            return None;
+        }
+        if cycles < self.cycles_remaining {
+            self.cycles_remaining -= cycles;
+            return None;
+        }
+        if self.internal_clock {
+            self.transfer_active = false;
+            self.cycles_remaining = 0;
+            Some(self.endpoint.exchange(sb))
+        } else {
+            // poll the other side about once per bit time
+            self.cycles_remaining = SERIAL_CYCLES_PER_BIT;
+            let incoming = self.endpoint.poll_external(sb);
+            if incoming.is_some() {
+                self.transfer_active = false;
+            }
+            incoming
+        }
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.transfer_active);
+        writer.write_bool(self.internal_clock);
+        writer.write_u32(self.cycles_remaining);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.transfer_active = reader.read_bool()?;
+        self.internal_clock = reader.read_bool()?;
+        self.cycles_remaining = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -628,3 +628,1531 @@ fn segfault() {
     //let mem = Memory::new();
     //let cpu = Cpu::new();
 }
+
+#[test]
+fn save_state_round_trip() {
+    let mut cpu = Cpu::new();
+    cpu.a = 0x12;
+    cpu.sp = 0xC0DE;
+    cpu.pc = 0x0150;
+    cpu.set_mem(0xC123, 0x45);
+    cpu.set_mem(0xFF80, 0x67);
+    let state = cpu.save_state_bytes();
+
+    let mut restored = Cpu::new();
+    restored.load_state_bytes(&state).unwrap();
+    assert_eq!(restored.a, 0x12);
+    assert_eq!(restored.sp, 0xC0DE);
+    assert_eq!(restored.pc, 0x0150);
+    assert_eq!(restored.get_mem(0xC123), 0x45);
+    assert_eq!(restored.get_mem(0xFF80), 0x67);
+    assert_eq!(restored.save_state_bytes(), state);
+}
+
+#[test]
+fn save_state_rejects_bad_data() {
+    let mut cpu = Cpu::new();
+    cpu.a = 0x12;
+    let mut state = cpu.save_state_bytes();
+
+    // truncated states leave the CPU untouched
+    let mut other = Cpu::new();
+    other.a = 0x34;
+    assert!(other.load_state_bytes(&state[..state.len() - 1]).is_err());
+    assert_eq!(other.a, 0x34);
+
+    // so do states from another version of the format
+    state[4] = state[4].wrapping_add(1);
+    assert!(other.load_state_bytes(&state).is_err());
+    assert_eq!(other.a, 0x34);
+}
+
+#[test]
+fn rewind_buffer_returns_snapshots_newest_first() {
+    use crate::io::rewind::RewindBuffer;
+
+    let mut cpu = Cpu::new();
+    let mut rewind = RewindBuffer::new();
+    let mut states = vec![];
+    for i in 0..5u8 {
+        cpu.a = i;
+        cpu.set_mem(0xC000 + i as u16, i);
+        let state = cpu.save_state_bytes();
+        rewind.push(state.clone());
+        states.push(state);
+    }
+    assert_eq!(rewind.len(), 5);
+
+    while let Some(expected) = states.pop() {
+        assert_eq!(rewind.pop().unwrap(), expected);
+    }
+    assert!(rewind.is_empty());
+    assert_eq!(rewind.total_bytes(), 0);
+}
+
+#[test]
+fn rewind_buffer_keeps_snapshots_from_before_a_size_change() {
+    use crate::io::rewind::RewindBuffer;
+
+    let mut rewind = RewindBuffer::new();
+    let states = [vec![1u8; 10], vec![2u8; 20], vec![3u8; 20]];
+    for state in &states {
+        rewind.push(state.clone());
+    }
+    for expected in states.iter().rev() {
+        assert_eq!(rewind.pop().as_ref(), Some(expected));
+    }
+    assert!(rewind.is_empty());
+    assert_eq!(rewind.total_bytes(), 0);
+}
+
+/// A 32KB ROM-only cartridge with `code` at the entry point
+fn test_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    rom
+}
+
+#[test]
+fn headless_run_stops_on_serial_output() {
+    use crate::io::headless::*;
+
+    let rom = test_rom(&[
+        0x3E, b'O', // LD A, 'O'
+        0xE0, 0x01, // LDH (SB), A
+        0x3E, 0x81, // LD A, 0x81
+        0xE0, 0x02, // LDH (SC), A
+        0x18, 0xFE, // JR -2
+    ]);
+    let mut appstate = new_headless_state().unwrap();
+    appstate.gameboy.load_rom(rom);
+
+    let condition = StopCondition::SerialContains("O".to_string());
+    let outcome = run_headless(&mut appstate, 10, Some(&condition));
+    assert!(outcome.condition_met);
+    assert_eq!(outcome.frames_run, 1);
+    assert_eq!(appstate.gameboy.serial_output(), b"O");
+
+    let outcome = run_headless(&mut appstate, 2, Some(&StopCondition::MooneyeRegisters));
+    assert!(!outcome.condition_met);
+    assert_eq!(outcome.frames_run, 2);
+}
+
+/// Runs every `.gb`/`.gbc` file under `$RUSTY_BOY_TEST_ROMS` (default
+/// `test-roms/`) headlessly and prints a results table.
+///
+/// Blargg ROMs are judged by their serial output and Mooneye ROMs by the
+/// registers they set before `LD B, B`.  ROMs listed (one relative path
+/// per line) in `known-failures.txt` in that directory may fail without
+/// failing the test; anything else that doesn't pass is a regression.
+/// The suites aren't distributed with the emulator, so this does nothing
+/// if the directory doesn't exist.
+#[test]
+fn test_rom_suites() {
+    use crate::io::headless::*;
+    use std::path::{Path, PathBuf};
+
+    fn find_roms(dir: &Path, out: &mut Vec<PathBuf>) {
+        let mut entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(_) => vec![],
+        };
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                find_roms(&path, out);
+            } else if matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("gb") | Some("gbc")
+            ) {
+                out.push(path);
+            }
+        }
+    }
+
+    let rom_dir = std::env::var_os("RUSTY_BOY_TEST_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("test-roms"));
+    if !rom_dir.is_dir() {
+        println!("No test ROMs found at {:?}, skipping", rom_dir);
+        return;
+    }
+    let max_frames = std::env::var("RUSTY_BOY_TEST_ROM_FRAMES")
+        .ok()
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(60 * 60);
+    let known_failures = std::fs::read_to_string(rom_dir.join("known-failures.txt"))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect::<Vec<String>>();
+
+    let mut roms = vec![];
+    find_roms(&rom_dir, &mut roms);
+
+    let mut regressions = vec![];
+    println!("{:<60} {:>8} result", "ROM", "frames");
+    for rom_path in roms {
+        let name = rom_path
+            .strip_prefix(&rom_dir)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        let rom = std::fs::read(&rom_path).unwrap();
+
+        let run = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut appstate = new_headless_state().unwrap();
+            appstate.gameboy.load_rom(rom);
+            let outcome = run_headless(
+                &mut appstate,
+                max_frames,
+                Some(&StopCondition::TestRomFinished),
+            );
+            (outcome.frames_run, test_rom_result(&appstate.gameboy))
+        }));
+        let (frames, result) = match run {
+            Ok((frames, Some(TestRomResult::Passed))) => (frames, "passed"),
+            Ok((frames, Some(TestRomResult::Failed))) => (frames, "FAILED"),
+            Ok((frames, None)) => (frames, "TIMED OUT"),
+            Err(_) => (0, "PANICKED"),
+        };
+        let known = known_failures.contains(&name);
+        println!(
+            "{:<60} {:>8} {}{}",
+            name,
+            frames,
+            result,
+            if known { " (known)" } else { "" }
+        );
+        if result != "passed" && !known {
+            regressions.push(name);
+        }
+    }
+
+    assert!(
+        regressions.is_empty(),
+        "test ROMs not passing: {:?}",
+        regressions
+    );
+}
+
+#[test]
+fn serial_transfer_with_internal_clock() {
+    use crate::cpu::serial::CaptureEndpoint;
+
+    let mut cpu = Cpu::new();
+    cpu.serial.set_endpoint(Box::new(CaptureEndpoint::new()));
+    cpu.unset_serial_io_interrupt_bit();
+    cpu.set_mem(0xFF01, 0x42);
+    cpu.set_mem(0xFF02, 0x81);
+
+    // 8 bits at 8192Hz
+    cpu.serial_step(4095);
+    assert!(!cpu.get_serial_io_interrupt_bit());
+    assert_eq!(cpu.get_mem(0xFF02) & 0x80, 0x80);
+    cpu.serial_step(1);
+    assert!(cpu.get_serial_io_interrupt_bit());
+    assert_eq!(cpu.get_mem(0xFF02) & 0x80, 0);
+    assert_eq!(cpu.get_mem(0xFF01), 0xFF);
+    assert_eq!(cpu.serial_output(), &[0x42]);
+
+    // with the external clock and nothing connected the transfer never ends
+    cpu.unset_serial_io_interrupt_bit();
+    cpu.set_mem(0xFF02, 0x80);
+    cpu.serial_step(100_000);
+    assert!(!cpu.get_serial_io_interrupt_bit());
+}
+
+#[test]
+fn printer_prints_packets_to_png() {
+    use crate::cpu::serial::SerialEndpoint;
+    use crate::io::printer::Printer;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> u8 {
+        let mut packet = vec![0x88, 0x33, command, compressed as u8];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |acc, b| acc.wrapping_add(*b as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        for byte in packet {
+            assert_eq!(printer.exchange(byte), 0);
+        }
+        assert_eq!(printer.exchange(0), 0x81);
+        printer.exchange(0)
+    }
+
+    let dir = std::env::temp_dir().join(format!("rusty-boy-printer-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut printer = Printer::new(dir.clone());
+
+    assert_eq!(send_packet(&mut printer, 0x1, false, &[]), 0);
+    // one row of 20 solid tiles: a run of 320 0xFF bytes
+    let status = send_packet(
+        &mut printer,
+        0x4,
+        true,
+        &[0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xFF],
+    );
+    assert_eq!(status, 0x08);
+    // no feed before, 1 after, identity palette
+    assert_eq!(
+        send_packet(&mut printer, 0x2, false, &[1, 0x01, 0xE4, 0x40]),
+        0x02
+    );
+
+    let png = std::fs::read(dir.join("printout-0000.png")).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(&png[1..4], b"PNG");
+    // IHDR: 160 wide, 8 rows of tiles plus 8 rows of margin
+    assert_eq!(&png[16..24], &[0, 0, 0, 160, 0, 0, 0, 16]);
+}
+
+#[test]
+fn mbc3_rtc_latches_and_persists() {
+    use crate::io::constants::CPU_CYCLES_PER_SECOND;
+
+    let mut rom = test_rom(&[0x18, 0xFE]);
+    rom[0x147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+    rom[0x149] = 0x02; // one 8KB RAM bank
+    rom[0x134..0x13B].copy_from_slice(b"RTCTEST");
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom.clone());
+
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0xA000, 0x5A);
+    // 59 seconds past 23:59 on day 511
+    for (reg, value) in [(0x08, 59), (0x09, 59), (0x0A, 23), (0x0B, 0xFF), (0x0C, 1)] {
+        cpu.set_mem(0x4000, reg);
+        cpu.set_mem(0xA000, value);
+    }
+    cpu.cartridge_step(CPU_CYCLES_PER_SECOND as u32, CPU_CYCLES_PER_SECOND);
+
+    // reads return the old values until the clock is latched
+    cpu.set_mem(0x4000, 0x08);
+    assert_eq!(cpu.get_mem(0xA000), 59);
+    cpu.set_mem(0x6000, 0);
+    cpu.set_mem(0x6000, 1);
+    assert_eq!(cpu.get_mem(0xA000), 0);
+    cpu.set_mem(0x4000, 0x0C);
+    assert_eq!(cpu.get_mem(0xA000), 0x80);
+    cpu.set_mem(0x4000, 0x00);
+    assert_eq!(cpu.get_mem(0xA000), 0x5A);
+
+    let dir = std::env::temp_dir().join(format!("rusty-boy-rtc-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = cpu.save_file_path(dir.clone());
+    cpu.save_ram(&path);
+    assert_eq!(std::fs::read(&path).unwrap().len(), 0x2000 + 48);
+
+    let mut restored = Cpu::new();
+    restored.load_rom(rom);
+    restored.load_saved_ram(&path);
+    let _ = std::fs::remove_dir_all(&dir);
+    restored.set_mem(0x0000, 0x0A);
+    assert_eq!(restored.get_mem(0xA000), 0x5A);
+    restored.set_mem(0x4000, 0x0C);
+    assert_eq!(restored.get_mem(0xA000), 0x80);
+}
+
+#[test]
+fn mbc2_nibble_ram_and_register_select() {
+    let mut rom = test_rom(&[]);
+    rom.resize(0x4000 * 4, 0);
+    rom[0x147] = 0x06; // MBC2+BATTERY
+    rom[0x4000 * 3] = 0x33;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+
+    // address bit 8 clear: RAM enable, set: ROM bank
+    cpu.set_mem(0x0100, 0x0A);
+    assert_eq!(cpu.get_mem(0xA000), 0xFF);
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0x2100, 0x03);
+    assert_eq!(cpu.get_mem(0x4000), 0x33);
+
+    // only the low nibble is stored and the 512 cells repeat
+    cpu.set_mem(0xA001, 0x12);
+    assert_eq!(cpu.get_mem(0xA001), 0xF2);
+    assert_eq!(cpu.get_mem(0xA201), 0xF2);
+    assert_eq!(cpu.get_mem(0xBE01), 0xF2);
+}
+
+#[test]
+fn multicart_mappers_select_games() {
+    // MBC1M: four 16-bank games; mode 1 maps a game's bank 0 at 0x0000
+    let mut rom = test_rom(&[]);
+    rom.resize(0x4000 * 64, 0);
+    rom[0x147] = 0x01;
+    rom[0x148] = 0x05;
+    rom[0x4000 * 0x10] = 0x10;
+    rom[0x4000 * 0x12] = 0x12;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+    cpu.set_mem(0x6000, 0x01);
+    cpu.set_mem(0x4000, 0x01);
+    cpu.set_mem(0x2000, 0x02);
+    assert_eq!(cpu.get_mem(0x0000), 0x10);
+    assert_eq!(cpu.get_mem(0x4000), 0x12);
+
+    // MMM01: boots the menu in the last 32KB, which then locks in a game
+    let mut rom = test_rom(&[]);
+    rom.resize(0x4000 * 64, 0);
+    let menu = 0x4000 * 62;
+    rom[menu + 0x147] = 0x0B;
+    rom[menu] = 62;
+    rom[menu + 0x4000] = 63;
+    rom[0x4000 * 32] = 32;
+    rom[0x4000 * 33] = 33;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+    assert_eq!(cpu.get_mem(0x0000), 62);
+    assert_eq!(cpu.get_mem(0x4000), 63);
+    // game at bank 32, two banks big: only bank bit 0 stays writable
+    cpu.set_mem(0x2000, 0x20);
+    cpu.set_mem(0x6000, 0x3C);
+    cpu.set_mem(0x0000, 0x40);
+    assert_eq!(cpu.get_mem(0x0000), 32);
+    assert_eq!(cpu.get_mem(0x4000), 33);
+    cpu.set_mem(0x2000, 0x00);
+    cpu.set_mem(0x4000, 0x30);
+    assert_eq!(cpu.get_mem(0x0000), 32);
+    assert_eq!(cpu.get_mem(0x4000), 33);
+}
+
+#[test]
+fn huc3_rtc_commands() {
+    use crate::io::constants::CPU_CYCLES_PER_SECOND;
+
+    let mut rom = test_rom(&[]);
+    rom[0x147] = 0xFE;
+    rom[0x149] = 0x02;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0xA000, 0x42);
+    assert_eq!(cpu.get_mem(0xA000), 0x42);
+
+    // set the minutes to 0x59 nibble by nibble, then let a minute pass
+    cpu.set_mem(0x0000, 0x0B);
+    for command in [0x40, 0x50, 0x39, 0x35] {
+        cpu.set_mem(0xA000, command);
+    }
+    cpu.cartridge_step(60 * CPU_CYCLES_PER_SECOND as u32, CPU_CYCLES_PER_SECOND);
+    for command in [0x40, 0x10, 0x10] {
+        cpu.set_mem(0x0000, 0x0B);
+        cpu.set_mem(0xA000, command);
+    }
+    cpu.set_mem(0x0000, 0x0C);
+    assert_eq!(cpu.get_mem(0xA000), 0x5);
+    cpu.set_mem(0x0000, 0x0D);
+    assert_eq!(cpu.get_mem(0xA000), 1);
+}
+
+#[test]
+fn mbc5_rumble_bit_drives_motor() {
+    let mut rom = test_rom(&[]);
+    rom[0x147] = 0x1E; // MBC5+RUMBLE+RAM+BATTERY
+    rom[0x149] = 0x02;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0xA000, 0x42);
+    cpu.set_mem(0x4000, 0x08);
+    assert!(cpu.rumble_active());
+    // the motor bit doesn't switch away from RAM bank 0
+    assert_eq!(cpu.get_mem(0xA000), 0x42);
+    cpu.set_mem(0x4000, 0x00);
+    assert!(!cpu.rumble_active());
+}
+
+#[test]
+fn mbc7_eeprom_and_accelerometer() {
+    fn eeprom_bits(cpu: &mut Cpu, bits: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        for &bit in bits {
+            cpu.set_mem(0xA080, 0x80 | (bit << 1));
+            cpu.set_mem(0xA080, 0xC0 | (bit << 1));
+            out.push(cpu.get_mem(0xA080) & 1);
+        }
+        out
+    }
+    fn to_bits(value: u16, count: u8) -> Vec<u8> {
+        (0..count).rev().map(|i| ((value >> i) & 1) as u8).collect()
+    }
+    /// start bit, opcode and address
+    fn command(opcode: u16, address: u16) -> Vec<u8> {
+        to_bits((1 << 10) | (opcode << 8) | address, 11)
+    }
+
+    let mut rom = test_rom(&[]);
+    rom[0x147] = 0x22;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0x4000, 0x40);
+
+    cpu.set_tilt(1.0, -0.5);
+    cpu.set_mem(0xA000, 0x55);
+    cpu.set_mem(0xA010, 0xAA);
+    let x = u16::from_le_bytes([cpu.get_mem(0xA020), cpu.get_mem(0xA030)]);
+    let y = u16::from_le_bytes([cpu.get_mem(0xA040), cpu.get_mem(0xA050)]);
+    assert_eq!(x, 0x81D0 - 0x70);
+    assert_eq!(y, 0x81D0 - 0x38);
+
+    // enable writes, write 0xBEEF to word 5, read it back
+    eeprom_bits(&mut cpu, &command(0b00, 0xC0));
+    cpu.set_mem(0xA080, 0x00);
+    eeprom_bits(&mut cpu, &command(0b01, 5));
+    eeprom_bits(&mut cpu, &to_bits(0xBEEF, 16));
+    cpu.set_mem(0xA080, 0x00);
+    let out = eeprom_bits(&mut cpu, &command(0b10, 5));
+    assert_eq!(out[10], 0, "dummy bit");
+    let data = eeprom_bits(&mut cpu, &[0; 16]);
+    assert_eq!(data, to_bits(0xBEEF, 16));
+}
+
+#[test]
+fn pocket_camera_develops_photo_into_ram() {
+    use crate::cpu::cartridge::camera::{StillImageCamera, CAMERA_HEIGHT, CAMERA_WIDTH};
+    use crate::io::constants::CPU_CYCLES_PER_SECOND;
+
+    let mut rom = test_rom(&[]);
+    rom[0x147] = 0xFC;
+    rom[0x149] = 0x04;
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+
+    // left half black, right half white
+    let scene = (0..CAMERA_WIDTH * CAMERA_HEIGHT)
+        .map(|i| {
+            if i % CAMERA_WIDTH < CAMERA_WIDTH / 2 {
+                0
+            } else {
+                255
+            }
+        })
+        .collect::<Vec<u8>>();
+    let camera = StillImageCamera::from_grayscale(CAMERA_WIDTH, CAMERA_HEIGHT, &scene).unwrap();
+    cpu.set_camera_source(Box::new(camera));
+
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0x4000, 0x10);
+    cpu.set_mem(0xA002, 0x08);
+    cpu.set_mem(0xA003, 0x00);
+    for i in 0..16 {
+        cpu.set_mem(0xA006 + i * 3, 0x40);
+        cpu.set_mem(0xA007 + i * 3, 0x80);
+        cpu.set_mem(0xA008 + i * 3, 0xC0);
+    }
+    assert_eq!(cpu.get_mem(0xA004), 0x00, "registers are write only");
+
+    cpu.set_mem(0xA000, 0x01);
+    assert_eq!(cpu.get_mem(0xA000) & 1, 1, "busy while capturing");
+    cpu.cartridge_step(100_000, CPU_CYCLES_PER_SECOND);
+    assert_eq!(cpu.get_mem(0xA000) & 1, 1);
+    cpu.cartridge_step(200_000, CPU_CYCLES_PER_SECOND);
+    assert_eq!(cpu.get_mem(0xA000) & 1, 0, "capture finished");
+
+    cpu.set_mem(0x4000, 0x00);
+    // the first tile of a row is black, the last one white
+    assert_eq!((cpu.get_mem(0xA100), cpu.get_mem(0xA101)), (0xFF, 0xFF));
+    assert_eq!((cpu.get_mem(0xA1F0), cpu.get_mem(0xA1F1)), (0x00, 0x00));
+}
+
+#[test]
+fn rom_patches_apply() {
+    use crate::io::patch::apply_patch;
+
+    fn varint(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            n -= 1;
+        }
+    }
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let patch_crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    // IPS: a plain record and a run-length encoded one
+    let mut ips = b"PATCH".to_vec();
+    ips.extend_from_slice(&[0, 0, 2, 0, 2, 0xAA, 0xBB]);
+    ips.extend_from_slice(&[0, 0, 8, 0, 0, 0, 3, 0xCC]);
+    ips.extend_from_slice(b"EOF");
+    let patched = apply_patch(&[0; 16], &ips).unwrap();
+    assert_eq!(
+        &patched[..12],
+        &[0, 0, 0xAA, 0xBB, 0, 0, 0, 0, 0xCC, 0xCC, 0xCC, 0]
+    );
+
+    let source = [1, 2, 3, 4];
+
+    // UPS: XOR hunks, growing the ROM by a byte
+    let target = [1, 2, 7, 4, 9];
+    let mut ups = b"UPS1".to_vec();
+    varint(source.len(), &mut ups);
+    varint(target.len(), &mut ups);
+    varint(2, &mut ups);
+    ups.extend_from_slice(&[3 ^ 7, 0]);
+    varint(0, &mut ups);
+    ups.extend_from_slice(&[9, 0]);
+    let ups = with_footer(ups, &source, &target);
+    assert_eq!(apply_patch(&source, &ups).unwrap(), target);
+    assert!(apply_patch(&[1, 2, 3, 5], &ups).is_err(), "wrong ROM");
+
+    // BPS: source read, overlapping target copy and target read
+    let target = [1, 2, 3, 4, 1, 2, 3, 4, 0xEE];
+    let mut bps = b"BPS1".to_vec();
+    varint(source.len(), &mut bps);
+    varint(target.len(), &mut bps);
+    varint(0, &mut bps);
+    varint(3 << 2, &mut bps);
+    varint((3 << 2) | 3, &mut bps);
+    varint(0, &mut bps);
+    varint(1, &mut bps);
+    bps.push(0xEE);
+    let mut bps = with_footer(bps, &source, &target);
+    assert_eq!(apply_patch(&source, &bps).unwrap(), target);
+    bps[5] ^= 1;
+    assert!(apply_patch(&source, &bps).is_err(), "corrupt patch");
+
+    // malformed patches with good checksums are refused, not panicked on
+    let mut ups = b"UPS1".to_vec();
+    varint(source.len(), &mut ups);
+    varint(source.len(), &mut ups);
+    varint(1 << 63, &mut ups);
+    ups.extend_from_slice(&[1, 0]);
+    varint(1 << 63, &mut ups);
+    ups.extend_from_slice(&[1, 0]);
+    let ups = with_footer(ups, &source, &source);
+    assert!(apply_patch(&source, &ups).is_err(), "UPS offset overflow");
+
+    let mut bps = b"BPS1".to_vec();
+    varint(source.len(), &mut bps);
+    varint(source.len(), &mut bps);
+    varint(usize::MAX, &mut bps);
+    let bps = with_footer(bps, &source, &source);
+    assert!(apply_patch(&source, &bps).is_err(), "BPS metadata overflow");
+
+    let mut bps = b"BPS1".to_vec();
+    varint(source.len(), &mut bps);
+    varint(1 << 40, &mut bps);
+    varint(0, &mut bps);
+    let bps = with_footer(bps, &source, &source);
+    let error = apply_patch(&source, &bps).unwrap_err();
+    assert!(error.contains("allowed"), "{}", error);
+}
+
+#[test]
+fn roms_load_from_archives() {
+    use crate::io::archive::extract_rom;
+    use std::io::Write;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+    /// A zip archive with one deflated file per entry
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut directory = vec![];
+        for (name, data) in files {
+            let compressed = deflate(data);
+            let mut header = vec![];
+            header.extend_from_slice(&8u16.to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+            header.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0; 2]);
+
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&header);
+            // comment length, disk, internal and external attributes
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&header);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&compressed);
+        }
+        let directory_offset = out.len() as u32;
+        out.extend_from_slice(&directory);
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out
+    }
+
+    let rom = test_rom(&[]);
+    let other = test_rom(&[0x00, 0x76]);
+    let archive = zip(&[
+        ("readme.txt", b"not a rom"),
+        ("roms/game.gb", &rom),
+        ("roms/other.gbc", &other),
+    ]);
+
+    let first = extract_rom(archive.clone(), "games.zip", None).unwrap();
+    assert_eq!(first.name, "game.gb");
+    assert_eq!(first.data, rom);
+    let picked = extract_rom(archive.clone(), "games.zip", Some("other.gbc")).unwrap();
+    assert_eq!(picked.data, other);
+    assert!(extract_rom(archive.clone(), "games.zip", Some("missing.gb")).is_err());
+    let mut corrupt = archive;
+    let data_start = 30 + "readme.txt".len() + deflate(b"not a rom").len() + 30 + 12;
+    corrupt[data_start + 2] ^= 0xFF;
+    assert!(extract_rom(corrupt, "games.zip", None).is_err());
+
+    let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    gzip.write_all(&rom).unwrap();
+    let gzipped = extract_rom(gzip.finish().unwrap(), "game.gb.gz", None).unwrap();
+    assert_eq!(gzipped.name, "game.gb");
+    assert_eq!(gzipped.data, rom);
+}
+
+#[test]
+fn rom_header_report() {
+    use crate::cpu::cartridge::header::*;
+
+    let mut rom = test_rom(&[]);
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x13A].copy_from_slice(b"REPORT");
+    rom[0x143] = 0x80;
+    rom[0x147] = 0x13;
+    rom[0x149] = 0x03;
+    rom[0x14D] = header_checksum(&rom);
+    let global = global_checksum(&rom).to_be_bytes();
+    rom[0x14E..0x150].copy_from_slice(&global);
+
+    let info = rom_info(&rom).unwrap();
+    assert!(info.is_valid(), "{:?}", info.problems());
+    assert!(info.problems().is_empty());
+    assert_eq!(info.title, "REPORT");
+    assert_eq!(info.mapper, "MBC3");
+    assert!(info.battery);
+    assert_eq!(info.cgb, CgbSupport::Compatible);
+    assert_eq!(info.ram_size, Some(0x8000));
+    let json = info.to_json();
+    assert!(json.contains("\"title\": \"REPORT\""));
+    assert!(json.contains("\"valid\": true"));
+    assert_eq!(info.licensee, Some("None"));
+
+    // licensee codes are looked up, the new ones only when 0x014B says so
+    let mut licensed = rom.clone();
+    licensed[0x14B] = 0x01;
+    assert_eq!(rom_info(&licensed).unwrap().licensee, Some("Nintendo"));
+    licensed[0x14B] = 0x33;
+    licensed[0x144..0x146].copy_from_slice(b"A4");
+    assert_eq!(rom_info(&licensed).unwrap().licensee, Some("Konami"));
+    licensed[0x144..0x146].copy_from_slice(b"ZZ");
+    let info = rom_info(&licensed).unwrap();
+    assert!(!info.licensee_valid);
+    assert!(info.to_json().contains("\"licensee\": null"));
+    licensed[0x14B] = 0x02;
+    assert!(!rom_info(&licensed).unwrap().licensee_valid);
+
+    // a bad dump: header damaged and the file cut short
+    rom[0x134] = b'X';
+    rom.truncate(0x6000);
+    let info = rom_info(&rom).unwrap();
+    assert!(!info.is_valid());
+    assert!(!info.header_checksum_valid);
+    assert_eq!(info.problems().len(), 3);
+    assert!(rom_info(&rom[..0x100]).is_err());
+}
+
+#[test]
+fn battery_ram_flushes_only_when_dirty() {
+    let mut rom = test_rom(&[0x18, 0xFE]);
+    rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+    rom[0x149] = 0x02;
+    rom[0x134..0x13B].copy_from_slice(b"FLUSHED");
+    let dir = std::env::temp_dir().join(format!("rusty-boy-flush-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("FLUSHED.sav");
+
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom.clone());
+    cpu.flush_ram(&path);
+    assert!(!path.exists());
+
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0xA000, 0x42);
+    cpu.flush_ram(&path);
+    assert_eq!(std::fs::read(&path).unwrap()[0], 0x42);
+    assert!(!path.with_extension("sav.tmp").exists());
+
+    // nothing new to write
+    std::fs::remove_file(&path).unwrap();
+    cpu.flush_ram(&path);
+    assert!(!path.exists());
+    // nor after writing what is already there, or while RAM is disabled
+    cpu.set_mem(0xA000, 0x42);
+    cpu.set_mem(0x0000, 0x00);
+    cpu.set_mem(0xA001, 0x42);
+    cpu.flush_ram(&path);
+    assert!(!path.exists());
+
+    // the same cartridge without a battery keeps nothing
+    rom[0x147] = 0x02; // MBC1+RAM
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom.clone());
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0xA000, 0x42);
+    cpu.save_ram(&path);
+    assert!(!path.exists());
+
+    // MBC7: latching the accelerometer and reading the EEPROM keep nothing,
+    // only a write that changes a word does
+    fn eeprom_command(cpu: &mut Cpu, bits: u32, count: u8) {
+        for i in (0..count).rev() {
+            let bit = ((bits >> i) & 1) as u8;
+            cpu.set_mem(0xA080, 0x80 | (bit << 1));
+            cpu.set_mem(0xA080, 0xC0 | (bit << 1));
+        }
+    }
+    rom[0x147] = 0x22; // MBC7+SENSOR+RUMBLE+RAM+BATTERY
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom.clone());
+    cpu.set_mem(0x0000, 0x0A);
+    cpu.set_mem(0x4000, 0x40);
+    cpu.set_mem(0xA000, 0x55);
+    cpu.set_mem(0xA010, 0xAA);
+    eeprom_command(&mut cpu, 0b110_0000_0101, 11);
+    eeprom_command(&mut cpu, 0, 16);
+    cpu.set_mem(0xA080, 0x00);
+    cpu.flush_ram(&path);
+    assert!(!path.exists());
+    // enabling writes and writing the erased value changes nothing either
+    eeprom_command(&mut cpu, 0b100_1100_0000, 11);
+    cpu.set_mem(0xA080, 0x00);
+    eeprom_command(&mut cpu, (0b101_0000_0101 << 16) | 0xFFFF, 27);
+    cpu.set_mem(0xA080, 0x00);
+    cpu.flush_ram(&path);
+    assert!(!path.exists());
+    eeprom_command(&mut cpu, (0b101_0000_0101 << 16) | 0xBEEF, 27);
+    cpu.set_mem(0xA080, 0x00);
+    cpu.flush_ram(&path);
+    assert_eq!(std::fs::read(&path).unwrap()[10..12], [0xBE, 0xEF]);
+    std::fs::remove_file(&path).unwrap();
+
+    // HuC3: polling the clock keeps nothing, setting it does
+    rom[0x147] = 0xFE; // HuC3
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+    cpu.set_mem(0x0000, 0x0B);
+    for command in [0x40, 0x50, 0x62, 0x10, 0x10, 0x20] {
+        cpu.set_mem(0xA000, command);
+    }
+    cpu.flush_ram(&path);
+    assert!(!path.exists());
+    cpu.set_mem(0xA000, 0x25);
+    cpu.flush_ram(&path);
+    assert!(path.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn save_files_import_common_layouts() {
+    use crate::cpu::cartridge::{Cartridge, SaveLayout};
+
+    let mut rom = test_rom(&[0x18, 0xFE]);
+    rom[0x147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+    rom[0x149] = 0x02;
+    let mut cart = Cartridge::load(rom.clone()).unwrap();
+    cart.write_ram_value(0x0000, 0x0A);
+
+    // SRAM with the 48-byte clock footer other emulators write
+    let mut save = vec![0x11; 0x2000];
+    let mut footer = [0u8; 48];
+    footer[0] = 30; // seconds
+    save.extend_from_slice(&footer);
+    let (layout, warnings) = cart.import_ram(&save);
+    assert_eq!(layout, SaveLayout::RtcFooter(48));
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    assert_eq!(cart[0xA000], 0x11);
+
+    // a short save is loaded as far as it goes
+    let (layout, warnings) = cart.import_ram(&[0x22; 0x800]);
+    assert_eq!(layout, SaveLayout::Raw);
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(cart[0xA000], 0x22);
+    assert_eq!(cart[0xA800], 0x11);
+
+    // a clock footer on a cartridge without a clock is dropped
+    rom[0x147] = 0x13; // MBC3+RAM+BATTERY
+    let mut cart = Cartridge::load(rom).unwrap();
+    let (layout, warnings) = cart.import_ram(&save);
+    assert_eq!(layout, SaveLayout::Raw);
+    assert!(warnings[0].contains("no clock"));
+    let (_, warnings) = cart.import_ram(&[0; 0x10000]);
+    assert!(warnings[0].contains("ignoring the last"));
+}
+
+#[test]
+fn custom_mappers_can_be_registered() {
+    use crate::cpu::cartridge::mapper::*;
+    use crate::cpu::cartridge::Cartridge;
+    use crate::cpu::savestate::{SaveState, StateReader, StateWriter};
+
+    /// Maps the bank written anywhere in 0x0000-0x7FFF, with no RAM
+    #[derive(Clone)]
+    struct LatchMapper {
+        bank: u32,
+    }
+
+    impl Mapper for LatchMapper {
+        fn name(&self) -> &'static str {
+            "latch"
+        }
+        fn state_tag(&self) -> u8 {
+            0x80
+        }
+        fn rom_offset(&self, address: u16) -> usize {
+            match address {
+                0x0000..=0x3FFF => address as usize,
+                _ => banked_rom_offset(self.bank, address),
+            }
+        }
+        fn read_ram(&self, _address: u16) -> &u8 {
+            &OPEN_BUS
+        }
+        fn write_ram(&mut self, _address: u16, _value: u8) -> bool {
+            false
+        }
+        fn write_register(&mut self, _address: u16, value: u8) {
+            self.bank = value as u32;
+        }
+        fn reset(&mut self) {
+            self.bank = 1;
+        }
+    }
+
+    impl SaveState for LatchMapper {
+        fn save_state(&self, writer: &mut StateWriter) {
+            writer.write_u32(self.bank);
+        }
+        fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+            self.bank = reader.read_u32()?;
+            Ok(())
+        }
+    }
+
+    let mut rom = vec![0u8; 0x10000];
+    rom[0x148] = 0x01; // 64KB
+    for bank in 0..4 {
+        rom[bank * 0x4000 + 0x1000] = bank as u8;
+    }
+
+    // nothing knows type 0x20, so the cartridge reads as open bus
+    rom[0x147] = 0x20;
+    let mut cart = Cartridge::load(rom.clone()).unwrap();
+    cart.write_ram_value(0x0000, 0x0A);
+    cart.write_ram_value(0xA000, 0x12);
+    assert_eq!(cart[0xA000], 0xFF);
+    assert_eq!(cart[0x5000], 1);
+
+    register_mapper(0x20, |_| Box::new(LatchMapper { bank: 1 }));
+    let mut cart = Cartridge::load(rom).unwrap();
+    unregister_mapper(0x20);
+    assert_eq!(cart.mapper_name(), "latch");
+    cart.write_ram_value(0x7FFF, 3);
+    assert_eq!(cart[0x5000], 3);
+    cart.reset();
+    assert_eq!(cart[0x5000], 1);
+}
+
+#[test]
+fn boot_rom_runs_until_unmapped() {
+    let mut rom = test_rom(&[0x18, 0xFE]);
+    rom[0x0000] = 0xAA;
+
+    let mut boot_rom = vec![0u8; DMG_BOOT_ROM_SIZE];
+    boot_rom[0x00..0x05].copy_from_slice(&[
+        0x3E, 0x42, // LD A, 0x42
+        0xEA, 0x00, 0xC0, // LD (0xC000), A
+    ]);
+    boot_rom[0xFC..].copy_from_slice(&[
+        0x3E, 0x01, // LD A, 1
+        0xE0, 0x50, // LDH (0xFF50), A
+    ]);
+
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom.clone());
+    assert!(cpu.set_boot_rom(vec![0; 0x800]).is_err());
+    cpu.set_boot_rom(boot_rom).unwrap();
+    assert_eq!(cpu.pc, 0);
+    assert_eq!(cpu.get_mem(0x0000), 0x3E);
+    while cpu.pc != 0x100 {
+        cpu.dispatch_opcode();
+    }
+    assert_eq!(cpu.get_mem(0xC000), 0x42);
+    assert_eq!(cpu.get_mem(0x0000), 0xAA);
+
+    // a CGB boot ROM picking DMG mode for the game
+    let mut boot_rom = vec![0u8; CGB_BOOT_ROM_SIZE];
+    boot_rom[0x00..0x04].copy_from_slice(&[
+        0x3E, 0x04, // LD A, 4
+        0xE0, 0x4C, // LDH (KEY0), A
+    ]);
+    boot_rom[0xFC..0xFF].copy_from_slice(&[0xC3, 0x00, 0x02]); // JP 0x0200
+    boot_rom[0x200..0x205].copy_from_slice(&[
+        0xE0, 0x50, // LDH (0xFF50), A
+        0xC3, 0x00, 0x01, // JP 0x0100
+    ]);
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom);
+    cpu.set_boot_rom(boot_rom).unwrap();
+    assert!(cpu.gbc_mode);
+    while cpu.pc != 0x100 {
+        cpu.dispatch_opcode();
+    }
+    assert!(!cpu.gbc_mode);
+    assert!(cpu.dmg_compat_palettes);
+    // KEY0 is locked once the boot ROM is gone
+    cpu.set_mem(0xFF4C, 0x80);
+    assert_eq!(cpu.get_mem(0xFF4C), 0x04);
+}
+
+#[test]
+fn model_sets_post_boot_state() {
+    use crate::cpu::model::Model;
+
+    let mut rom = test_rom(&[0x18, 0xFE]);
+    rom[0x143] = 0x80; // CGB enhanced
+
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom.clone());
+    assert_eq!(cpu.model(), Model::Cgb);
+    assert!(cpu.gbc_mode);
+    assert_eq!((cpu.a, cpu.d, cpu.e), (0x11, 0xFF, 0x56));
+
+    // a color game on an original Game Boy runs without color
+    cpu.set_model(Some(Model::Dmg));
+    assert!(!cpu.gbc_mode);
+    assert_eq!((cpu.a, cpu.c), (0x01, 0x13));
+    assert_eq!(cpu.get_mem(0xFF4D), 0xFF);
+    // and stays that way when another ROM is loaded
+    rom[0x143] = 0x00;
+    cpu.load_rom(rom.clone());
+    assert_eq!(cpu.model(), Model::Dmg);
+
+    cpu.set_model(Some(Model::Agb));
+    assert!(!cpu.gbc_mode);
+    assert_eq!((cpu.a, cpu.b), (0x11, 0x01));
+
+    cpu.set_model(Some(Model::Sgb));
+    assert_eq!(cpu.get_mem(0xFF26), 0xF0);
+    assert!(cpu.set_boot_rom(vec![0; CGB_BOOT_ROM_SIZE]).is_err());
+
+    assert_eq!("MGB".parse::<Model>(), Ok(Model::Mgb));
+    assert!("gba".parse::<Model>().is_err());
+}
+
+#[test]
+fn memory_accesses_tick_the_system() {
+    let mut cpu = Cpu::new();
+    cpu.load_rom(test_rom(&[
+        0x3E, 0xC0, // LD A, 0xC0
+        0xE0, 0x46, // LDH (DMA), A
+        0x7E, // LD A, (HL)
+        0x18, 0xFE, // JR -2
+    ]));
+    for i in 0..0xA0 {
+        cpu.set_mem(0xC000 + i, i as u8);
+    }
+    cpu.set_hl(0xFE10);
+
+    assert_eq!(cpu.dispatch_opcode(), 8);
+    let cycles = cpu.cycles;
+    assert_eq!(cpu.dispatch_opcode(), 12);
+    assert_eq!(cpu.cycles - cycles, 12);
+    // the DMA has started copying by the time LD A, (HL) reads OAM
+    assert_eq!(cpu.dispatch_opcode(), 8);
+    assert_eq!(cpu.a, 0xFF);
+    assert_eq!(cpu.get_mem(0xFE9F), 0xFF);
+
+    // a byte per M-cycle after a 1 M-cycle delay
+    for _ in 0..158 {
+        cpu.tick();
+    }
+    assert_eq!(cpu.get_mem(0xFE00), 0xFF);
+    cpu.tick();
+    assert_eq!(cpu.get_mem(0xFE00), 0x00);
+    assert_eq!(cpu.get_mem(0xFE9F), 0x9F);
+}
+
+#[test]
+fn timer_counts_falling_edges_of_the_divider() {
+    let mut cpu = Cpu::new();
+    cpu.unset_timer_interrupt_bit();
+    cpu.set_mem(0xFF04, 0);
+    cpu.set_mem(0xFF06, 0x80);
+    cpu.set_mem(0xFF05, 0xFE);
+    // 262144 Hz: bit 3 of the counter, so every 4 M-cycles
+    cpu.set_mem(0xFF07, 0x05);
+    assert_eq!(cpu.get_mem(0xFF07), 0xFD);
+
+    for _ in 0..4 {
+        cpu.tick();
+    }
+    assert_eq!(cpu.get_mem(0xFF05), 0xFF);
+    for _ in 0..4 {
+        cpu.tick();
+    }
+    // TIMA reads 0 for an M-cycle before TMA is loaded
+    assert_eq!(cpu.get_mem(0xFF05), 0x00);
+    assert!(!cpu.get_timer_interrupt_bit());
+    cpu.tick();
+    assert_eq!(cpu.get_mem(0xFF05), 0x80);
+    assert!(cpu.get_timer_interrupt_bit());
+    // writes to TIMA are ignored in the M-cycle of the reload, TMA's go
+    // through
+    cpu.set_mem(0xFF05, 0x10);
+    cpu.set_mem(0xFF06, 0x20);
+    assert_eq!(cpu.get_mem(0xFF05), 0x20);
+
+    // writing TIMA in the M-cycle after the overflow cancels the reload
+    cpu.tick();
+    cpu.unset_timer_interrupt_bit();
+    cpu.set_mem(0xFF05, 0xFF);
+    while cpu.get_mem(0xFF05) == 0xFF {
+        cpu.tick();
+    }
+    cpu.set_mem(0xFF05, 0x42);
+    cpu.tick();
+    assert_eq!(cpu.get_mem(0xFF05), 0x42);
+    assert!(!cpu.get_timer_interrupt_bit());
+
+    // resetting DIV while bit 3 is set is a falling edge
+    cpu.set_mem(0xFF04, 0);
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(cpu.get_div(), 0);
+    cpu.set_mem(0xFF04, 0x12);
+    assert_eq!(cpu.get_mem(0xFF05), 0x43);
+    // and so is turning the timer off
+    cpu.tick();
+    cpu.tick();
+    cpu.set_mem(0xFF07, 0x01);
+    assert_eq!(cpu.get_mem(0xFF05), 0x44);
+}
+
+#[test]
+fn pixel_fifo_draws_mid_line_changes() {
+    use crate::cpu::ppu::{PpuMode, RenderMode};
+    use crate::io::constants::TILE_PALETTE;
+
+    /// M-cycles mode 3 takes on the next line
+    fn mode3_mcycles(cpu: &mut Cpu) -> u32 {
+        while cpu.ppu.mode() != PpuMode::Drawing {
+            cpu.tick();
+        }
+        let mut mcycles = 0;
+        while cpu.ppu.mode() == PpuMode::Drawing {
+            cpu.tick();
+            mcycles += 1;
+        }
+        mcycles
+    }
+
+    let mut cpu = Cpu::new();
+    assert_eq!(mode3_mcycles(&mut cpu), 42);
+    cpu.set_render_mode(RenderMode::Fifo);
+    let plain = mode3_mcycles(&mut cpu);
+    assert_eq!(plain, 43);
+    cpu.set_mem(0xFF43, 7);
+    let scrolled = mode3_mcycles(&mut cpu);
+    assert!(scrolled > plain);
+    // on the next line
+    let y = cpu.ly() + 1 + 16;
+    for i in 0..10 {
+        cpu.mem.oam[i * 4] = y;
+        cpu.mem.oam[i * 4 + 1] = 8 + 16 * i as u8;
+    }
+    cpu.set_mem(0xFF43, 0);
+    cpu.set_mem(0xFF40, 0x93);
+    assert!(mode3_mcycles(&mut cpu) > scrolled + 10 * 6 / 4);
+
+    // turn the background from white to black half way through a line
+    while cpu.ly() != 100 {
+        cpu.tick();
+    }
+    cpu.set_mem(0xFF47, 0xFC);
+    while cpu.ppu.mode() != PpuMode::Drawing {
+        cpu.tick();
+    }
+    for _ in 0..25 {
+        cpu.tick();
+    }
+    cpu.set_mem(0xFF47, 0xFF);
+    let frame = loop {
+        cpu.tick();
+        if let Some(frame) = cpu.take_frame() {
+            break frame;
+        }
+    };
+    assert_eq!(frame[100][0], TILE_PALETTE[0]);
+    assert_eq!(frame[100][159], TILE_PALETTE[3]);
+
+    // a white window over a black background, saved half way through
+    // drawing a line
+    let vram = &mut cpu.mem.video_ram[0];
+    vram.fill(0);
+    vram[0x10..0x20].fill(0xFF);
+    vram[0x1800..0x1C00].fill(1);
+    cpu.set_mem(0xFF47, 0xE4);
+    cpu.set_mem(0xFF4A, 0);
+    cpu.set_mem(0xFF4B, 7);
+    cpu.set_mem(0xFF40, 0xF1);
+    while cpu.take_frame().is_none() {
+        cpu.tick();
+    }
+    while cpu.ly() != 50 {
+        cpu.tick();
+    }
+    while cpu.ppu.mode() != PpuMode::Drawing {
+        cpu.tick();
+    }
+    for _ in 0..20 {
+        cpu.tick();
+    }
+    let state = cpu.save_state_bytes();
+
+    // loaded into an emulator that is drawing a line without the window,
+    // the line starts over and the window stays
+    let mut restored = Cpu::new();
+    restored.set_render_mode(RenderMode::Fifo);
+    restored.set_mem(0xFF4A, 200);
+    while restored.ppu.mode() != PpuMode::Drawing {
+        restored.tick();
+    }
+    for _ in 0..20 {
+        restored.tick();
+    }
+    restored.load_state_bytes(&state).unwrap();
+    let frame = loop {
+        restored.tick();
+        if let Some(frame) = restored.take_frame() {
+            break frame;
+        }
+    };
+    for y in [50, 51, 100] {
+        assert!(frame[y].iter().all(|&pixel| pixel == TILE_PALETTE[0]));
+    }
+}
+
+#[test]
+fn stat_interrupt_fires_on_rising_edges_of_its_line() {
+    use crate::cpu::model::Model;
+    use crate::cpu::ppu::PpuMode;
+
+    fn run_until(cpu: &mut Cpu, ly: u8, mode: PpuMode) {
+        while cpu.ly() != ly || cpu.ppu.mode() != mode {
+            cpu.tick();
+        }
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.set_mem(0xFF45, 50);
+    cpu.set_mem(0xFF41, 0x40);
+    run_until(&mut cpu, 49, PpuMode::HBlank);
+    cpu.unset_lcdc_interrupt_bit();
+    run_until(&mut cpu, 50, PpuMode::OamScan);
+    assert!(cpu.get_lcdc_interrupt_bit());
+    // LY=LYC holds the line up through HBlank, blocking its source
+    cpu.set_mem(0xFF41, 0x48);
+    cpu.unset_lcdc_interrupt_bit();
+    run_until(&mut cpu, 50, PpuMode::HBlank);
+    assert!(!cpu.get_lcdc_interrupt_bit());
+    run_until(&mut cpu, 51, PpuMode::HBlank);
+    assert!(cpu.get_lcdc_interrupt_bit());
+
+    // LY reads 0 for most of line 153, where LY=LYC fires for LYC 0
+    cpu.set_mem(0xFF41, 0x40);
+    cpu.set_mem(0xFF45, 0);
+    run_until(&mut cpu, 153, PpuMode::VBlank);
+    cpu.unset_lcdc_interrupt_bit();
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(cpu.ly(), 0);
+    assert_eq!(cpu.ppu.mode(), PpuMode::VBlank);
+    assert!(cpu.get_lcdc_interrupt_bit());
+    // and doesn't again when line 0 starts
+    cpu.unset_lcdc_interrupt_bit();
+    run_until(&mut cpu, 0, PpuMode::OamScan);
+    assert!(!cpu.get_lcdc_interrupt_bit());
+    assert_eq!(cpu.get_mem(0xFF41) & 0x7, 0x6);
+
+    // writing STAT during VBlank requests the interrupt on a DMG only
+    cpu.unset_vblank_interrupt_bit();
+    cpu.disable_interrupts();
+    cpu.mem[0xFFFF] = 0;
+    run_until(&mut cpu, 144, PpuMode::VBlank);
+    // VBlank is requested whether or not interrupts are enabled
+    assert!(cpu.get_vblank_interrupt_bit());
+    cpu.set_mem(0xFF41, 0);
+    assert!(cpu.get_lcdc_interrupt_bit());
+    cpu.unset_lcdc_interrupt_bit();
+    cpu.set_model(Some(Model::Cgb));
+    cpu.reset();
+    run_until(&mut cpu, 144, PpuMode::VBlank);
+    cpu.unset_lcdc_interrupt_bit();
+    cpu.set_mem(0xFF41, 0);
+    assert!(!cpu.get_lcdc_interrupt_bit());
+}
+
+#[test]
+fn cgb_tiles_use_their_attributes() {
+    use crate::cpu::ppu::RenderMode;
+    use crate::io::deferred_renderer::deferred_renderer_draw_scanline;
+
+    let mut cpu = Cpu::new();
+    cpu.gbc_mode = true;
+    cpu.set_mem(0xFF40, 0x93);
+    // tile 1 in bank 1 has a single pixel of color 1 on the left
+    cpu.mem.video_ram[1][16] = 0x80;
+    // the first tile of the map is tile 1 from bank 1, flipped horizontally,
+    // with palette 1 and priority over sprites
+    cpu.mem.video_ram[0][0x1800] = 1;
+    cpu.mem.video_ram[1][0x1800] = 0x80 | 0x20 | 0x08 | 0x01;
+    // a sprite of color 1 with palette 2 over x 7 to 14
+    cpu.mem.video_ram[0][32] = 0xFF;
+    cpu.mem.oam[..4].copy_from_slice(&[16, 15, 2, 0x02]);
+    cpu.mem.gbc_background_color_palette[8..12].copy_from_slice(&[0xFF, 0x7F, 0x1F, 0x00]);
+    cpu.mem.gbc_sprite_color_palette[18..20].copy_from_slice(&[0xE0, 0x03]);
+    let white = (248, 248, 248);
+    let red = (248, 0, 0);
+    let green = (0, 248, 0);
+
+    let line = deferred_renderer_draw_scanline(0, &mut cpu, &mut 0);
+    assert_eq!(line[0], white);
+    // the flipped pixel is drawn over the sprite
+    assert_eq!(line[7], red);
+    assert_eq!(line[8], green);
+
+    // the FIFO draws the same
+    cpu.set_render_mode(RenderMode::Fifo);
+    let frame = loop {
+        cpu.tick();
+        if let Some(frame) = cpu.take_frame() {
+            break frame;
+        }
+    };
+    assert_eq!(frame[0], line);
+
+    // without LCDC bit 0 the background loses its priority
+    cpu.set_mem(0xFF40, 0x92);
+    let line = deferred_renderer_draw_scanline(0, &mut cpu, &mut 0);
+    assert_eq!(line[0], white);
+    assert_eq!(line[7], green);
+}
+
+#[test]
+fn cgb_games_get_their_own_save_files() {
+    let dir = std::path::PathBuf::from("saves");
+    let mut paths = vec![];
+    for (title, checksum) in [(&b"ORACLE.AGES"[..], 0x1234), (b"ORACLE/SEAS", 0x5678)] {
+        let mut rom = test_rom(&[0x18, 0xFE]);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x143] = 0x80; // CGB flag, not part of the title
+        rom[0x14E..0x150].copy_from_slice(&u16::to_be_bytes(checksum));
+        let mut cpu = Cpu::new();
+        cpu.load_rom(rom);
+        paths.push(cpu.save_file_path(dir.clone()));
+        paths.push(cpu.save_state_path(dir.clone(), 3));
+    }
+    assert_eq!(paths[0], dir.join("ORACLE.AGES 1234.sav"));
+    assert_eq!(paths[1], dir.join("states/ORACLE.AGES 1234.ss3"));
+    assert_eq!(paths[2], dir.join("ORACLESEAS 5678.sav"));
+    assert_eq!(paths[3], dir.join("states/ORACLESEAS 5678.ss3"));
+
+    // named after the file the ROM came from when it is known
+    let mut cpu = Cpu::new();
+    cpu.load_rom(test_rom(&[0x18, 0xFE]));
+    cpu.set_rom_name("Oracle of Ages (USA).gbc");
+    assert_eq!(
+        cpu.save_file_path(dir.clone()),
+        dir.join("Oracle of Ages (USA).sav")
+    );
+    assert_eq!(
+        cpu.save_state_path(dir.clone(), 0),
+        dir.join("states/Oracle of Ages (USA).ss0")
+    );
+}
+
+#[test]
+fn link_cable_drops_late_bytes() {
+    use crate::cpu::serial::{SerialEndpoint, TcpEndpoint};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let server = std::thread::spawn(move || TcpEndpoint::listen(addr).unwrap());
+    let mut clocking = loop {
+        if let Ok(endpoint) = TcpEndpoint::connect(addr) {
+            break endpoint;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    let mut passive = server.join().unwrap();
+
+    // nobody answers: the request is cancelled rather than answered late
+    assert_eq!(clocking.exchange(0x11), 0xFF);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(passive.poll_external(0x22), None);
+
+    let answer = std::thread::spawn(move || loop {
+        if let Some(incoming) = passive.poll_external(0x44) {
+            break incoming;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    });
+    assert_eq!(clocking.exchange(0x33), 0x44);
+    assert_eq!(answer.join().unwrap(), 0x33);
+}
+
+#[test]
+fn lcd_stops_while_turned_off() {
+    use crate::cpu::ppu::PpuMode;
+    use crate::io::constants::TILE_PALETTE;
+
+    let mut cpu = Cpu::new();
+    while cpu.ly() != 10 {
+        cpu.tick();
+    }
+    cpu.set_mem(0xFF40, 0x11);
+    assert_eq!(cpu.ly(), 0);
+    assert_eq!(cpu.get_mem(0xFF41) & 0x3, 0);
+    cpu.unset_vblank_interrupt_bit();
+    // a blank frame still comes once a frame's worth of time
+    let mut mcycles = 0;
+    let frame = loop {
+        cpu.tick();
+        mcycles += 1;
+        if let Some(frame) = cpu.take_frame() {
+            break frame;
+        }
+    };
+    assert_eq!(mcycles, 456 * 154 / 4);
+    assert_eq!(frame[10][10], TILE_PALETTE[0]);
+    assert_eq!(cpu.ly(), 0);
+    assert!(!cpu.get_vblank_interrupt_bit());
+
+    // turning it on starts line 0 over, without an OAM scan
+    cpu.set_mem(0xFF40, 0x91);
+    for _ in 0..19 {
+        cpu.tick();
+        assert_eq!(cpu.ppu.mode(), PpuMode::HBlank);
+        assert_eq!(cpu.ly(), 0);
+    }
+    cpu.tick();
+    assert_eq!(cpu.ppu.mode(), PpuMode::Drawing);
+    while cpu.ly() == 0 {
+        cpu.tick();
+    }
+    assert_eq!(cpu.ppu.mode(), PpuMode::OamScan);
+}
+
+#[test]
+fn halt_ends_on_enabled_interrupts_without_ime() {
+    // HALT; then an endless loop
+    let mut cpu = Cpu::new();
+    cpu.load_rom(test_rom(&[0x76, 0x18, 0xFE]));
+    cpu.disable_interrupts();
+    cpu.mem[0xFF0F] = 0;
+    cpu.mem[0xFFFF] = 0x01;
+    cpu.dispatch_opcode();
+    assert_eq!(cpu.state, CpuState::Halt);
+    // the STAT interrupt isn't enabled in IE, so doesn't end HALT
+    cpu.set_mem(0xFF41, 0x08);
+    while !cpu.get_vblank_interrupt_bit() {
+        assert_eq!(cpu.state, CpuState::Halt);
+        cpu.dispatch_opcode();
+    }
+    cpu.dispatch_opcode();
+    assert_eq!(cpu.state, CpuState::Normal);
+    // and without IME the handler isn't called
+    assert_eq!(cpu.pc, 0x101);
+    assert!(cpu.get_vblank_interrupt_bit());
+}
+
+#[test]
+fn test_rom_results_need_the_final_report() {
+    use crate::cpu::serial::CaptureEndpoint;
+    use crate::io::headless::*;
+
+    let mut cpu = Cpu::new();
+    for (serial, result) in [
+        (&b"01:ok\nPassed\n"[..], Some(TestRomResult::Passed)),
+        (
+            b"cpu_instrs\n\nPassed all tests\n\n",
+            Some(TestRomResult::Passed),
+        ),
+        (b"Failed #3\n", Some(TestRomResult::Failed)),
+        (b"Failed", None),
+        (b"Passed 01\nrunning 02\n", None),
+        (b"Failed tests are listed below:\n01\n", None),
+    ] {
+        cpu.serial.set_endpoint(Box::new(CaptureEndpoint {
+            buffer: serial.to_vec(),
+        }));
+        assert_eq!(test_rom_result(&cpu), result, "{:?}", serial);
+    }
+
+    // LD B, B with the Fibonacci numbers in the registers; only the
+    // breakpoint makes them a result
+    cpu.serial.set_endpoint(Box::new(CaptureEndpoint::new()));
+    cpu.load_rom(test_rom(&[
+        0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34,   // LD B..L
+        0x00, // NOP
+        0x40, // LD B, B
+        0x18, 0xFE, // JR -2
+    ]));
+    for _ in 0..7 {
+        cpu.dispatch_opcode();
+    }
+    assert_eq!(mooneye_registers(&cpu), MOONEYE_PASS);
+    assert_eq!(test_rom_result(&cpu), None);
+    cpu.dispatch_opcode();
+    assert_eq!(test_rom_result(&cpu), Some(TestRomResult::Passed));
+}
+
+#[test]
+fn rewind_snapshots_are_only_taken_when_enabled() {
+    use crate::io::headless::*;
+
+    let mut appstate = new_headless_state().unwrap();
+    appstate.gameboy.load_rom(test_rom(&[0x18, 0xFE]));
+    run_headless(&mut appstate, 8, None);
+    assert!(appstate.rewind.is_empty());
+
+    appstate.rewind_enabled = true;
+    run_headless(&mut appstate, 8, None);
+    assert_eq!(appstate.rewind.len(), 2);
+}
@@ -0,0 +1,154 @@
+//! DIV, TIMA, TMA and TAC
+//!
+//! DIV is the top byte of a 16-bit counter that counts every clock cycle.
+//! TIMA counts falling edges of one of the counter's bits, picked by TAC
+//! and ANDed with TAC's enable bit, so anything that drops that signal
+//! counts: resetting DIV, turning the timer off or switching to a bit that
+//! is low.  The APU's frame sequencer is clocked the same way from bit 12
+//! (bit 13 in double speed).
+//!
+//! When TIMA overflows it holds 0 for an M-cycle before TMA is loaded and
+//! the interrupt requested.  Writing TIMA in that M-cycle cancels the
+//! reload; in the M-cycle of the reload TIMA ignores writes and writes to
+//! TMA go through to TIMA too.
+
+use crate::cpu::constants::*;
+use crate::cpu::Cpu;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimaState {
+    Running,
+    /// TIMA overflowed and reads 0; TMA is loaded on the next M-cycle
+    Overflowed,
+    /// TMA was loaded into TIMA this M-cycle
+    Reloaded,
+}
+
+impl TimaState {
+    /// Identifies the state in save-states
+    pub fn id(self) -> u8 {
+        match self {
+            TimaState::Running => 0,
+            TimaState::Overflowed => 1,
+            TimaState::Reloaded => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<TimaState> {
+        match id {
+            0 => Some(TimaState::Running),
+            1 => Some(TimaState::Overflowed),
+            2 => Some(TimaState::Reloaded),
+            _ => None,
+        }
+    }
+}
+
+impl Cpu {
+    /// FF04 Div
+    pub fn get_div(&self) -> u8 {
+        (self.div_counter >> 8) as u8
+    }
+
+    /// The counter bit TIMA counts, from the bottom bits of TAC
+    fn timer_bit(&self) -> u16 {
+        match self.mem[0xFF07_u16] & 0x3 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            3 => 7,
+            _ => unreachable!("The impossible happened!"),
+        }
+    }
+
+    fn is_timer_on(&self) -> bool {
+        (self.mem[0xFF07_u16] & 0x4) >> 2 == 1
+    }
+
+    /// What TIMA watches for falling edges
+    fn timer_signal(&self) -> bool {
+        self.is_timer_on() && (self.div_counter >> self.timer_bit()) & 1 == 1
+    }
+
+    /// The counter bit the APU's frame sequencer watches
+    fn apu_div_bit(&self) -> u16 {
+        if self.double_speed {
+            13
+        } else {
+            12
+        }
+    }
+
+    /// Advances the timers by an M-cycle
+    pub(crate) fn timer_step(&mut self) {
+        match self.tima_state {
+            TimaState::Overflowed => {
+                self.mem[0xFF05] = self.mem[0xFF06_u16];
+                self.set_timer_interrupt_bit();
+                self.tima_state = TimaState::Reloaded;
+            }
+            TimaState::Reloaded => self.tima_state = TimaState::Running,
+            TimaState::Running => (),
+        }
+        let counter = self.div_counter.wrapping_add(4);
+        self.set_div_counter(counter);
+    }
+
+    /// Changes the internal counter, counting the falling edges it causes
+    fn set_div_counter(&mut self, counter: u16) {
+        let old_signal = self.timer_signal();
+        let apu_bit = self.apu_div_bit();
+        let old_apu_signal = (self.div_counter >> apu_bit) & 1 == 1;
+
+        self.div_counter = counter;
+        self.mem[0xFF04] = self.get_div();
+
+        if old_apu_signal && (counter >> apu_bit) & 1 == 0 {
+            self.apu.step();
+        }
+        if old_signal && !self.timer_signal() {
+            self.inc_timer();
+        }
+    }
+
+    fn inc_timer(&mut self) {
+        let old_val = self.mem[0xFF05_u16];
+        let (new_val, overflowed) = old_val.overflowing_add(1);
+        self.mem[0xFF05] = new_val;
+        if overflowed {
+            self.tima_state = TimaState::Overflowed;
+        }
+    }
+
+    /// Any write to DIV resets the whole counter
+    pub(crate) fn reset_div(&mut self) {
+        self.set_div_counter(0);
+    }
+
+    pub(crate) fn write_tima(&mut self, value: byte) {
+        match self.tima_state {
+            TimaState::Running => self.mem[0xFF05] = value,
+            TimaState::Overflowed => {
+                self.mem[0xFF05] = value;
+                self.tima_state = TimaState::Running;
+            }
+            TimaState::Reloaded => (),
+        }
+    }
+
+    pub(crate) fn write_tma(&mut self, value: byte) {
+        self.mem[0xFF06] = value;
+        if self.tima_state == TimaState::Reloaded {
+            self.mem[0xFF05] = value;
+        }
+    }
+
+    pub(crate) fn write_tac(&mut self, value: byte) {
+        let old_signal = self.timer_signal();
+        // the top 5 bits read back as 1
+        self.mem[0xFF07] = value | 0xF8;
+        if old_signal && !self.timer_signal() {
+            self.inc_timer();
+        }
+    }
+}
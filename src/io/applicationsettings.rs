@@ -1,6 +1,10 @@
 //! Stores all settings related to the application from a user perspective
 
+use crate::cpu::model::Model;
+use crate::cpu::ppu::RenderMode;
+use crate::cpu::Cpu;
 use crate::io::constants::SCALE;
+use crate::io::headless::StopCondition;
 use app_dirs::*;
 use clap::ArgMatches;
 use std::path::PathBuf;
@@ -18,7 +22,35 @@ pub struct ApplicationSettings {
     pub memvis_mode: bool,
     pub debugger_on: bool,
     pub vulkan_mode: bool,
-    _config_path: Option<PathBuf>,
+    pub headless_mode: bool,
+    /// Print a report on the ROM instead of running it
+    pub rom_info: bool,
+    /// Maximum number of frames to run in headless mode
+    pub headless_frames: u64,
+    /// Stops a headless run early
+    pub stop_condition: Option<StopCondition>,
+    /// Where a headless run dumps its results
+    pub output_dir: Option<PathBuf>,
+    /// Address to wait on for a link cable connection
+    pub link_listen: Option<String>,
+    /// Address of another instance to connect a link cable to
+    pub link_connect: Option<String>,
+    /// Plug a Game Boy Printer into the link port
+    pub printer: bool,
+    /// File to load when the ROM is in a zip archive
+    pub rom_entry: Option<String>,
+    /// Patch to apply to the ROM, if not a sidecar file next to it
+    pub patch: Option<PathBuf>,
+    /// Image the Pocket Camera sees
+    pub camera_image: Option<PathBuf>,
+    /// Battery save to use instead of `<game>.sav` in the data path
+    pub save_file: Option<PathBuf>,
+    /// Boot ROM to use instead of one found in the config path
+    pub boot_rom: Option<PathBuf>,
+    /// Hardware to emulate instead of the one the ROM header asks for
+    pub model: Option<Model>,
+    pub render_mode: RenderMode,
+    config_path: Option<PathBuf>,
     pub data_path: Option<PathBuf>,
     pub ui_scale: f32,
 }
@@ -34,6 +66,38 @@ impl ApplicationSettings {
         let trace_mode = arguments.is_present("trace");
         let memvis_mode = arguments.is_present("visualize");
         let vulkan_mode = arguments.is_present("vulkan");
+        let headless_mode = arguments.is_present("headless");
+        let rom_info = arguments.is_present("rom-info");
+        let headless_frames = match arguments.value_of("frames") {
+            Some(frames) => frames
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid frame count {:?}: {}", frames, e))?,
+            None => 600,
+        };
+        let stop_condition = if let Some(text) = arguments.value_of("until-serial") {
+            Some(StopCondition::SerialContains(text.to_string()))
+        } else if arguments.is_present("until-mooneye") {
+            Some(StopCondition::MooneyeRegisters)
+        } else {
+            None
+        };
+        let output_dir = arguments.value_of("output-dir").map(PathBuf::from);
+        let link_listen = arguments.value_of("link-listen").map(str::to_string);
+        let link_connect = arguments.value_of("link-connect").map(str::to_string);
+        let printer = arguments.is_present("printer");
+        let rom_entry = arguments.value_of("rom-entry").map(str::to_string);
+        let patch = arguments.value_of("patch").map(PathBuf::from);
+        let camera_image = arguments.value_of("camera-image").map(PathBuf::from);
+        let save_file = arguments.value_of("save-file").map(PathBuf::from);
+        let boot_rom = arguments.value_of("bootrom").map(PathBuf::from);
+        let model = match arguments.value_of("model") {
+            Some(model) => Some(model.parse::<Model>()?),
+            None => None,
+        };
+        let render_mode = match arguments.value_of("renderer") {
+            Some(renderer) => renderer.parse::<RenderMode>()?,
+            None => RenderMode::default(),
+        };
 
         // Set up debugging or command-line logging
         let (should_debugger, _handle) = if debug_mode && cfg!(feature = "debugger") {
@@ -75,11 +139,49 @@ impl ApplicationSettings {
             trace_mode,
             memvis_mode,
             vulkan_mode,
-            _config_path: config_path,
+            headless_mode,
+            rom_info,
+            headless_frames,
+            stop_condition,
+            output_dir,
+            link_listen,
+            link_connect,
+            printer,
+            rom_entry,
+            patch,
+            camera_image,
+            save_file,
+            boot_rom,
+            model,
+            render_mode,
+            config_path,
             data_path,
             debugger_on: should_debugger,
             //               logger_handle: handle,
             ui_scale: SCALE,
         })
     }
+
+    /// Where the battery save for the loaded game is read from and
+    /// written to, if anywhere
+    pub fn save_file(&self, gameboy: &Cpu) -> Option<PathBuf> {
+        self.save_file.clone().or_else(|| {
+            self.data_path
+                .clone()
+                .map(|data_path| gameboy.save_file_path(data_path))
+        })
+    }
+
+    /// The boot ROM to run before the loaded game: the one given with
+    /// `--bootrom`, or the one for the emulated model in the config path
+    /// (e.g. `cgb_boot.bin`) if it is there
+    pub fn boot_rom(&self, gameboy: &Cpu) -> Option<PathBuf> {
+        self.boot_rom.clone().or_else(|| {
+            let name = gameboy.model().boot_rom_file_name();
+            self.config_path
+                .as_ref()
+                .map(|config_path| config_path.join(name))
+                .filter(|path| path.is_file())
+        })
+    }
 }
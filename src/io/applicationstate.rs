@@ -8,8 +8,8 @@ use std;
 use crate::cpu;
 use crate::io::constants::*;
 
-use crate::io::deferred_renderer::deferred_renderer_draw_scanline;
 use crate::io::graphics::renderer::Renderer;
+use crate::io::rewind::RewindBuffer;
 
 use std::num::Wrapping;
 
@@ -20,16 +20,21 @@ pub struct ApplicationState {
     //renderer: render::Renderer<'static>,
     cycle_count: u64,
     prev_time: u64,
-    /// counts cycles since last timer update
-    timer_cycles: u64,
-    /// counts cycles since last divider register update
-    div_timer_cycles: u64,
-    /// How many CPU cycles per second
-    cycles_per_second: u64,
     /// counts cycles since last sound update
     sound_cycles: u64,
     _screenshot_frame_num: Wrapping<u64>,
     pub renderer: Box<dyn Renderer>,
+    /// The most recently completed frame
+    pub last_frame: [[(u8, u8, u8); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT],
+    /// Recent snapshots of `gameboy` for playing frames backwards
+    pub rewind: RewindBuffer,
+    /// Whether snapshots are recorded for `rewind`; off by default, as
+    /// only frontends with someone to rewind need them
+    pub rewind_enabled: bool,
+    /// While set, each step restores an older snapshot instead of recording one
+    pub rewinding: bool,
+    /// The rumble state last reported to the renderer
+    rumble_active: bool,
 }
 
 impl ApplicationState {
@@ -43,12 +48,14 @@ impl ApplicationState {
             //sound_system: device,
             cycle_count: 0,
             prev_time: 0,
-            timer_cycles: 0,
-            div_timer_cycles: 0,
-            cycles_per_second: CPU_CYCLES_PER_SECOND,
             sound_cycles: 0,
             _screenshot_frame_num: Wrapping(0),
             renderer,
+            last_frame: [[(0, 0, 0); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT],
+            rewind: RewindBuffer::new(),
+            rewind_enabled: false,
+            rewinding: false,
+            rumble_active: false,
         })
     }
 
@@ -62,225 +69,57 @@ impl ApplicationState {
     */
 
     /// Runs the emulator for 1 frame and requests that frame to be drawn.
+    ///
+    /// When `rewinding` is set the newest rewind snapshot is restored
+    /// first, so repeated steps play the game backwards.
     pub fn step(&mut self) {
-        let (
-            _cycles_per_vblank,
-            _cycles_per_hsync,
-            cycles_per_second,
-            cycles_per_divider_step,
-            oam_scan_cycles,
-            vram_scan_cycles,
-            hblank_cycles,
-        ) = if self.gameboy.gbc_mode && self.gameboy.double_speed {
-            (
-                CPU_CYCLES_PER_VBLANK * 2,
-                CYCLES_PER_HSYNC * 2,
-                CPU_CYCLES_PER_SECOND * 2,
-                CPU_CYCLES_PER_DIVIDER_STEP * 2,
-                80 * 2,
-                168 * 2,
-                208 * 2,
-            )
-        } else {
-            (
-                CPU_CYCLES_PER_VBLANK,
-                CYCLES_PER_HSYNC,
-                CPU_CYCLES_PER_SECOND,
-                CPU_CYCLES_PER_DIVIDER_STEP,
-                80,
-                168,
-                208,
-            )
-        };
-        self.cycles_per_second = cycles_per_second;
-        let audio_timing_cycles = cycles_per_second / 512; //256;
-        let mut scanline_cycles: u32 = 0;
-        let mut y = 0;
-        let mut window_counter: u16 = 0;
-        let mut vblank_iterations = 0;
-
-        #[derive(Debug, Clone, Copy)]
-        enum GameBoyMode {
-            /// Mode 2
-            OamScan,
-            /// Mode 3
-            VramScan,
-            /// Mode 0
-            HBlank,
-            /// Mode 1
-            VBlank,
-        }
-
-        let mut mode = GameBoyMode::OamScan;
-        self.gameboy.set_oam_lock();
-        if self.gameboy.get_interrupts_enabled()
-            && self.gameboy.get_lcdc_interrupt_enabled()
-            && self.gameboy.get_oam_interrupt()
-        {
-            // TODO: I don't think any of this `if` stuff matters given how it's done
-            // clean up:
-            // interrupts are only triggered on a rising edge
-            if !self.gameboy.get_lcdc_interrupt_bit() {
-                self.gameboy.set_lcdc_interrupt_bit();
+        if self.rewinding {
+            if let Some(state) = self.rewind.pop() {
+                if let Err(e) = self.gameboy.load_state_bytes(&state) {
+                    error!("Could not rewind: {}", e);
+                    self.rewind.clear();
+                }
             }
         }
-        if self.gameboy.ly() != 0 {
-            self.gameboy.inc_ly();
-            assert_eq!(self.gameboy.ly(), 0);
+        self.run_frame();
+        if self.rewind_enabled && !self.rewinding && self.rewind.frame_finished() {
+            self.rewind.push(self.gameboy.save_state_bytes());
         }
-        let mut frame = [[(0u8, 0u8, 0u8); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT];
-        'steploop: loop {
-            let mut cycles_this_loop = 0;
-            match mode {
-                GameBoyMode::OamScan => {
-                    if scanline_cycles < oam_scan_cycles {
-                        cycles_this_loop = self.gameboy.dispatch_opcode() as u32;
-                        scanline_cycles += cycles_this_loop;
-                        self.cycle_count += cycles_this_loop as u64;
-                    } else {
-                        mode = GameBoyMode::VramScan;
-                        self.gameboy.set_oam_and_display_lock();
-                    }
-                }
-                GameBoyMode::VramScan => {
-                    if scanline_cycles < (vram_scan_cycles + oam_scan_cycles) {
-                        cycles_this_loop = self.gameboy.dispatch_opcode() as u32;
-                        scanline_cycles += cycles_this_loop;
-                        self.cycle_count += cycles_this_loop as u64;
-                    } else {
-                        mode = GameBoyMode::HBlank;
-                        self.gameboy.set_hblank();
-                        if self.gameboy.get_interrupts_enabled()
-                            && self.gameboy.get_lcdc_interrupt_enabled()
-                            && self.gameboy.get_hblank_interrupt()
-                        {
-                            // interrupts are only triggered on a rising edge
-                            if !self.gameboy.get_lcdc_interrupt_bit() {
-                                self.gameboy.set_lcdc_interrupt_bit();
-                            }
-                        }
-                    }
-                }
-                GameBoyMode::HBlank => {
-                    if scanline_cycles < (hblank_cycles + vram_scan_cycles + oam_scan_cycles) {
-                        cycles_this_loop = self.gameboy.dispatch_opcode() as u32;
-                        scanline_cycles += cycles_this_loop;
-                        self.cycle_count += cycles_this_loop as u64;
-                    } else {
-                        // TODO: split out render logic into here so we can maintain timer state, etc.
-                        // All dispatch_opcodes need to be overseen by the appropriate external timing stuff
-                        let scanline = deferred_renderer_draw_scanline(
-                            y,
-                            &mut self.gameboy,
-                            &mut window_counter,
-                        );
-
-                        frame[y as usize] = scanline;
-                        y += 1;
-                        self.gameboy.inc_ly();
-
-                        scanline_cycles -= hblank_cycles + vram_scan_cycles + oam_scan_cycles;
-                        assert!(
-                            scanline_cycles < hblank_cycles + vram_scan_cycles + oam_scan_cycles
-                        );
-                        if y == (GB_SCREEN_HEIGHT as u8) {
-                            self.gameboy.set_vblank();
-                            if self.gameboy.get_interrupts_enabled() {
-                                if self.gameboy.get_vblank_interrupt_enabled() {
-                                    self.gameboy.set_vblank_interrupt_bit();
-                                }
-                                if self.gameboy.get_lcdc_interrupt_enabled()
-                                    && self.gameboy.get_vblank_interrupt_stat()
-                                {
-                                    self.gameboy.set_lcdc_interrupt_bit();
-                                }
-                            }
-                            mode = GameBoyMode::VBlank;
-                        } else {
-                            mode = GameBoyMode::OamScan;
-                            self.gameboy.set_oam_lock();
-                            if self.gameboy.get_interrupts_enabled()
-                                && self.gameboy.get_lcdc_interrupt_enabled()
-                                && self.gameboy.get_oam_interrupt()
-                            {
-                                if !self.gameboy.get_lcdc_interrupt_bit() {
-                                    self.gameboy.set_lcdc_interrupt_bit();
-                                }
-                            }
-                        }
-                    }
-                }
-                GameBoyMode::VBlank => {
-                    // TOOD: verify if this is correct, should LY roll over to 0 at the end of the frame or the start of a new frame?
-                    if vblank_iterations == 9 {
-                        //10 {
-                        //assert_eq!(self.gameboy.ly(), 153);
-
-                        // REVIEW: why is this here?
-                        //self.gameboy.set_oam_and_display_lock();
 
-                        self.prev_time = self.cycle_count;
-
-                        //for memory visualization
-                        self.gameboy.remove_old_events();
+        let rumble_active = self.gameboy.rumble_active();
+        if rumble_active != self.rumble_active {
+            self.rumble_active = rumble_active;
+            self.renderer.set_rumble(rumble_active);
+        }
+    }
 
-                        // do render of frame to screen here
-                        self.renderer.draw_frame(&frame);
-
-                        break 'steploop;
-                    }
-                    if scanline_cycles < (hblank_cycles + vram_scan_cycles + oam_scan_cycles) {
-                        cycles_this_loop = self.gameboy.dispatch_opcode() as u32;
-                        scanline_cycles += cycles_this_loop;
-                        self.cycle_count += cycles_this_loop as u64;
-                    } else {
-                        scanline_cycles -= hblank_cycles + vram_scan_cycles + oam_scan_cycles;
-                        self.gameboy.inc_ly();
-                        vblank_iterations += 1;
-                    }
-                }
-            }
+    fn run_frame(&mut self) {
+        loop {
+            let cycles_this_loop = self.gameboy.dispatch_opcode() as u64;
+            self.cycle_count += cycles_this_loop;
 
             // Audio timing
-            self.sound_cycles += cycles_this_loop as u64;
-            if self.sound_cycles >= audio_timing_cycles as u64 {
+            let audio_timing_cycles = self.gameboy.cycles_per_second() / 512; //256;
+            self.sound_cycles += cycles_this_loop;
+            if self.sound_cycles >= audio_timing_cycles {
                 // TODO: trigger this properly based on writes to registers
                 //   and APU state (i.e. not here, somewhere CPU accessible)
                 // HACK: we just update it randomly
                 //self.update_channel_vars();
                 self.renderer.audio_step(&self.gameboy);
-                self.sound_cycles -= audio_timing_cycles as u64;
+                self.sound_cycles -= audio_timing_cycles;
             }
 
-            // FF04 (DIV) Divider Register stepping
-            self.div_timer_cycles += cycles_this_loop as u64;
-            while self.div_timer_cycles >= cycles_per_divider_step {
-                let old_div_val = self.gameboy.get_div();
-                self.gameboy.inc_div();
-                self.div_timer_cycles -= cycles_per_divider_step;
-
-                let div_bit = if self.gameboy.double_speed { 5 } else { 4 };
-                // TODO: div can be reset on write, this falling-edge
-                //  detection logic does not see that
+            if let Some(frame) = self.gameboy.take_frame() {
+                self.prev_time = self.cycle_count;
 
-                // Update APU-DIV
-                if (old_div_val >> div_bit) & 1 == 1 {
-                    let new_div_val = self.gameboy.get_div();
-                    if (new_div_val >> div_bit) & 1 == 0 {
-                        // falling edge
-                        self.gameboy.apu.step();
-                    }
-                }
-            }
+                //for memory visualization
+                self.gameboy.remove_old_events();
 
-            // FF05 (TIMA) Timer counter stepping
-            self.timer_cycles += cycles_this_loop as u64;
-            let timer_hz = self.gameboy.timer_frequency_hz();
-            let cpu_cycles_per_timer_counter_step =
-                (cycles_per_second as f64 / (timer_hz as f64)) as u64;
-            while self.timer_cycles >= cpu_cycles_per_timer_counter_step {
-                self.gameboy.timer_cycle();
-                self.timer_cycles -= cpu_cycles_per_timer_counter_step;
+                // do render of frame to screen here
+                self.renderer.draw_frame(&frame);
+                self.last_frame = frame;
+                break;
             }
         }
     }
@@ -0,0 +1,200 @@
+//! Reading ROMs that are stored in zip or gzip archives
+//!
+//! Archives are recognised by their magic number rather than their file
+//! extension; anything else is taken to be a plain ROM.  Zip archives may
+//! hold several files, of which the first `.gb`/`.gbc` entry is used
+//! unless another one is asked for.  Stored and deflated zip entries are
+//! supported, zip64 and 7z archives are not.
+
+use std::io::Read;
+use std::path::Path;
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4b50;
+/// Size of the end of central directory record without its comment
+const ZIP_END_OF_DIRECTORY_SIZE: usize = 22;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const SEVEN_ZIP_MAGIC: [u8; 6] = [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+
+/// A ROM taken out of whatever it was stored in
+#[derive(Debug, Clone)]
+pub struct RomFile {
+    /// The ROM's file name: the archive entry's if it came from one
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads the ROM at `path`, extracting it if it's in an archive.
+/// `entry` picks a file in a zip archive by its path or file name.
+pub fn read_rom_file(path: &Path, entry: Option<&str>) -> Result<RomFile, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Could not read ROM {:?}: {}", path, e))?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    extract_rom(data, &file_name, entry).map_err(|e| format!("Could not load {:?}: {}", path, e))
+}
+
+/// Extracts the ROM from `data` if it is an archive, otherwise returns it
+/// as it is.  `file_name` is the name `data` was loaded from.
+pub fn extract_rom(data: Vec<u8>, file_name: &str, entry: Option<&str>) -> Result<RomFile, String> {
+    if data.starts_with(&ZIP_LOCAL_HEADER.to_le_bytes()) {
+        extract_zip(&data, entry)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        extract_gzip(&data, file_name)
+    } else if data.starts_with(&SEVEN_ZIP_MAGIC) {
+        Err("7z archives are not supported".to_string())
+    } else {
+        Ok(RomFile {
+            name: file_name.to_string(),
+            data,
+        })
+    }
+}
+
+fn extract_gzip(data: &[u8], file_name: &str) -> Result<RomFile, String> {
+    let mut decoder = flate2::read::GzDecoder::new(data);
+    let mut rom = vec![];
+    decoder
+        .read_to_end(&mut rom)
+        .map_err(|e| format!("could not decompress gzip data: {}", e))?;
+
+    // gzip may record the original name, otherwise drop the .gz
+    let name = decoder
+        .header()
+        .and_then(|header| header.filename())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .unwrap_or_else(|| {
+            let lower = file_name.to_ascii_lowercase();
+            if lower.ends_with(".gz") {
+                file_name[..file_name.len() - 3].to_string()
+            } else {
+                file_name.to_string()
+            }
+        });
+    Ok(RomFile { name, data: rom })
+}
+
+/// An entry in a zip archive's central directory
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    size: usize,
+    local_header_offset: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "zip archive is truncated".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "zip archive is truncated".to_string())
+}
+
+/// Lists the files in a zip archive from its central directory
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    // the end of directory record is followed by a comment of up to 64KB
+    let end = (0..=data.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE))
+        .rev()
+        .take(0x10000 + ZIP_END_OF_DIRECTORY_SIZE)
+        .find(|&i| read_u32(data, i) == Ok(ZIP_END_OF_DIRECTORY))
+        .ok_or_else(|| "zip archive has no central directory".to_string())?;
+    let count = read_u16(data, end + 10)? as usize;
+    let mut offset = read_u32(data, end + 16)? as usize;
+    if offset == 0xFFFF_FFFF {
+        return Err("zip64 archives are not supported".to_string());
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if read_u32(data, offset)? != ZIP_CENTRAL_HEADER {
+            return Err("zip central directory is corrupt".to_string());
+        }
+        let name_length = read_u16(data, offset + 28)? as usize;
+        let extra_length = read_u16(data, offset + 30)? as usize;
+        let comment_length = read_u16(data, offset + 32)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or_else(|| "zip archive is truncated".to_string())?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: read_u16(data, offset + 10)?,
+            crc32: read_u32(data, offset + 16)?,
+            compressed_size: read_u32(data, offset + 20)? as usize,
+            size: read_u32(data, offset + 24)? as usize,
+            local_header_offset: read_u32(data, offset + 42)? as usize,
+        });
+        offset += 46 + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+/// The file name of a zip entry, without the directories
+fn entry_file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    lower.ends_with(".gb") || lower.ends_with(".gbc")
+}
+
+fn extract_zip(data: &[u8], wanted: Option<&str>) -> Result<RomFile, String> {
+    let entries = zip_entries(data)?;
+    let entry = match wanted {
+        Some(wanted) => entries
+            .iter()
+            .find(|e| e.name == wanted || entry_file_name(&e.name) == wanted)
+            .ok_or_else(|| format!("zip archive has no entry named {:?}", wanted))?,
+        None => entries
+            .iter()
+            .find(|e| is_rom_name(&e.name))
+            .ok_or_else(|| "zip archive has no .gb or .gbc file".to_string())?,
+    };
+
+    let header = entry.local_header_offset;
+    if read_u32(data, header)? != ZIP_LOCAL_HEADER {
+        return Err(format!("zip entry {:?} is corrupt", entry.name));
+    }
+    let start =
+        header + 30 + read_u16(data, header + 26)? as usize + read_u16(data, header + 28)? as usize;
+    let compressed = data
+        .get(start..start + entry.compressed_size)
+        .ok_or_else(|| "zip archive is truncated".to_string())?;
+
+    let rom = match entry.method {
+        0 => compressed.to_vec(),
+        8 => {
+            let mut rom = vec![];
+            flate2::read::DeflateDecoder::new(compressed)
+                .read_to_end(&mut rom)
+                .map_err(|e| format!("could not decompress {:?}: {}", entry.name, e))?;
+            rom
+        }
+        method => {
+            return Err(format!(
+                "zip entry {:?} uses unsupported compression method {}",
+                entry.name, method
+            ))
+        }
+    };
+    if rom.len() != entry.size || crc32fast::hash(&rom) != entry.crc32 {
+        return Err(format!(
+            "zip entry {:?} is corrupt (checksum mismatch)",
+            entry.name
+        ));
+    }
+
+    Ok(RomFile {
+        name: entry_file_name(&entry.name).to_string(),
+        data: rom,
+    })
+}
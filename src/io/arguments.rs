@@ -48,6 +48,121 @@ pub fn read_arguments<'input>() -> ArgMatches<'input> {
                 .help("Disassemble a ROM, print it, and exit")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("rom-info")
+                .long("rom-info")
+                .help("Prints a JSON report on the ROM's header and exits; the exit code is 1 if the header is invalid")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .long("headless")
+                .help("Runs without a window or sound, for CI and batch runs")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .value_name("N")
+                .help("Number of frames to run in headless mode (default 600)")
+                .requires("headless")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("until-serial")
+                .long("until-serial")
+                .value_name("TEXT")
+                .help("Stops a headless run once the serial output contains TEXT")
+                .requires("headless")
+                .conflicts_with("until-mooneye")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("until-mooneye")
+                .long("until-mooneye")
+                .help("Stops a headless run once the registers hold a Mooneye test result")
+                .requires("headless")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .long("output-dir")
+                .value_name("DIR")
+                .help("Where a headless run writes frame.png, serial.txt and registers.txt")
+                .requires("headless")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("link-listen")
+                .long("link-listen")
+                .value_name("ADDRESS")
+                .help("Waits for another rusty-boy to connect a link cable on ADDRESS (e.g. 127.0.0.1:8765)")
+                .conflicts_with("link-connect")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("link-connect")
+                .long("link-connect")
+                .value_name("ADDRESS")
+                .help("Connects a link cable to another rusty-boy listening on ADDRESS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("printer")
+                .long("printer")
+                .help("Plugs a Game Boy Printer into the link port; printouts are saved as PNGs")
+                .conflicts_with_all(&["link-listen", "link-connect"])
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("rom-entry")
+                .long("rom-entry")
+                .value_name("NAME")
+                .help("File to load from a zip archive, by path or file name; defaults to the first .gb/.gbc file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("patch")
+                .long("patch")
+                .value_name("FILE")
+                .help("IPS, UPS or BPS patch to apply to the ROM when loading it (the ROM file is not changed); defaults to a patch named like the ROM next to it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("camera-image")
+                .long("camera-image")
+                .value_name("FILE")
+                .help("PNG or PGM image the Pocket Camera takes photos of")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("save-file")
+                .long("save-file")
+                .value_name("FILE")
+                .help("Battery save to load and write instead of the one in the user data path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bootrom")
+                .long("bootrom")
+                .value_name("FILE")
+                .help("Boot ROM to run before the game, instead of dmg_boot.bin or cgb_boot.bin in the user config path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .value_name("MODEL")
+                .help("Hardware to emulate: dmg, mgb, sgb, cgb or agb (picked from the ROM header by default)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("renderer")
+                .long("renderer")
+                .value_name("RENDERER")
+                .help("How lines are drawn: deferred (fast, the default) or fifo (accurate mid-line effects)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("remove-nops")
                 .long("remove-nops")
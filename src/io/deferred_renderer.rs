@@ -1,5 +1,4 @@
-use std::collections::BTreeSet;
-
+use crate::cpu::constants::MemAddr;
 use crate::cpu::Cpu;
 use crate::io::constants::*;
 
@@ -16,13 +15,110 @@ use crate::io::constants::*;
 // v-blank interrupt occurs at the start of the end of drawing
 // LCDC status is used on each line?
 
+/// Color number and, on CGB, tile attributes of a pixel of the background
+/// or window tile map at `map_addr`
+fn tile_map_pixel(cpu: &Cpu, map_addr: MemAddr, map_x: u16, map_y: u16) -> (u8, u8) {
+    let idx_into_tile_idx_mem = map_addr + ((map_y >> 3) << 5) + (map_x >> 3);
+    let tile_idx = cpu.mem[idx_into_tile_idx_mem];
+    let tile_start_relative = cpu.get_nth_background_tile_idx(tile_idx as u16);
+
+    // the attribute map sits at the same address in VRAM bank 1
+    let gbc_tile_flags = if cpu.gbc_mode {
+        cpu.mem.video_ram[1][(idx_into_tile_idx_mem - 0x8000) as usize]
+    } else {
+        0
+    };
+    let vram_index = ((gbc_tile_flags >> 3) & 1) as usize;
+    let x_flip = ((gbc_tile_flags >> 5) & 1) == 1;
+    let y_flip = ((gbc_tile_flags >> 6) & 1) == 1;
+
+    // Lower 3 bits determine which line of the tile we're on
+    let nth_line = if y_flip {
+        7 - (map_y & 0x7)
+    } else {
+        map_y & 0x7
+    };
+    // pixels go from MSB to LSB within a tile
+    let nth_pixel = if x_flip {
+        map_x & 0x7
+    } else {
+        7 - (map_x & 0x7)
+    };
+
+    // 1 line = 2 bytes, so we double it
+    let px_color = tile_pixel_color(
+        cpu,
+        vram_index,
+        tile_start_relative + nth_line * 2,
+        nth_pixel,
+    );
+    (px_color, gbc_tile_flags)
+}
+
+/// Color number of a pixel of the tile line at `line_start` in VRAM
+fn tile_pixel_color(cpu: &Cpu, vram_index: usize, line_start: u16, nth_pixel: u16) -> u8 {
+    let tile_byte_1_bit = (cpu.mem.video_ram[vram_index][line_start as usize] >> nth_pixel) & 0x1;
+    let tile_byte_2_bit =
+        (cpu.mem.video_ram[vram_index][line_start as usize + 1] >> nth_pixel) & 0x1;
+    (tile_byte_2_bit << 1) | tile_byte_1_bit
+}
+
+/// Color number and flags of sprite `obj_idx` at (`x`, `y`), if it covers
+/// that pixel with a color that isn't transparent
+fn sprite_pixel(cpu: &Cpu, x: u8, y: u8, obj_idx: u8) -> Option<(u8, u8)> {
+    let offset = OBJECT_ATTRIBUTE_START + (obj_idx as u16 * OBJECT_ATTRIBUTE_BLOCK_SIZE);
+    let sprite_y = cpu.mem[offset].wrapping_sub(16);
+    let sprite_x = cpu.mem[offset + 1];
+    let tile_index: u8 = cpu.mem[offset + 2];
+    let flags: u8 = cpu.mem[offset + 3];
+
+    // sprites are drawn 8 pixels left of their x coordinate
+    let xth_pixel = (x as u16 + 8).wrapping_sub(sprite_x as u16);
+    if xth_pixel >= 8 {
+        return None;
+    }
+    let yth_pixel = y.wrapping_sub(sprite_y);
+
+    let x_flip = ((flags >> 5) & 1) == 1;
+    let y_flip = ((flags >> 6) & 1) == 1;
+    let vram_index = if cpu.gbc_mode {
+        ((flags >> 3) & 1) as usize
+    } else {
+        0
+    };
+
+    let sprite_y_size = if cpu.lcdc_sprite_size() { 16 } else { 8 };
+    let yth_pixel = if y_flip {
+        (sprite_y_size - 1) - yth_pixel
+    } else {
+        yth_pixel
+    };
+    let tile_index = if cpu.lcdc_sprite_size() {
+        let tile_16 = tile_index & !1;
+        if yth_pixel >= 8 {
+            tile_16 + 1
+        } else {
+            tile_16
+        }
+    } else {
+        tile_index
+    };
+
+    let tile_line = ((yth_pixel & 0x7) * 2) as u16;
+    // pixels go from MSB to LSB within a tile
+    let nth_pixel = if x_flip { xth_pixel } else { 7 - xth_pixel };
+    let tile_start_relative = tile_index as u16 * 16;
+    let px_color = tile_pixel_color(cpu, vram_index, tile_start_relative + tile_line, nth_pixel);
+    // color 0 is transparent
+    (px_color != 0).then_some((px_color, flags))
+}
+
 pub fn deferred_renderer_draw_scanline(
     y: u8,
     cpu: &mut Cpu,
     window_counter: &mut u16,
 ) -> [(u8, u8, u8); GB_SCREEN_WIDTH] {
     let mut bg_pixels = [(0u8, 0u8, 0u8); GB_SCREEN_WIDTH];
-    let mut bg_opacities = [false; GB_SCREEN_WIDTH];
 
     // invalid y, just return
     if y >= (GB_SCREEN_HEIGHT as u8) {
@@ -42,40 +138,52 @@ pub fn deferred_renderer_draw_scanline(
     } else {
         TILE_MAP_1_START
     };
-    // TODO: could also be implemented with popcnt on a 64bit number
-    let mut sprites_seen: BTreeSet<u8> = std::collections::BTreeSet::new();
     let adj_y = y.wrapping_add(scy) as u16;
     let (bg_color1, bg_color2, bg_color3, bg_color4) = cpu.bgp();
-    let bg_colors = [
-        TILE_PALETTE[bg_color1 as usize],
-        TILE_PALETTE[bg_color2 as usize],
-        TILE_PALETTE[bg_color3 as usize],
-        TILE_PALETTE[bg_color4 as usize],
-    ];
-    let (sprite1_color1, sprite1_color2, sprite1_color3, sprite1_color4) = cpu.obp0();
-    let (sprite2_color1, sprite2_color2, sprite2_color3, sprite2_color4) = cpu.obp1();
-    let sprite_colors1 = [
-        TILE_PALETTE[sprite1_color1 as usize],
-        TILE_PALETTE[sprite1_color2 as usize],
-        TILE_PALETTE[sprite1_color3 as usize],
-        TILE_PALETTE[sprite1_color4 as usize],
-    ];
-    let sprite_colors2 = [
-        TILE_PALETTE[sprite2_color1 as usize],
-        TILE_PALETTE[sprite2_color2 as usize],
-        TILE_PALETTE[sprite2_color3 as usize],
-        TILE_PALETTE[sprite2_color4 as usize],
-    ];
-
-    let mut inc_window_counter = false;
+    let bg_shades = [bg_color1, bg_color2, bg_color3, bg_color4];
+    let (obp0_1, obp0_2, obp0_3, obp0_4) = cpu.obp0();
+    let sprite_shades1 = [obp0_1, obp0_2, obp0_3, obp0_4];
+    let (obp1_1, obp1_2, obp1_3, obp1_4) = cpu.obp1();
+    let sprite_shades2 = [obp1_1, obp1_2, obp1_3, obp1_4];
+    let (bg_colors, sprite_colors1, sprite_colors2, blank_color) = if cpu.dmg_compat_palettes {
+        // the shades index the colors the CGB boot ROM picked
+        (
+            bg_shades.map(|shade| cpu.background_color_palette_info(shade * 2)),
+            sprite_shades1.map(|shade| cpu.sprite_color_palette_info(shade * 2)),
+            sprite_shades2.map(|shade| cpu.sprite_color_palette_info(8 + shade * 2)),
+            cpu.background_color_palette_info(0),
+        )
+    } else {
+        (
+            bg_shades.map(|shade| TILE_PALETTE[shade as usize]),
+            sprite_shades1.map(|shade| TILE_PALETTE[shade as usize]),
+            sprite_shades2.map(|shade| TILE_PALETTE[shade as usize]),
+            TILE_PALETTE[0],
+        )
+    };
 
-    // (x_coord, obj_idx)
-    let mut oam_objects: Vec<(u8, u8)> = (0..=40)
-        .map(|obj_idx: u8| {
+    // on CGB, LCDC bit 0 doesn't turn the background and window off, it
+    // takes away their priority over sprites
+    let bg_win_display = cpu.gbc_mode || cpu.lcdc_bg_win_display();
+    let bg_win_priority = cpu.lcdc_bg_win_display();
+    // TODO: techincally win_x = 166 has special behavior but it may be
+    // inconsistent due to hardware bugs
+    let window_on = bg_win_display
+        && cpu.lcdc_window_on()
+        && y >= cpu.window_y_pos()
+        && cpu.window_x_pos() < 166;
+
+    // the first 10 sprites on the line in OAM order, whether or not they're
+    // on screen: (x_coord, obj_idx)
+    let sprite_y_size = if cpu.lcdc_sprite_size() { 16 } else { 8 };
+    let mut oam_objects: Vec<(u8, u8)> = (0..40)
+        .filter_map(|obj_idx: u8| {
             let offset = OBJECT_ATTRIBUTE_START + (obj_idx as u16 * OBJECT_ATTRIBUTE_BLOCK_SIZE);
+            let sprite_y = cpu.mem[offset].wrapping_sub(16);
             let sprite_x: u8 = cpu.mem[offset + 1];
-            (sprite_x, obj_idx)
+            (y.wrapping_sub(sprite_y) < sprite_y_size).then_some((sprite_x, obj_idx))
         })
+        .take(10)
         .collect::<Vec<(u8, u8)>>();
 
     if !cpu.gbc_mode {
@@ -83,274 +191,55 @@ pub fn deferred_renderer_draw_scanline(
         oam_objects.sort();
     }
 
-    let row = adj_y >> 3;
-    for x in 0..GB_SCREEN_WIDTH {
-        let mut gbc_bg_override = false;
-        let adj_x = (x as u8).wrapping_add(scx) as u16;
-        if cpu.gbc_mode || cpu.lcdc_bg_win_display() {
-            let col = adj_x >> 3;
-            let idx_into_tile_idx_mem = tile_bg_map_addr + (row << 5) + col;
-            let tile_idx = cpu.mem[idx_into_tile_idx_mem];
-            let tile_start_relative = cpu.get_nth_background_tile_idx(tile_idx as u16);
-
-            let gbc_flag_idx = idx_into_tile_idx_mem - 0x8000;
-            //let gbc_flag_idx = (row << 5) + col;
-            let gbc_tile_flags = cpu.mem.video_ram[1][gbc_flag_idx as usize];
-            let vram_index = if cpu.gbc_mode {
-                ((gbc_tile_flags >> 3) & 1) as usize
+    for (x, pixel) in bg_pixels.iter_mut().enumerate() {
+        let (px_color, gbc_tile_flags) = if window_on && (x as u8) + 7 >= cpu.window_x_pos() {
+            let win_x = (x as u8 + 7 - cpu.window_x_pos()) as u16;
+            tile_map_pixel(cpu, tile_win_map_addr, win_x, *window_counter)
+        } else if bg_win_display {
+            let adj_x = (x as u8).wrapping_add(scx) as u16;
+            tile_map_pixel(cpu, tile_bg_map_addr, adj_x, adj_y)
+        } else {
+            (0, 0)
+        };
+
+        // the highest priority sprite with a pixel here, even if it ends up
+        // behind the background
+        let sprite = if cpu.lcdc_sprite_display() {
+            oam_objects
+                .iter()
+                .find_map(|&(_, obj_idx)| sprite_pixel(cpu, x as u8, y, obj_idx))
+        } else {
+            None
+        };
+        // sprite priority bit: if set the sprite is only drawn over color 0
+        // of background and window, as is every sprite on CGB over tiles
+        // with their priority bit set
+        let sprite = sprite.filter(|&(_, flags)| {
+            let bg_over_sprite = if cpu.gbc_mode {
+                bg_win_priority && (flags >> 7 == 1 || gbc_tile_flags >> 7 == 1)
             } else {
-                0
+                flags >> 7 == 1
             };
+            !(bg_over_sprite && px_color != 0)
+        });
 
-            // Lower 3 bits determine which line of the tile we're on
-            let mut nth_line = adj_y & 0x7;
-            // 8 choices for which pixel on the line we're on, so we take 3 bits here
-            let tile_pixel = adj_x & 0x7;
-            // pixels go from MSB to LSB within a tile
-            let mut nth_pixel = 7 - tile_pixel;
-
-            if cpu.gbc_mode {
-                let x_flip = ((gbc_tile_flags >> 5) & 1) == 1;
-                let y_flip = ((gbc_tile_flags >> 6) & 1) == 1;
-                if y_flip {
-                    nth_line = 7 - nth_line;
-                }
-                if x_flip {
-                    nth_pixel = 7 - nth_pixel;
-                }
-            }
-
-            // 1 line = 2 bytes, so we double it
-            let tile_line = nth_line * 2;
-
-            let tile_byte_1_bit = (cpu.mem.video_ram[vram_index]
-                [(tile_start_relative + tile_line) as usize]
-                >> nth_pixel)
-                & 0x1;
-            let tile_byte_2_bit = (cpu.mem.video_ram[vram_index]
-                [(tile_start_relative + (tile_line + 1)) as usize]
-                >> nth_pixel)
-                & 0x1;
-            let px_color = (tile_byte_2_bit << 1) | tile_byte_1_bit;
-
-            if cpu.gbc_mode {
-                let bg_palette = (gbc_tile_flags & 0x7) * 4 * 2;
-                let bg_priority = ((gbc_tile_flags >> 7) & 1) == 1;
-                let colors = [
-                    cpu.background_color_palette_info(bg_palette),
-                    cpu.background_color_palette_info(bg_palette + 2),
-                    cpu.background_color_palette_info(bg_palette + 4),
-                    cpu.background_color_palette_info(bg_palette + 6),
-                ];
-                bg_pixels[x] = colors[px_color as usize];
-                gbc_bg_override = gbc_bg_override || bg_priority;
-            } else {
-                bg_pixels[x] = bg_colors[px_color as usize];
+        *pixel = match sprite {
+            Some((sprite_color, flags)) if cpu.gbc_mode => {
+                cpu.sprite_color_palette_info((flags & 0x7) * 4 * 2 + sprite_color * 2)
             }
-            bg_opacities[x] = bg_opacities[x] || (px_color != 0);
-
-            // window here
-            // TODO: techincally win_x = 166 has special behavior but it may be
-            // inconsistent due to hardware bugs
-            if cpu.lcdc_window_on()
-                && ((x as u8).wrapping_add(7) >= cpu.window_x_pos()//.wrapping_sub(7)
-                    && (y as u8) >= cpu.window_y_pos())
-                && (cpu.window_x_pos() < 166)
-            {
-                let win_y = (y as u16) - (cpu.window_y_pos() as u16);
-
-                let win_x = cpu.window_x_pos().wrapping_sub(7);
-                let win_x = if (adj_x as u8) >= win_x {
-                    ((x as u8) - win_x) as u16
-                } else {
-                    adj_x
-                };
-                let row = *window_counter >> 3;
-                let col = win_x >> 3;
-                let idx_into_tile_idx_mem = tile_win_map_addr + (row << 5) + col;
-                let tile_idx = cpu.mem[idx_into_tile_idx_mem];
-                let tile_start_relative = cpu.get_nth_background_tile_idx(tile_idx as u16);
-
-                let gbc_flag_idx = idx_into_tile_idx_mem - 0x8000;
-                //let gbc_flag_idx = (row << 5) + col;
-                let gbc_tile_flags = cpu.mem.video_ram[1][gbc_flag_idx as usize];
-                //let gbc_tile_flags = cpu.mem.video_ram[1][((row << 5) + col) as usize];
-                let vram_index = if cpu.gbc_mode {
-                    ((gbc_tile_flags >> 3) & 1) as usize
-                } else {
-                    0
-                };
-
-                // select the correct y pos based on lower 3 bits
-                let mut nth_line = win_y & 0x7;
-                // 8 choices for which pixel on the line we're on, so we take 3 bits here
-                let tile_pixel = adj_x & 0x7;
-                // pixels go from MSB to LSB within a tile
-                let mut nth_pixel = 7 - tile_pixel;
-
-                if cpu.gbc_mode {
-                    let x_flip = ((gbc_tile_flags >> 5) & 1) == 1;
-                    let y_flip = ((gbc_tile_flags >> 6) & 1) == 1;
-                    if y_flip {
-                        nth_line = 7 - nth_line;
-                    }
-                    if x_flip {
-                        nth_pixel = 7 - nth_pixel;
-                    }
-                }
-
-                let tile_line = nth_line * 2;
-
-                let tile_byte_1_bit = (cpu.mem.video_ram[vram_index]
-                    [(tile_start_relative + tile_line) as usize]
-                    >> nth_pixel)
-                    & 0x1;
-                let tile_byte_2_bit = (cpu.mem.video_ram[vram_index]
-                    [(tile_start_relative + (tile_line + 1)) as usize]
-                    >> nth_pixel)
-                    & 0x1;
-                let px_color = (tile_byte_2_bit << 1) | tile_byte_1_bit;
-
-                if cpu.gbc_mode {
-                    let bg_palette = (gbc_tile_flags & 0x7) * 4 * 2;
-                    let bg_priority = ((gbc_tile_flags >> 7) & 1) == 1;
-                    let colors = [
-                        cpu.background_color_palette_info(bg_palette),
-                        cpu.background_color_palette_info(bg_palette + 2),
-                        cpu.background_color_palette_info(bg_palette + 4),
-                        cpu.background_color_palette_info(bg_palette + 6),
-                    ];
-                    bg_pixels[x] = colors[px_color as usize];
-                    gbc_bg_override = gbc_bg_override || bg_priority;
-                } else {
-                    bg_pixels[x] = bg_colors[px_color as usize];
-                }
-                bg_opacities[x] = bg_opacities[x] || (px_color != 0);
-
-                inc_window_counter = true;
+            Some((sprite_color, flags)) if (flags >> 4) & 1 == 1 => {
+                sprite_colors2[sprite_color as usize]
             }
-        }
-
-        if cpu.lcdc_sprite_display() {
-            for &(_, obj_idx) in oam_objects.iter() {
-                if sprites_seen.len() >= 10 && !sprites_seen.contains(&obj_idx) {
-                    continue;
-                }
-                let offset =
-                    OBJECT_ATTRIBUTE_START + (obj_idx as u16 * OBJECT_ATTRIBUTE_BLOCK_SIZE);
-                let mut sprite_y: u8 = cpu.mem[offset];
-                let mut sprite_x: u8 = cpu.mem[offset + 1];
-                // TODO: figure out if condition below with all edge cases later
-                let original_sprite_y = sprite_y;
-                sprite_y = sprite_y.wrapping_sub(16);
-
-                let sprite_y_size = if cpu.lcdc_sprite_size() { 16 } else { 8 };
-                if ((x as u8) < sprite_x && (x as u8).wrapping_add(8) >= sprite_x)
-                    && ((y as u8) < sprite_y.wrapping_add(sprite_y_size) && (y as u8) >= sprite_y)
-                {
-                    sprites_seen.insert(obj_idx);
-                } else {
-                    continue;
-                }
-                if (sprite_x == 0 || sprite_x >= 168)
-                    && (original_sprite_y == 0 || original_sprite_y >= 160)
-                {
-                    // sprite is "hidden"
-                    continue;
-                }
-                sprite_x = sprite_x.wrapping_sub(8);
-
-                let tile_index: u8 = cpu.mem[offset + 2];
-                // TODO implement flag handling (priority, flips...)
-                let flags: u8 = cpu.mem[offset + 3];
-                let x_flip = ((flags >> 5) & 1) == 1;
-                let y_flip = ((flags >> 6) & 1) == 1;
-                let win_bg_over_sprite = ((flags >> 7) & 1) == 1;
-                let alt_palette = ((flags >> 4) & 1) == 1;
-                let cgb_palette_num = flags & 0x7;
-                let vram_index = if cpu.gbc_mode {
-                    ((flags >> 3) & 1) as usize
-                } else {
-                    0
-                };
-
-                let xth_pixel = (x as u8).wrapping_sub(sprite_x);
-                let yth_pixel = (y as u8).wrapping_sub(sprite_y);
-
-                let xth_pixel = if x_flip { 7 - xth_pixel } else { xth_pixel };
-                let yth_pixel = if y_flip {
-                    (sprite_y_size - 1) - yth_pixel
-                } else {
-                    yth_pixel
-                };
-
-                let tile_index = if cpu.lcdc_sprite_size() {
-                    let tile_16 = tile_index & !1;
-                    if yth_pixel >= 8 {
-                        tile_16 + 1
-                    } else {
-                        tile_16
-                    }
-                } else {
-                    tile_index
-                };
-
-                let tile_line = ((yth_pixel & 0x7) * 2) as u16;
-                // 8 choices for which pixel on the line we're on, so we take 3 bits here
-                let tile_pixel = xth_pixel & 0x7;
-                // pixels go from MSB to LSB within a tile
-                let nth_pixel = 7 - tile_pixel;
-                let tile_start_relative = tile_index as u16 * 16;
-                let tile_byte_1_bit = (cpu.mem.video_ram[vram_index]
-                    [(tile_start_relative + tile_line) as usize]
-                    >> nth_pixel)
-                    & 0x1;
-                let tile_byte_2_bit = (cpu.mem.video_ram[vram_index]
-                    [(tile_start_relative + (tile_line + 1)) as usize]
-                    >> nth_pixel)
-                    & 0x1;
-                let px_color = (tile_byte_2_bit << 1) | tile_byte_1_bit;
-                let true_color = if alt_palette {
-                    sprite_colors2[px_color as usize]
-                } else {
-                    sprite_colors1[px_color as usize]
-                };
-
-                if px_color == 0 && cpu.gbc_mode && gbc_bg_override {
-                    break;
-                }
-                // transparency
-                if px_color != 0 || !cpu.lcdc_bg_win_display() {
-                    let bg_priority = if cpu.gbc_mode {
-                        match (gbc_bg_override, win_bg_over_sprite) {
-                            (false, false) => false,
-                            _ => cpu.lcdc_bg_win_display() && bg_opacities[x],
-                        }
-                    } else {
-                        win_bg_over_sprite && bg_opacities[x]
-                    };
-                    if bg_priority {
-                        continue;
-                    }
-                    if cpu.gbc_mode {
-                        let colors = [
-                            cpu.sprite_color_palette_info(cgb_palette_num * 4 * 2),
-                            cpu.sprite_color_palette_info((cgb_palette_num * 4 * 2) + 2),
-                            cpu.sprite_color_palette_info((cgb_palette_num * 4 * 2) + 4),
-                            cpu.sprite_color_palette_info((cgb_palette_num * 4 * 2) + 6),
-                        ];
-                        bg_pixels[x] = colors[px_color as usize];
-                    } else {
-                        bg_pixels[x] = true_color;
-                    }
-                    // highest priority pixel value found here, we shouldn't draw anything else
-                    break;
-                }
+            Some((sprite_color, _)) => sprite_colors1[sprite_color as usize],
+            None if cpu.gbc_mode => {
+                cpu.background_color_palette_info((gbc_tile_flags & 0x7) * 4 * 2 + px_color * 2)
             }
-        }
+            None if bg_win_display => bg_colors[px_color as usize],
+            None => blank_color,
+        };
     }
 
-    if inc_window_counter {
+    if window_on {
         *window_counter += 1;
     }
     bg_pixels
@@ -4,6 +4,7 @@ use sdl2::rect::{Point, Rect};
 use sdl2::surface::Surface;
 use sdl2::*;
 
+use crate::cpu::savestate::SAVE_STATE_SLOTS;
 use crate::cpu::Cpu;
 use crate::io::applicationsettings::ApplicationSettings;
 use crate::io::constants::*;
@@ -19,6 +20,11 @@ pub struct Sdl2Renderer {
     canvas: render::Canvas<video::Window>,
     controller: Option<sdl2::controller::GameController>, // storing to keep alive
     _sound_cycles: u64,
+    /// The save-state slot used by the save and load hotkeys
+    save_state_slot: u8,
+    /// Tilt fed to cartridges with an accelerometer, from the left stick
+    /// or the arrow keys
+    tilt: (f32, f32),
 }
 
 // copied from legacy code:
@@ -106,6 +112,8 @@ impl Sdl2Renderer {
             canvas: renderer,
             controller,
             _sound_cycles: 0,
+            save_state_slot: 0,
+            tilt: (0.0, 0.0),
         })
     }
 
@@ -129,6 +137,13 @@ impl Sdl2Renderer {
             }
         }
     }
+
+    /// Sets the slot used by the save-state hotkeys
+    fn select_save_state_slot(&mut self, slot: u8) {
+        debug_assert!(slot < SAVE_STATE_SLOTS);
+        self.save_state_slot = slot;
+        info!("Selected save-state slot {}", slot);
+    }
 }
 
 impl Renderer for Sdl2Renderer {
@@ -209,6 +224,12 @@ impl Renderer for Sdl2Renderer {
                 } => {
                     let deadzone = 10000;
                     trace!("Axis {:?} moved to {}", axis, val);
+                    let analog = val as f32 / i16::MAX as f32;
+                    match axis {
+                        controller::Axis::LeftX => self.tilt.0 = analog,
+                        controller::Axis::LeftY => self.tilt.1 = analog,
+                        _ => {}
+                    }
                     match axis {
                         controller::Axis::LeftX if deadzone < (val as i32).abs() => {
                             if val < 0 {
@@ -322,14 +343,44 @@ impl Renderer for Sdl2Renderer {
                                 // gameboy = Cpu::new();
                                 // gameboy.load_rom(rom_file);
                             }
+                            Keycode::F5 => {
+                                ret_vec.push(EventResponse::SaveState(self.save_state_slot))
+                            }
+                            Keycode::F8 => {
+                                ret_vec.push(EventResponse::LoadState(self.save_state_slot))
+                            }
+                            Keycode::Backspace => ret_vec.push(EventResponse::Rewind(true)),
                             Keycode::A => gameboy.press_a(),
                             Keycode::S => gameboy.press_b(),
                             Keycode::D => gameboy.press_select(),
                             Keycode::F => gameboy.press_start(),
-                            Keycode::Up => gameboy.press_up(),
-                            Keycode::Down => gameboy.press_down(),
-                            Keycode::Left => gameboy.press_left(),
-                            Keycode::Right => gameboy.press_right(),
+                            Keycode::Up => {
+                                gameboy.press_up();
+                                self.tilt.1 = -1.0;
+                            }
+                            Keycode::Down => {
+                                gameboy.press_down();
+                                self.tilt.1 = 1.0;
+                            }
+                            Keycode::Left => {
+                                gameboy.press_left();
+                                self.tilt.0 = -1.0;
+                            }
+                            Keycode::Right => {
+                                gameboy.press_right();
+                                self.tilt.0 = 1.0;
+                            }
+                            // number keys select the save-state slot
+                            Keycode::Num0 => self.select_save_state_slot(0),
+                            Keycode::Num1 => self.select_save_state_slot(1),
+                            Keycode::Num2 => self.select_save_state_slot(2),
+                            Keycode::Num3 => self.select_save_state_slot(3),
+                            Keycode::Num4 => self.select_save_state_slot(4),
+                            Keycode::Num5 => self.select_save_state_slot(5),
+                            Keycode::Num6 => self.select_save_state_slot(6),
+                            Keycode::Num7 => self.select_save_state_slot(7),
+                            Keycode::Num8 => self.select_save_state_slot(8),
+                            Keycode::Num9 => self.select_save_state_slot(9),
                             _ => (),
                         }
                     }
@@ -345,10 +396,23 @@ impl Renderer for Sdl2Renderer {
                             Keycode::S => gameboy.unpress_b(),
                             Keycode::D => gameboy.unpress_select(),
                             Keycode::F => gameboy.unpress_start(),
-                            Keycode::Up => gameboy.unpress_up(),
-                            Keycode::Down => gameboy.unpress_down(),
-                            Keycode::Left => gameboy.unpress_left(),
-                            Keycode::Right => gameboy.unpress_right(),
+                            Keycode::Up => {
+                                gameboy.unpress_up();
+                                self.tilt.1 = 0.0;
+                            }
+                            Keycode::Down => {
+                                gameboy.unpress_down();
+                                self.tilt.1 = 0.0;
+                            }
+                            Keycode::Left => {
+                                gameboy.unpress_left();
+                                self.tilt.0 = 0.0;
+                            }
+                            Keycode::Right => {
+                                gameboy.unpress_right();
+                                self.tilt.0 = 0.0;
+                            }
+                            Keycode::Backspace => ret_vec.push(EventResponse::Rewind(false)),
 
                             _ => (),
                         }
@@ -384,10 +448,21 @@ impl Renderer for Sdl2Renderer {
                 _ => (),
             }
         }
+        gameboy.set_tilt(self.tilt.0, self.tilt.1);
 
         return ret_vec;
     }
 
+    fn set_rumble(&mut self, active: bool) {
+        // rumble until told to stop, SDL caps the duration itself
+        let (strength, duration_ms) = if active { (0xFFFF, u32::MAX) } else { (0, 0) };
+        if let Some(ref mut controller) = self.controller {
+            if let Err(e) = controller.set_rumble(strength, strength, duration_ms) {
+                debug!("Controller {} can't rumble: {}", controller.name(), e);
+            }
+        }
+    }
+
     fn audio_step(&mut self, gb: &Cpu) {
         // TODO:
         /*
@@ -5,6 +5,12 @@ use crate::io::constants::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH};
 pub enum EventResponse {
     ProgramTerminated,
     Reset,
+    /// Write a save-state to the given slot
+    SaveState(u8),
+    /// Restore the save-state in the given slot
+    LoadState(u8),
+    /// Start (`true`) or stop (`false`) playing frames backwards
+    Rewind(bool),
 }
 
 pub trait Renderer {
@@ -18,4 +24,7 @@ pub trait Renderer {
     fn audio_step(&mut self, _gb: &Cpu) {
         unimplemented!();
     }
+
+    /// Called when the cartridge's rumble motor turns on or off
+    fn set_rumble(&mut self, _active: bool) {}
 }
@@ -0,0 +1,177 @@
+//! Running the emulator without a display
+//!
+//! Used for CI and batch runs of test ROMs: the ROM runs for a fixed
+//! number of frames or until a stop condition is met, after which the
+//! final frame, serial output and registers can be dumped.
+
+use std::path::Path;
+
+use crate::cpu::serial::CaptureEndpoint;
+use crate::cpu::Cpu;
+use crate::io::applicationstate::ApplicationState;
+use crate::io::constants::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH};
+use crate::io::graphics::renderer::{EventResponse, Renderer};
+use crate::io::image::write_frame_png;
+
+/// A renderer that draws nothing and plays no sound
+#[derive(Debug, Default)]
+pub struct HeadlessRenderer;
+
+impl Renderer for HeadlessRenderer {
+    fn draw_frame(&mut self, _frame: &[[(u8, u8, u8); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT]) {}
+
+    fn handle_events(&mut self, _: &mut Cpu) -> Vec<EventResponse> {
+        vec![]
+    }
+
+    fn audio_step(&mut self, _gb: &Cpu) {}
+}
+
+/// Creates an emulator with no display whose serial output is captured
+pub fn new_headless_state() -> Result<ApplicationState, String> {
+    let mut appstate = ApplicationState::new(Box::new(HeadlessRenderer))?;
+    appstate
+        .gameboy
+        .serial
+        .set_endpoint(Box::new(CaptureEndpoint::new()));
+    Ok(appstate)
+}
+
+/// When a headless run should stop before its frame limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopCondition {
+    /// The serial output contains this text (Blargg style test ROMs)
+    SerialContains(String),
+    /// `LD B, B` has run with B, C, D, E, H and L holding either the
+    /// Fibonacci numbers 3, 5, 8, 13, 21, 34 (pass) or all 0x42 (fail),
+    /// as Mooneye test ROMs do
+    MooneyeRegisters,
+    /// Either kind of test ROM has reported a result
+    TestRomFinished,
+}
+
+impl StopCondition {
+    pub fn is_met(&self, gameboy: &Cpu) -> bool {
+        match self {
+            StopCondition::SerialContains(text) => {
+                String::from_utf8_lossy(gameboy.serial_output()).contains(text.as_str())
+            }
+            StopCondition::MooneyeRegisters => mooneye_result(gameboy).is_some(),
+            StopCondition::TestRomFinished => test_rom_result(gameboy).is_some(),
+        }
+    }
+}
+
+/// The verdict of a test ROM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestRomResult {
+    Passed,
+    Failed,
+}
+
+/// Looks for a test ROM result in the serial output (Blargg) or the
+/// registers (Mooneye)
+pub fn test_rom_result(gameboy: &Cpu) -> Option<TestRomResult> {
+    blargg_result(gameboy).or_else(|| mooneye_result(gameboy))
+}
+
+/// The result Blargg ROMs print as their last line: "Passed", "Failed",
+/// or from the combined ROMs "Passed all tests" or "Failed N tests"
+fn blargg_result(gameboy: &Cpu) -> Option<TestRomResult> {
+    let serial = String::from_utf8_lossy(gameboy.serial_output());
+    // the line isn't finished until its newline is sent
+    if !serial.ends_with('\n') {
+        return None;
+    }
+    let last_line = serial.trim_end().lines().last()?.trim();
+    if last_line == "Passed" || last_line == "Passed all tests" {
+        Some(TestRomResult::Passed)
+    } else if last_line == "Failed" || last_line.starts_with("Failed ") {
+        Some(TestRomResult::Failed)
+    } else {
+        None
+    }
+}
+
+/// The result Mooneye ROMs leave in the registers before `LD B, B`
+fn mooneye_result(gameboy: &Cpu) -> Option<TestRomResult> {
+    if !gameboy.ld_b_b_executed {
+        return None;
+    }
+    match mooneye_registers(gameboy) {
+        MOONEYE_PASS => Some(TestRomResult::Passed),
+        MOONEYE_FAIL => Some(TestRomResult::Failed),
+        _ => None,
+    }
+}
+
+/// Register values a passing Mooneye test leaves in B, C, D, E, H, L
+pub const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+/// Register values a failing Mooneye test leaves in B, C, D, E, H, L
+pub const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+/// The values of B, C, D, E, H and L
+pub fn mooneye_registers(gameboy: &Cpu) -> [u8; 6] {
+    use crate::cpu::constants::CpuRegister;
+
+    let mut out = [0u8; 6];
+    for (o, reg) in out.iter_mut().zip(&[
+        CpuRegister::B,
+        CpuRegister::C,
+        CpuRegister::D,
+        CpuRegister::E,
+        CpuRegister::H,
+        CpuRegister::L,
+    ]) {
+        *o = gameboy.access_register(*reg).unwrap_or(0);
+    }
+    out
+}
+
+/// How a headless run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadlessOutcome {
+    pub frames_run: u64,
+    /// Whether the stop condition (if any) was met
+    pub condition_met: bool,
+}
+
+/// Runs up to `max_frames` frames, checking `condition` after each one
+pub fn run_headless(
+    appstate: &mut ApplicationState,
+    max_frames: u64,
+    condition: Option<&StopCondition>,
+) -> HeadlessOutcome {
+    let mut frames_run = 0;
+    while frames_run < max_frames {
+        appstate.step();
+        frames_run += 1;
+        if let Some(condition) = condition {
+            if condition.is_met(&appstate.gameboy) {
+                return HeadlessOutcome {
+                    frames_run,
+                    condition_met: true,
+                };
+            }
+        }
+    }
+    HeadlessOutcome {
+        frames_run,
+        condition_met: condition.is_none(),
+    }
+}
+
+/// Writes `frame.png`, `serial.txt` and `registers.txt` into `dir`
+pub fn dump_results(appstate: &ApplicationState, dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("Could not create output directory {:?}: {}", dir, e))?;
+    write_frame_png(&appstate.last_frame, &dir.join("frame.png"))?;
+    std::fs::write(dir.join("serial.txt"), appstate.gameboy.serial_output())
+        .map_err(|e| format!("Could not write serial output: {}", e))?;
+    std::fs::write(
+        dir.join("registers.txt"),
+        format!("{}\n", appstate.gameboy.register_dump()),
+    )
+    .map_err(|e| format!("Could not write register state: {}", e))?;
+    Ok(())
+}
@@ -0,0 +1,30 @@
+//! Writing images to disk
+
+use std::path::Path;
+
+use crate::io::constants::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH};
+
+/// Writes a frame out as an 8-bit RGB PNG
+pub fn write_frame_png(
+    frame: &[[(u8, u8, u8); GB_SCREEN_WIDTH]; GB_SCREEN_HEIGHT],
+    path: &Path,
+) -> Result<(), String> {
+    let data = frame
+        .iter()
+        .flat_map(|row| row.iter().flat_map(|&(r, g, b)| [r, g, b]))
+        .collect::<Vec<u8>>();
+    write_png(path, GB_SCREEN_WIDTH as u32, GB_SCREEN_HEIGHT as u32, &data)
+}
+
+/// Writes 8-bit RGB pixel data out as a PNG
+pub fn write_png(path: &Path, width: u32, height: u32, rgb: &[u8]) -> Result<(), String> {
+    let file = std::fs::File::create(path)
+        .map_err(|e| format!("Could not create PNG {:?}: {}", path, e))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(rgb))
+        .map_err(|e| format!("Could not write PNG {:?}: {}", path, e))
+}
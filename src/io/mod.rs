@@ -3,6 +3,7 @@
 #[cfg(feature = "desktop")]
 pub mod applicationsettings;
 pub mod applicationstate;
+pub mod archive;
 #[cfg(feature = "cli")]
 pub mod arguments;
 pub mod constants;
@@ -11,5 +12,10 @@ pub mod deferred_renderer;
 pub mod dr_sdl2;
 pub mod events;
 pub mod graphics;
+pub mod headless;
+pub mod image;
+pub mod patch;
+pub mod printer;
+pub mod rewind;
 #[cfg(feature = "desktop")]
 pub mod sound;
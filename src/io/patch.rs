@@ -0,0 +1,302 @@
+//! Applying IPS, UPS and BPS patches to ROMs
+//!
+//! Patches are applied in memory when the ROM is loaded; the ROM file on
+//! disk is never changed.  The format is recognised from the patch's magic
+//! number:
+//!
+//! | format | magic   | contents                                          |
+//! |--------|---------|---------------------------------------------------|
+//! | IPS    | `PATCH` | records of 24-bit offset, 16-bit length and data  |
+//! | UPS    | `UPS1`  | XOR hunks between source and target               |
+//! | BPS    | `BPS1`  | copy commands from the source, patch or target    |
+//!
+//! UPS and BPS end with the CRC32s of the source, target and patch, which
+//! are all checked.
+
+use std::path::{Path, PathBuf};
+
+/// The largest ROM a UPS or BPS patch may produce; larger targets are
+/// refused before anything is allocated
+pub const MAX_PATCHED_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl PatchFormat {
+    /// Recognises the format of `patch` from its header
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(b"PATCH") {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+
+    /// File extensions looked for next to a ROM, in order of preference
+    const SIDECAR_EXTENSIONS: [&'static str; 3] = ["bps", "ups", "ips"];
+}
+
+/// Returns the patched ROM, leaving `rom` as it is
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Ups) => apply_ups(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err("Not an IPS, UPS or BPS patch".to_string()),
+    }
+}
+
+/// Reads the patch at `patch_path` and applies it to `rom`
+pub fn apply_patch_file(rom: &[u8], patch_path: &Path) -> Result<Vec<u8>, String> {
+    let patch = std::fs::read(patch_path)
+        .map_err(|e| format!("Could not read patch {:?}: {}", patch_path, e))?;
+    apply_patch(rom, &patch).map_err(|e| format!("Could not apply patch {:?}: {}", patch_path, e))
+}
+
+/// Finds a patch with the same name as the ROM next to it, e.g.
+/// `game.bps` for `game.gb`
+pub fn find_sidecar_patch(rom_path: &Path) -> Option<PathBuf> {
+    PatchFormat::SIDECAR_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || "IPS patch is truncated".to_string();
+    let mut out = rom.to_vec();
+    let mut pos = 5;
+    loop {
+        let record = patch.get(pos..pos + 3).ok_or_else(truncated)?;
+        if record == b"EOF" {
+            pos += 3;
+            break;
+        }
+        let offset = u32::from_be_bytes([0, record[0], record[1], record[2]]) as usize;
+        let size = patch.get(pos + 3..pos + 5).ok_or_else(truncated)?;
+        let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+        pos += 5;
+
+        let data = if size == 0 {
+            // run-length encoded record: a 16-bit count and one byte
+            let rle = patch.get(pos..pos + 3).ok_or_else(truncated)?;
+            pos += 3;
+            vec![rle[2]; u16::from_be_bytes([rle[0], rle[1]]) as usize]
+        } else {
+            let data = patch.get(pos..pos + size).ok_or_else(truncated)?;
+            pos += size;
+            data.to_vec()
+        };
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    // an optional 24-bit length to truncate the ROM to
+    if let Some(length) = patch.get(pos..pos + 3) {
+        out.truncate(u32::from_be_bytes([0, length[0], length[1], length[2]]) as usize);
+    }
+    Ok(out)
+}
+
+/// Reads the variable length numbers used by UPS and BPS
+fn read_varint(patch: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let out_of_range = || "Patch has an out of range number".to_string();
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        let byte = *patch
+            .get(*pos)
+            .ok_or_else(|| "Patch is truncated".to_string())?;
+        *pos += 1;
+        value = (byte as usize & 0x7F)
+            .checked_mul(shift)
+            .and_then(|v| v.checked_add(value))
+            .ok_or_else(out_of_range)?;
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift = shift.checked_mul(0x80).ok_or_else(out_of_range)?;
+        value = value.checked_add(shift).ok_or_else(out_of_range)?;
+    }
+}
+
+/// Checks the CRC32s at the end of a UPS or BPS patch, returning the
+/// expected CRC32 of the target
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<u32, String> {
+    if patch.len() < 16 {
+        return Err("Patch is truncated".to_string());
+    }
+    let footer = &patch[patch.len() - 12..];
+    let crc =
+        |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+
+    if crc32fast::hash(&patch[..patch.len() - 4]) != crc(8) {
+        return Err("Patch is corrupt (checksum mismatch)".to_string());
+    }
+    if crc32fast::hash(rom) != crc(0) {
+        return Err("Patch is for a different ROM (checksum mismatch)".to_string());
+    }
+    Ok(crc(4))
+}
+
+/// Checks the size a UPS or BPS patch says it produces
+fn check_target_size(target_size: usize) -> Result<(), String> {
+    if target_size > MAX_PATCHED_ROM_SIZE {
+        return Err(format!(
+            "Patch makes a {} byte ROM, more than the {} bytes allowed",
+            target_size, MAX_PATCHED_ROM_SIZE
+        ));
+    }
+    Ok(())
+}
+
+fn check_target(target: &[u8], expected_crc: u32) -> Result<(), String> {
+    if crc32fast::hash(target) != expected_crc {
+        return Err("Patched ROM does not match the patch's checksum".to_string());
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut pos = 4;
+    let source_size = read_varint(patch, &mut pos)?;
+    let target_size = read_varint(patch, &mut pos)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "Patch is for a {} byte ROM, not {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+    check_target_size(target_size)?;
+
+    let out_of_range = || "UPS patch writes out of range".to_string();
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut offset: usize = 0;
+    while pos < end {
+        offset = offset
+            .checked_add(read_varint(patch, &mut pos)?)
+            .ok_or_else(out_of_range)?;
+        // XOR bytes in until a zero, which also advances the offset
+        loop {
+            let byte = *patch
+                .get(pos)
+                .filter(|_| pos < end)
+                .ok_or_else(|| "UPS patch is truncated".to_string())?;
+            pos += 1;
+            if let Some(out_byte) = out.get_mut(offset) {
+                *out_byte ^= byte;
+            }
+            offset = offset.checked_add(1).ok_or_else(out_of_range)?;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut pos = 4;
+    let source_size = read_varint(patch, &mut pos)?;
+    let target_size = read_varint(patch, &mut pos)?;
+    let metadata_size = read_varint(patch, &mut pos)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "Patch is for a {} byte ROM, not {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+    check_target_size(target_size)?;
+
+    let out_of_range = || "BPS patch reads out of range".to_string();
+    pos = pos.checked_add(metadata_size).ok_or_else(out_of_range)?;
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    // the relative offsets of the copy commands are signed
+    let read_relative = |pos: &mut usize, base: usize| -> Result<usize, String> {
+        let data = read_varint(patch, pos)?;
+        let distance = data >> 1;
+        if data & 1 == 1 {
+            base.checked_sub(distance)
+        } else {
+            base.checked_add(distance)
+        }
+        .ok_or_else(out_of_range)
+    };
+
+    while pos < end {
+        let data = read_varint(patch, &mut pos)?;
+        let length = (data >> 2) + 1;
+        // which also keeps the additions below from overflowing
+        if length > target_size - out.len() {
+            return Err("BPS patch writes past the end of the ROM".to_string());
+        }
+        match data & 3 {
+            // source read: copy from the same place in the ROM
+            0 => {
+                let start = out.len();
+                let bytes = rom.get(start..start + length).ok_or_else(out_of_range)?;
+                out.extend_from_slice(bytes);
+            }
+            // target read: copy from the patch
+            1 => {
+                let bytes = patch
+                    .get(pos..pos + length)
+                    .filter(|_| pos + length <= end)
+                    .ok_or_else(out_of_range)?;
+                out.extend_from_slice(bytes);
+                pos += length;
+            }
+            // source copy: copy from anywhere in the ROM
+            2 => {
+                source_offset = read_relative(&mut pos, source_offset)?;
+                let source_end = source_offset.checked_add(length).ok_or_else(out_of_range)?;
+                let bytes = rom
+                    .get(source_offset..source_end)
+                    .ok_or_else(out_of_range)?;
+                out.extend_from_slice(bytes);
+                source_offset = source_end;
+            }
+            // target copy: copy from what has been written, which may
+            // overlap the bytes being written
+            _ => {
+                target_offset = read_relative(&mut pos, target_offset)?;
+                for _ in 0..length {
+                    let byte = *out.get(target_offset).ok_or_else(out_of_range)?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(format!(
+            "Patched ROM is {} bytes, the patch expects {}",
+            out.len(),
+            target_size
+        ));
+    }
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
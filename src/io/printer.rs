@@ -0,0 +1,303 @@
+//! Game Boy Printer emulation
+//!
+//! The printer sits on the link port and is always clocked by the Game
+//! Boy.  Every command is sent as a packet:
+//!
+//! | bytes | contents                                      |
+//! |-------|-----------------------------------------------|
+//! | 2     | magic `0x88 0x33`                             |
+//! | 1     | command: 1 init, 2 print, 4 data, 0xF status  |
+//! | 1     | 1 if the data is RLE compressed               |
+//! | 2     | data length, little-endian                    |
+//! | n     | data                                          |
+//! | 2     | checksum of command through data, LE          |
+//! | 2     | dummy bytes, answered with 0x81 and status    |
+//!
+//! Image data is sent as 2bpp tiles, 20 tiles to a row.  Each print
+//! command prints everything received since the last one; a print with
+//! a feed after it ends the printout, which is then written as a PNG.
+
+use std::path::PathBuf;
+
+use crate::cpu::serial::SerialEndpoint;
+use crate::io::image::write_png;
+
+const PRINTER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINTER_WIDTH / 8;
+/// Pixel rows fed per unit of margin
+const MARGIN_LINE_HEIGHT: usize = 8;
+/// How many status requests report busy after a print
+const BUSY_STATUS_POLLS: u8 = 4;
+
+/// Reply to the first dummy byte: a printer is connected
+const PRINTER_ALIVE: u8 = 0x81;
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// A Game Boy Printer plugged into the link port
+#[derive(Debug)]
+pub struct Printer {
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet_data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_polls: u8,
+    /// Decompressed tile data not printed yet
+    image_data: Vec<u8>,
+    /// RGB pixel rows of the printout in progress
+    page: Vec<u8>,
+    output_dir: PathBuf,
+}
+
+impl Printer {
+    /// Printouts are written into `output_dir`
+    pub fn new(output_dir: PathBuf) -> Printer {
+        Printer {
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet_data: vec![],
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            image_data: vec![],
+            page: vec![],
+            output_dir,
+        }
+    }
+
+    /// Feeds one byte of a packet, returning the printer's reply
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x33 => PacketState::Command,
+            PacketState::Magic2 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 1 == 1;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.packet_data.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.packet_data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.packet_data.len() == self.length as usize {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                reply = PRINTER_ALIVE;
+                self.run_command();
+                PacketState::Status
+            }
+            PacketState::Status => {
+                reply = self.status;
+                PacketState::Magic1
+            }
+        };
+        reply
+    }
+
+    fn run_command(&mut self) {
+        if self.received_checksum != self.checksum {
+            warn!(
+                "Printer packet checksum mismatch: expected {:04X}, got {:04X}",
+                self.checksum, self.received_checksum
+            );
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            // initialize
+            0x1 => {
+                self.image_data.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            // print
+            0x2 => {
+                if self.packet_data.len() < 4 {
+                    warn!("Printer print command is missing its arguments");
+                    return;
+                }
+                let sheets = self.packet_data[0];
+                let margin_before = (self.packet_data[1] >> 4) as usize;
+                let margin_after = (self.packet_data[1] & 0xF) as usize;
+                let palette = self.packet_data[2];
+                // byte 3 is the exposure, which only darkens real paper
+
+                if sheets > 0 {
+                    self.feed(margin_before);
+                    self.print_image_data(palette);
+                }
+                self.image_data.clear();
+                if margin_after > 0 {
+                    self.feed(margin_after);
+                    self.finish_page();
+                }
+                self.status = STATUS_BUSY;
+                self.busy_polls = BUSY_STATUS_POLLS;
+            }
+            // data
+            0x4 => {
+                if self.compressed {
+                    let data = decompress(&self.packet_data);
+                    self.image_data.extend(data);
+                } else {
+                    self.image_data.extend_from_slice(&self.packet_data);
+                }
+                if !self.image_data.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+            }
+            // status
+            0xF => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    if self.busy_polls == 0 {
+                        self.status &= !STATUS_BUSY;
+                    }
+                }
+            }
+            otherwise => warn!("Unknown printer command {:X}", otherwise),
+        }
+    }
+
+    /// Appends `margin` units of blank paper to the page
+    fn feed(&mut self, margin: usize) {
+        let len = self.page.len() + margin * MARGIN_LINE_HEIGHT * PRINTER_WIDTH * 3;
+        self.page.resize(len, 0xFF);
+    }
+
+    /// Appends the received tiles to the page, shaded through `palette`
+    fn print_image_data(&mut self, palette: u8) {
+        const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+        let tile_rows = self.image_data.len() / (TILES_PER_ROW * 16);
+        for tile_row in 0..tile_rows {
+            for line in 0..8 {
+                for x in 0..PRINTER_WIDTH {
+                    let tile = tile_row * TILES_PER_ROW + x / 8;
+                    let low = self.image_data[tile * 16 + line * 2];
+                    let high = self.image_data[tile * 16 + line * 2 + 1];
+                    let bit = 7 - (x % 8);
+                    let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+                    let shade = SHADES[((palette >> (color * 2)) & 0x3) as usize];
+                    self.page.extend_from_slice(&[shade, shade, shade]);
+                }
+            }
+        }
+    }
+
+    /// Writes the page out as a PNG and starts a new one
+    fn finish_page(&mut self) {
+        let height = self.page.len() / (PRINTER_WIDTH * 3);
+        if height == 0 {
+            return;
+        }
+        let page = std::mem::take(&mut self.page);
+        let result = std::fs::create_dir_all(&self.output_dir)
+            .map_err(|e| format!("Could not create printout directory: {}", e))
+            .and_then(|()| {
+                let path = self.next_printout_path();
+                write_png(&path, PRINTER_WIDTH as u32, height as u32, &page).map(|()| path)
+            });
+        match result {
+            Ok(path) => info!("Printed to {:?}", path),
+            Err(e) => error!("Could not save printout: {}", e),
+        }
+    }
+
+    /// The first `printout-NNNN.png` in the output directory that isn't taken
+    fn next_printout_path(&self) -> PathBuf {
+        (0..)
+            .map(|n| self.output_dir.join(format!("printout-{:04}.png", n)))
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+}
+
+/// Expands the printer's RLE: a control byte with bit 7 set repeats the
+/// next byte `(ctrl & 0x7F) + 2` times, otherwise the next `ctrl + 1`
+/// bytes are copied as is.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let ctrl = data[i];
+        i += 1;
+        if ctrl & 0x80 == 0x80 {
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(byte, (ctrl & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + ctrl as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+impl SerialEndpoint for Printer {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.receive(outgoing)
+    }
+}
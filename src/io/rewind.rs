@@ -0,0 +1,200 @@
+//! Rewind buffer
+//!
+//! Keeps a bounded history of save-states so that the last few seconds
+//! of emulation can be played backwards.
+//!
+//! Only the newest snapshot is stored in full.  Every older snapshot is
+//! stored as the XOR of itself and the snapshot that follows it, with
+//! runs of zero bytes collapsed.  Consecutive frames differ in very few
+//! bytes so this keeps each entry small.
+
+use std::collections::VecDeque;
+
+/// Take a snapshot every this many frames
+pub const REWIND_SNAPSHOT_INTERVAL: u32 = 4;
+/// The maximum number of snapshots kept (about 20 seconds at 60fps)
+pub const REWIND_MAX_SNAPSHOTS: usize = 300;
+/// The maximum number of bytes used by stored snapshots
+pub const REWIND_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+enum RewindEntry {
+    /// A complete save-state
+    Full(Vec<u8>),
+    /// A compressed XOR against the next newer snapshot
+    Delta(Vec<u8>),
+}
+
+impl RewindEntry {
+    fn len(&self) -> usize {
+        match self {
+            RewindEntry::Full(bytes) | RewindEntry::Delta(bytes) => bytes.len(),
+        }
+    }
+}
+
+/// A ring buffer of delta compressed save-states
+#[derive(Debug, Clone)]
+pub struct RewindBuffer {
+    /// Oldest snapshot at the front, newest (always `Full`) at the back
+    entries: VecDeque<RewindEntry>,
+    total_bytes: usize,
+    frames_since_snapshot: u32,
+}
+
+impl Default for RewindBuffer {
+    fn default() -> RewindBuffer {
+        RewindBuffer::new()
+    }
+}
+
+impl RewindBuffer {
+    pub fn new() -> RewindBuffer {
+        RewindBuffer {
+            entries: VecDeque::new(),
+            total_bytes: 0,
+            frames_since_snapshot: 0,
+        }
+    }
+
+    /// Number of snapshots that can currently be rewound to
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Memory used by the stored snapshots, in bytes
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.total_bytes = 0;
+        self.frames_since_snapshot = 0;
+    }
+
+    /// Called once per emulated frame; returns true when a snapshot is due
+    pub fn frame_finished(&mut self) -> bool {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot >= REWIND_SNAPSHOT_INTERVAL {
+            self.frames_since_snapshot = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adds a new snapshot, compressing the previous newest one against it
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(RewindEntry::Full(prev)) = self.entries.pop_back() {
+            self.total_bytes -= prev.len();
+            let entry = if prev.len() == state.len() {
+                RewindEntry::Delta(encode_delta(&prev, &state))
+            } else {
+                RewindEntry::Full(prev)
+            };
+            self.total_bytes += entry.len();
+            self.entries.push_back(entry);
+        }
+        self.total_bytes += state.len();
+        self.entries.push_back(RewindEntry::Full(state));
+
+        while self.entries.len() > 1
+            && (self.entries.len() > REWIND_MAX_SNAPSHOTS || self.total_bytes > REWIND_MAX_BYTES)
+        {
+            let oldest = self.entries.pop_front().unwrap();
+            self.total_bytes -= oldest.len();
+        }
+    }
+
+    /// Removes and returns the newest snapshot
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = match self.entries.pop_back()? {
+            RewindEntry::Full(bytes) => bytes,
+            RewindEntry::Delta(_) => unreachable!("newest rewind entry must be complete"),
+        };
+        self.total_bytes -= newest.len();
+
+        // a snapshot from before a size change is already complete
+        if matches!(self.entries.back(), Some(RewindEntry::Delta(_))) {
+            if let Some(RewindEntry::Delta(delta)) = self.entries.pop_back() {
+                self.total_bytes -= delta.len();
+                let prev = decode_delta(&delta, &newest);
+                self.total_bytes += prev.len();
+                self.entries.push_back(RewindEntry::Full(prev));
+            }
+        }
+        self.frames_since_snapshot = 0;
+
+        Some(newest)
+    }
+}
+
+/// Encodes `old XOR new` as a sequence of
+/// `(zero run length: u32, literal length: u32, literal bytes)` records
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(old.len(), new.len());
+    let mut out = vec![];
+    let mut i = 0;
+    while i < old.len() {
+        let run_start = i;
+        while i < old.len() && old[i] == new[i] {
+            i += 1;
+        }
+        let zero_run = i - run_start;
+
+        let literal_start = i;
+        // end the literal only once a few identical bytes in a row are
+        // found; short matches are cheaper to store as literals
+        let mut matching = 0;
+        while i < old.len() && matching < 8 {
+            if old[i] == new[i] {
+                matching += 1;
+            } else {
+                matching = 0;
+            }
+            i += 1;
+        }
+        if matching > 0 {
+            i -= matching;
+        }
+
+        out.extend_from_slice(&(zero_run as u32).to_le_bytes());
+        out.extend_from_slice(&((i - literal_start) as u32).to_le_bytes());
+        out.extend(
+            old[literal_start..i]
+                .iter()
+                .zip(&new[literal_start..i])
+                .map(|(a, b)| a ^ b),
+        );
+    }
+    out
+}
+
+/// Reverses `encode_delta`, rebuilding the older state from the newer one
+fn decode_delta(delta: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = new.to_vec();
+    let read_u32 = |pos: usize| {
+        u32::from_le_bytes([delta[pos], delta[pos + 1], delta[pos + 2], delta[pos + 3]]) as usize
+    };
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_u32(pos);
+        let literal_len = read_u32(pos + 4);
+        pos += 8;
+        for (byte, x) in out[i..i + literal_len]
+            .iter_mut()
+            .zip(&delta[pos..pos + literal_len])
+        {
+            *byte ^= x;
+        }
+        i += literal_len;
+        pos += literal_len;
+    }
+    out
+}
@@ -15,7 +15,11 @@ pub mod cpu;
 pub mod disasm;
 pub mod io;
 
+use crate::cpu::cartridge::camera::{CallbackCamera, CAMERA_HEIGHT, CAMERA_WIDTH};
+use crate::cpu::model::Model;
+use crate::cpu::ppu::RenderMode;
 use crate::io::applicationstate::*;
+use crate::io::archive::extract_rom;
 use crate::io::constants::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH};
 use crate::io::graphics::renderer::Renderer;
 
@@ -30,6 +34,12 @@ extern "C" {
     fn warn_message(message: *const u8, length: usize);
     fn debug_message(message: *const u8, length: usize);
     fn trace_message(message: *const u8, length: usize);
+    /// Called when the cartridge's rumble motor turns on or off.
+    fn set_rumble(active: bool);
+    /// Called when a Pocket Camera takes a photo: fill the `width`x`height`
+    /// bytes at `image` with grayscale pixels, top to bottom, left to right,
+    /// where 0 is black.
+    fn camera_capture(image: *mut u8, width: usize, height: usize);
 }
 
 const LOGGER: ExternalLogger = ExternalLogger;
@@ -42,13 +52,20 @@ pub extern "C" fn create_emulator() -> Option<Box<ApplicationState>> {
         .map(|()| log::set_max_level(log::LevelFilter::Info))
         .unwrap();
     let external_renderer = ExternalRenderer;
-    let application_state = match ApplicationState::new(Box::new(external_renderer)) {
+    let mut application_state = match ApplicationState::new(Box::new(external_renderer)) {
         Ok(apst) => apst,
         Err(e) => {
             error!("Fatal error: could not create Gameboy: {}", e);
             return None;
         }
     };
+    application_state.rewind_enabled = true;
+    let camera = CallbackCamera::new(|image| unsafe {
+        camera_capture(image.as_mut_ptr(), CAMERA_WIDTH, CAMERA_HEIGHT);
+    });
+    application_state
+        .gameboy
+        .set_camera_source(Box::new(camera));
     Some(Box::new(application_state))
 }
 
@@ -102,6 +119,50 @@ pub extern "C" fn press_button(
 #[no_mangle]
 pub extern "C" fn reset(application_state: &mut ApplicationState) {
     application_state.gameboy.reset();
+    application_state.rewind.clear();
+}
+
+/// Set the hardware to emulate and reset: 1 DMG, 2 MGB, 3 SGB, 4 CGB, 5 AGB,
+/// or 0 to pick it from the ROM header.
+#[no_mangle]
+pub extern "C" fn set_model(application_state: &mut ApplicationState, model: u8) {
+    if model != 0 && Model::from_id(model).is_none() {
+        error!("Unknown model {}", model);
+        return;
+    }
+    application_state.gameboy.set_model(Model::from_id(model));
+    application_state.rewind.clear();
+}
+
+/// Draw with the pixel FIFO, which shows changes made in the middle of a
+/// line, or with the faster deferred renderer.
+#[no_mangle]
+pub extern "C" fn set_pixel_fifo(application_state: &mut ApplicationState, enabled: bool) {
+    application_state.gameboy.set_render_mode(if enabled {
+        RenderMode::Fifo
+    } else {
+        RenderMode::Deferred
+    });
+}
+
+/// Start or stop rewinding; while rewinding each `step` plays an older frame.
+#[no_mangle]
+pub extern "C" fn set_rewinding(application_state: &mut ApplicationState, rewinding: bool) {
+    application_state.rewinding = rewinding;
+}
+
+/// The number of snapshots that can currently be rewound to.
+#[no_mangle]
+pub extern "C" fn rewind_snapshot_count(application_state: &ApplicationState) -> usize {
+    application_state.rewind.len()
+}
+
+/// Set the tilt read by cartridges with an accelerometer, in g from -1 to 1.
+/// `x` is positive when tilted right, `y` when tilted towards the bottom of
+/// the screen.
+#[no_mangle]
+pub extern "C" fn set_tilt(application_state: &mut ApplicationState, x: f32, y: f32) {
+    application_state.gameboy.set_tilt(x, y);
 }
 
 /// Load a new ROM into the emulator.
@@ -115,7 +176,35 @@ pub unsafe extern "C" fn load_rom(
     rom_data_len: usize,
 ) {
     let rom_data = std::slice::from_raw_parts(rom_data_ptr, rom_data_len).to_owned();
-    application_state.gameboy.load_rom(rom_data);
+    // zipped or gzipped ROMs are unpacked first
+    let rom_file = match extract_rom(rom_data, "", None) {
+        Ok(rom_file) => rom_file,
+        Err(e) => {
+            error!("Could not load ROM: {}", e);
+            return;
+        }
+    };
+    application_state.gameboy.load_rom(rom_file.data);
+    application_state.rewind.clear();
+}
+
+/// Run a boot ROM (256 bytes for DMG, 2304 for CGB) before the game,
+/// resetting the emulator.
+///
+/// # Safety
+/// Boot_rom_ptr must point to a valid slice of memory that is at least boot_rom_len bytes long.
+#[no_mangle]
+pub unsafe extern "C" fn load_boot_rom(
+    application_state: &mut ApplicationState,
+    boot_rom_ptr: *const u8,
+    boot_rom_len: usize,
+) {
+    let boot_rom = std::slice::from_raw_parts(boot_rom_ptr, boot_rom_len).to_owned();
+    if let Err(e) = application_state.gameboy.set_boot_rom(boot_rom) {
+        error!("Could not load boot ROM: {}", e);
+        return;
+    }
+    application_state.rewind.clear();
 }
 
 #[repr(C)]
@@ -140,6 +229,12 @@ impl Renderer for ExternalRenderer {
         // TODO: look into restructing the code so this trait isn't required
         vec![]
     }
+
+    fn set_rumble(&mut self, active: bool) {
+        unsafe {
+            set_rumble(active);
+        }
+    }
 }
 
 use log::{Level, Metadata, Record};
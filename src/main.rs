@@ -31,9 +31,18 @@ pub mod io;
 use crate::debugger::graphics::Debugger;
 use crate::io::applicationsettings::*;
 use crate::io::applicationstate::*;
+use crate::io::archive::RomFile;
 use crate::io::graphics::renderer::EventResponse;
 use crate::io::graphics::renderer::Renderer;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How often battery backed RAM is written out while a game is running
+const RAM_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Set by the SIGINT/SIGTERM handler, checked by the main loop
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
 #[allow(unused_variables)]
 fn main() {
     let arguments = io::arguments::read_arguments();
@@ -55,6 +64,13 @@ fn main() {
         }
     };
 
+    if application_settings.rom_info {
+        std::process::exit(rom_info_main(&application_settings));
+    }
+    if application_settings.headless_mode {
+        std::process::exit(headless_main(&application_settings));
+    }
+
     use crate::io::dr_sdl2;
 
     #[cfg(feature = "vulkan")]
@@ -76,22 +92,41 @@ fn main() {
             return;
         }
     };
+    // after SDL is initialized so these replace its handlers
+    install_exit_signal_handlers();
+    appstate.rewind_enabled = true;
 
-    trace!("loading ROM");
-    let rom_bytes = {
-        use std::fs::File;
-        use std::io::Read;
-
-        let mut rom = File::open(application_settings.rom_file_name)
-            .map_err(|e| format!("Could not open ROM file: {}", e))
-            .unwrap();
-        let mut rom_buffer = Vec::with_capacity(0x4000);
-        rom.read_to_end(&mut rom_buffer)
-            .map_err(|e| format!("Could not read ROM data from file: {}", e))
-            .unwrap();
-        rom_buffer
-    };
-    appstate.gameboy.load_rom(rom_bytes);
+    appstate.gameboy.set_model(application_settings.model);
+    appstate
+        .gameboy
+        .set_render_mode(application_settings.render_mode);
+    match read_rom(&application_settings) {
+        Ok(rom) => {
+            appstate.gameboy.load_rom(rom.data);
+            appstate.gameboy.set_rom_name(&rom.name);
+        }
+        Err(e) => {
+            eprintln!("Fatal error: {}", e);
+            return;
+        }
+    }
+    if let Err(e) = load_boot_rom(&application_settings, &mut appstate) {
+        eprintln!("Fatal error: {}", e);
+        return;
+    }
+    let save_file = application_settings.save_file(&appstate.gameboy);
+    if let Some(ref save_file) = save_file {
+        appstate.gameboy.load_saved_ram(save_file);
+    }
+
+    if let Err(e) = connect_link_cable(&application_settings, &mut appstate) {
+        eprintln!("Fatal error: {}", e);
+        return;
+    }
+    if let Err(e) = attach_camera_image(&application_settings, &mut appstate) {
+        eprintln!("Fatal error: {}", e);
+        return;
+    }
     //    application_settings.data_path.clone(),
 
     // delay debugger so loading rom can be logged if need be
@@ -101,8 +136,25 @@ fn main() {
         None
     };
 
+    let mut last_ram_flush = std::time::Instant::now();
     loop {
         let time_since_last_frame = std::time::Instant::now();
+        if EXIT_REQUESTED.load(Ordering::Relaxed) {
+            info!("Signal received, exiting");
+            if let Some(ref mut debugger) = debugger {
+                debugger.die();
+            }
+            if let Some(ref save_file) = save_file {
+                appstate.gameboy.save_ram(save_file);
+            }
+            std::process::exit(0);
+        }
+        if last_ram_flush.elapsed() >= RAM_FLUSH_INTERVAL {
+            if let Some(ref save_file) = save_file {
+                appstate.gameboy.flush_ram(save_file);
+            }
+            last_ram_flush = std::time::Instant::now();
+        }
         for event in appstate
             .renderer
             .handle_events(&mut appstate.gameboy /* , &application_settings*/)
@@ -114,14 +166,45 @@ fn main() {
                     if let Some(ref mut debugger) = debugger {
                         debugger.die();
                     }
-                    appstate
-                        .gameboy
-                        .save_ram(application_settings.data_path.clone());
+                    if let Some(ref save_file) = save_file {
+                        appstate.gameboy.save_ram(save_file);
+                    }
                     std::process::exit(0);
                 }
                 EventResponse::Reset => {
                     info!("Resetting gameboy");
                     appstate.gameboy.reset();
+                    appstate.rewind.clear();
+                }
+                EventResponse::SaveState(slot) => {
+                    let result = match application_settings.data_path {
+                        Some(ref data_path) => {
+                            appstate.gameboy.save_state_to_slot(data_path.clone(), slot)
+                        }
+                        None => Err("no user data path available".to_string()),
+                    };
+                    match result {
+                        Ok(path) => info!("Saved state to slot {} ({:?})", slot, path),
+                        Err(e) => error!("Could not save state to slot {}: {}", slot, e),
+                    }
+                }
+                EventResponse::LoadState(slot) => {
+                    let result = match application_settings.data_path {
+                        Some(ref data_path) => appstate
+                            .gameboy
+                            .load_state_from_slot(data_path.clone(), slot),
+                        None => Err("no user data path available".to_string()),
+                    };
+                    match result {
+                        Ok(()) => {
+                            info!("Loaded state from slot {}", slot);
+                            appstate.rewind.clear();
+                        }
+                        Err(e) => error!("Could not load state from slot {}: {}", slot, e),
+                    }
+                }
+                EventResponse::Rewind(rewinding) => {
+                    appstate.rewinding = rewinding;
                 }
             }
         }
@@ -140,3 +223,189 @@ fn main() {
         }
     }
 }
+
+/// Makes SIGINT and SIGTERM ask the main loop to save and exit
+#[cfg(unix)]
+fn install_exit_signal_handlers() {
+    extern "C" fn request_exit(_signal: libc::c_int) {
+        EXIT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    let handler = request_exit as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is
+        // async-signal-safe
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            warn!("Could not install handler for signal {}", signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn install_exit_signal_handlers() {}
+
+/// Plugs a TCP link cable or a printer into the link port if one was asked for
+fn connect_link_cable(
+    application_settings: &ApplicationSettings,
+    appstate: &mut ApplicationState,
+) -> Result<(), String> {
+    use crate::cpu::serial::{SerialEndpoint, TcpEndpoint};
+    use crate::io::printer::Printer;
+
+    let endpoint: Box<dyn SerialEndpoint> = if let Some(ref addr) = application_settings.link_listen
+    {
+        Box::new(TcpEndpoint::listen(addr.as_str())?)
+    } else if let Some(ref addr) = application_settings.link_connect {
+        Box::new(TcpEndpoint::connect(addr.as_str())?)
+    } else if application_settings.printer {
+        let mut printout_dir = application_settings
+            .data_path
+            .clone()
+            .ok_or_else(|| "no user data path available for printouts".to_string())?;
+        printout_dir.push("printouts");
+        Box::new(Printer::new(printout_dir))
+    } else {
+        return Ok(());
+    };
+    appstate.gameboy.serial.set_endpoint(endpoint);
+    Ok(())
+}
+
+/// Shows the Pocket Camera the image given with `--camera-image`
+fn attach_camera_image(
+    application_settings: &ApplicationSettings,
+    appstate: &mut ApplicationState,
+) -> Result<(), String> {
+    use crate::cpu::cartridge::camera::StillImageCamera;
+
+    if let Some(ref path) = application_settings.camera_image {
+        let camera = StillImageCamera::open(path)?;
+        appstate.gameboy.set_camera_source(Box::new(camera));
+    }
+    Ok(())
+}
+
+/// Runs the boot ROM given with `--bootrom` or found in the config path
+/// before the game, if there is one
+fn load_boot_rom(
+    application_settings: &ApplicationSettings,
+    appstate: &mut ApplicationState,
+) -> Result<(), String> {
+    if let Some(path) = application_settings.boot_rom(&appstate.gameboy) {
+        info!("Loading boot ROM {:?}", path);
+        let boot_rom = std::fs::read(&path)
+            .map_err(|e| format!("Could not read boot ROM {:?}: {}", path, e))?;
+        appstate.gameboy.set_boot_rom(boot_rom)?;
+    }
+    Ok(())
+}
+
+/// Reads the ROM, extracting it from an archive if need be, and applies
+/// the patch given with `--patch`, or one next to the ROM with the same
+/// name
+fn read_rom(application_settings: &ApplicationSettings) -> Result<RomFile, String> {
+    use crate::io::archive::read_rom_file;
+    use crate::io::patch::{apply_patch_file, find_sidecar_patch};
+    use std::path::Path;
+
+    trace!("loading ROM");
+    let rom_path = Path::new(&application_settings.rom_file_name);
+    let mut rom_file = read_rom_file(rom_path, application_settings.rom_entry.as_deref())?;
+    info!("Loading ROM {}", rom_file.name);
+
+    let patch = application_settings
+        .patch
+        .clone()
+        .or_else(|| find_sidecar_patch(rom_path));
+    if let Some(patch) = patch {
+        info!("Applying patch {:?}", patch);
+        rom_file.data = apply_patch_file(&rom_file.data, &patch)?;
+    }
+    Ok(rom_file)
+}
+
+/// Prints the header report of the (unpatched) ROM, returns the exit code
+fn rom_info_main(application_settings: &ApplicationSettings) -> i32 {
+    use crate::cpu::cartridge::header::rom_info;
+    use crate::io::archive::read_rom_file;
+    use std::path::Path;
+
+    let report = read_rom_file(
+        Path::new(&application_settings.rom_file_name),
+        application_settings.rom_entry.as_deref(),
+    )
+    .and_then(|rom_file| rom_info(&rom_file.data));
+    match report {
+        Ok(report) => {
+            println!("{}", report.to_json());
+            if report.is_valid() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("Fatal error: {}", e);
+            2
+        }
+    }
+}
+
+/// Runs the ROM without a window, dumps the results and returns the exit code
+fn headless_main(application_settings: &ApplicationSettings) -> i32 {
+    use crate::io::headless::*;
+
+    let mut appstate = match new_headless_state() {
+        Ok(apst) => apst,
+        Err(e) => {
+            eprintln!("Fatal error: could not create Gameboy: {}", e);
+            return 2;
+        }
+    };
+    appstate.gameboy.set_model(application_settings.model);
+    appstate
+        .gameboy
+        .set_render_mode(application_settings.render_mode);
+    match read_rom(application_settings) {
+        Ok(rom) => {
+            appstate.gameboy.load_rom(rom.data);
+            appstate.gameboy.set_rom_name(&rom.name);
+        }
+        Err(e) => {
+            eprintln!("Fatal error: {}", e);
+            return 2;
+        }
+    }
+    if let Err(e) = load_boot_rom(application_settings, &mut appstate) {
+        eprintln!("Fatal error: {}", e);
+        return 2;
+    }
+    if let Err(e) = attach_camera_image(application_settings, &mut appstate) {
+        eprintln!("Fatal error: {}", e);
+        return 2;
+    }
+
+    let outcome = run_headless(
+        &mut appstate,
+        application_settings.headless_frames,
+        application_settings.stop_condition.as_ref(),
+    );
+    info!(
+        "Ran {} frames, stop condition met: {}",
+        outcome.frames_run, outcome.condition_met
+    );
+    println!("{}", appstate.gameboy.register_dump());
+
+    if let Some(ref output_dir) = application_settings.output_dir {
+        if let Err(e) = dump_results(&appstate, output_dir) {
+            eprintln!("Fatal error: could not write results: {}", e);
+            return 2;
+        }
+    }
+
+    if outcome.condition_met {
+        0
+    } else {
+        1
+    }
+}
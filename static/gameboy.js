@@ -116,6 +116,62 @@ function trace_to_console(ptr, len) {
     console.info(str);
 }
 
+// rumble cartridges switch their motor on and off; mirror that on every
+// connected gamepad that supports vibration
+function set_gamepad_rumble(active) {
+    for (const gamepad of navigator.getGamepads()) {
+        if (!gamepad || !gamepad.vibrationActuator) {
+            continue;
+        }
+        if (active) {
+            gamepad.vibrationActuator.playEffect("dual-rumble", {
+                duration: 5000,
+                strongMagnitude: 1.0,
+                weakMagnitude: 1.0,
+            });
+        } else {
+            gamepad.vibrationActuator.reset();
+        }
+    }
+}
+
+// the Pocket Camera sees the webcam once the user has allowed it, and a
+// flat gray until then
+let cameraVideo = null;
+let cameraRequested = false;
+
+function capture_camera_image(ptr, width, height) {
+    const wasmMemory = new Uint8Array(rustWasm.instance.exports.memory.buffer);
+
+    if (!cameraRequested && navigator.mediaDevices) {
+        cameraRequested = true;
+        navigator.mediaDevices.getUserMedia({ video: true }).then(function(stream) {
+            cameraVideo = document.createElement("video");
+            cameraVideo.srcObject = stream;
+            cameraVideo.play();
+        }).catch(function(e) {
+            console.warn("No camera for the Pocket Camera: " + e);
+        });
+    }
+    if (!cameraVideo || cameraVideo.readyState < 2) {
+        wasmMemory.fill(0x80, ptr, ptr + width * height);
+        return;
+    }
+
+    const canvas = document.createElement("canvas");
+    canvas.width = width;
+    canvas.height = height;
+    const cameraCtx = canvas.getContext("2d");
+    cameraCtx.drawImage(cameraVideo, 0, 0, width, height);
+    const pixels = cameraCtx.getImageData(0, 0, width, height).data;
+    for (let i = 0; i < width * height; i++) {
+        const r = pixels[i * 4];
+        const g = pixels[i * 4 + 1];
+        const b = pixels[i * 4 + 2];
+        wasmMemory[ptr + i] = (r * 299 + g * 587 + b * 114) / 1000;
+    }
+}
+
 const wasmInit = async (wasmModuleUrl, importObject) => {
     console.log("wasmInit");
 
@@ -128,6 +184,8 @@ const wasmInit = async (wasmModuleUrl, importObject) => {
           warn_message: (ptr, len) => warn_to_console(ptr, len),
           debug_message: (ptr, len) => debug_to_console(ptr, len),
           trace_message: (ptr, len) => trace_to_console(ptr, len),
+          set_rumble: (active) => set_gamepad_rumble(active),
+          camera_capture: (ptr, width, height) => capture_camera_image(ptr, width, height),
       }
     };
   }
@@ -256,6 +314,16 @@ function setUpHandlers() {
                     e.gamepad.buttons.length, e.gamepad.axes.length);
     });
 
+    // tilt for cartridges with an accelerometer: gamma is the left/right
+    // tilt and beta the front/back tilt, in degrees
+    window.addEventListener("deviceorientation", function(e) {
+        if (!emulatorPtr || e.gamma === null || e.beta === null) {
+            return;
+        }
+        const clamp = (v) => Math.max(-1.0, Math.min(1.0, v));
+        rustWasm.instance.exports.set_tilt(emulatorPtr, clamp(e.gamma / 45.0), clamp(e.beta / 45.0));
+    });
+
     // user input support
     //var canvas = document.getElementById('canvas')
     // couldn't get it working on canvas directly... not sure why